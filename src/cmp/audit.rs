@@ -0,0 +1,89 @@
+//! Audit logs recording every decision a comparison made about an entry, for proving which entries it examined
+//!
+//! Each decision is written as a JSON object on a line of its own, as soon as it's made:
+//!
+//! ```text
+//! {"path":"directory/regular_file","type":32768,"decision":"unequal","diff":"Contents"}
+//! {"path":"fifo","type":4096,"decision":"ignored","reason":"excluded by the ignored paths, ignore files or include globs"}
+//! ```
+//!
+//! Types are the `S_IFMT` bits of the modes of the entries in the first tree, and are left out when unknown, as
+//! is the type of entries missing on one side. Entries ignored in both trees are recorded for each of them, and
+//! directories only differing under them are recorded as unequal with no difference of their own.
+
+use super::{Decision, Diff};
+use failure::{Fallible, ResultExt};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Serialize)]
+struct Record<'a> {
+    path: &'a Path,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    file_type: Option<u32>,
+    decision: Decision,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+}
+
+pub struct AuditLog {
+    // Lines are written whole, so records of parallel comparisons don't interleave and a run cut short leaves
+    // complete records behind
+    file: Mutex<LineWriter<File>>,
+    error: Mutex<Option<io::Error>>,
+}
+
+impl AuditLog {
+    pub fn create(path: &Path) -> Fallible<Self> {
+        let file = File::create(path).with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+        Ok(AuditLog {
+            file: Mutex::new(LineWriter::new(file)),
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Record a decision, the first error writing records being kept for `check`
+    pub fn record(&self, path: &Path, file_type: Option<u32>, decision: Decision, diff: Option<&Diff>) {
+        let record = Record {
+            path,
+            file_type,
+            decision,
+            reason: reason(decision, diff),
+            diff: diff.and_then(Diff::kind_name),
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(self.file.lock().unwrap(), "{}", line));
+        if let Err(e) = result {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// Fail if any record couldn't be written, as the log wouldn't account for every entry then
+    pub fn check(&self) -> Fallible<()> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e).context("Failed writing the audit log")?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why entries were left out of the comparison, their differences not reported or they're unequal with no
+/// difference of their own
+fn reason(decision: Decision, diff: Option<&Diff>) -> Option<&'static str> {
+    match decision {
+        Decision::Unequal if diff.is_none() => Some("differing entries under it"),
+        Decision::Ignored => Some("excluded by the ignored paths, ignore files or include globs"),
+        Decision::Skipped => Some("missing on one side"),
+        Decision::Changed => Some("modified during the comparison"),
+        Decision::Allowed => Some("differences allowed"),
+        Decision::Unchanged => Some("unchanged relative to the reference on both sides"),
+        Decision::OutOfSizeRange => Some("contents out of the compared size range"),
+        Decision::Equal | Decision::Unequal | Decision::ContentsEqual => None,
+    }
+}
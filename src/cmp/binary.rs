@@ -0,0 +1,455 @@
+//! Compact binary encoding of differences, for tools ingesting more of them than text or JSON is practical for
+//!
+//! Each difference is a record of its length, as a little endian `u32` counting the bytes after it, followed by:
+//!
+//! ```text
+//! version: u8, kind: u8, first: path, second: path, path: u8 (0 when absent) [path], payload
+//! ```
+//!
+//! Integers are little endian, paths and byte strings are their `u32` length followed by their bytes and
+//! sequences their `u32` count followed by their elements. The kind is the number of the `Diff` variant, the
+//! payload holding its fields in order. Bytes of a record after its payload are skipped, so fields can be added
+//! without a new version.
+
+#[cfg(unix)]
+use super::HashAlgorithm;
+use super::{Comparison, Diff};
+use failure::{bail, Fallible};
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::collections::HashSet;
+use std::convert::TryInto;
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::iter::FromIterator;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Version of the binary encoding records are written in
+pub const BINARY_FORMAT_VERSION: u8 = 1;
+
+impl Comparison {
+    /// Write the binary record of a difference, nothing being written for equal comparisons
+    pub fn write_binary(&self, writer: &mut impl Write) -> io::Result<()> {
+        if let Comparison::Unequal {
+            diff,
+            first,
+            second,
+            path,
+        } = self
+        {
+            let mut record = Encoder(vec![BINARY_FORMAT_VERSION, kind(diff)]);
+            record.path(first);
+            record.path(second);
+            record.optional_path(path.as_deref());
+            record.payload(diff);
+            let length: u32 = record.0.len().try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Difference too large for a binary record")
+            })?;
+            writer.write_all(&length.to_le_bytes())?;
+            writer.write_all(&record.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the next difference written with `Comparison::write_binary`, `None` at the end of the input
+pub fn decode_binary(reader: &mut impl Read) -> Fallible<Option<Comparison>> {
+    let mut length = [0; 4];
+    let mut filled = 0;
+    while filled < length.len() {
+        match reader.read(&mut length[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => bail!("Truncated binary record length"),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut record = vec![0; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut record)?;
+
+    let mut decoder = Decoder(&record);
+    let version = decoder.u8()?;
+    if version != BINARY_FORMAT_VERSION {
+        bail!(
+            "Binary record of version {}, only version {} is supported",
+            version,
+            BINARY_FORMAT_VERSION
+        );
+    }
+    let kind = decoder.u8()?;
+    let first = decoder.path()?;
+    let second = decoder.path()?;
+    let path = decoder.optional_path()?;
+    let diff = decoder.payload(kind)?;
+    Ok(Some(Comparison::Unequal {
+        diff,
+        first,
+        second,
+        path,
+    }))
+}
+
+/// Number of a kind of difference in records, which has to stay the same across versions and platforms
+fn kind(diff: &Diff) -> u8 {
+    match diff {
+        #[cfg(unix)]
+        Diff::Modes(..) => 0,
+        #[cfg(unix)]
+        Diff::Types(..) => 1,
+        #[cfg(unix)]
+        Diff::Nlinks(..) => 2,
+        #[cfg(unix)]
+        Diff::Mtimes(..) => 3,
+        #[cfg(unix)]
+        Diff::IoBlockSize(..) => 4,
+        #[cfg(unix)]
+        Diff::EntryCounts(..) => 5,
+        #[cfg(unix)]
+        Diff::Uids(..) => 6,
+        #[cfg(unix)]
+        Diff::Gids(..) => 7,
+        #[cfg(unix)]
+        Diff::Inodes(..) => 8,
+        #[cfg(unix)]
+        Diff::Sizes(..) => 9,
+        #[cfg(unix)]
+        Diff::TrailingPadding(..) => 10,
+        Diff::Contents(..) => 11,
+        Diff::RelocatedContents(..) => 12,
+        #[cfg(unix)]
+        Diff::ContentHash(..) => 13,
+        #[cfg(unix)]
+        Diff::DeviceTypes(..) => 14,
+        #[cfg(unix)]
+        Diff::LinkTarget(..) => 15,
+        #[cfg(unix)]
+        Diff::DirContents(..) => 16,
+        #[cfg(unix)]
+        Diff::DirOrder(..) => 17,
+        #[cfg(target_os = "linux")]
+        Diff::InodeFlags(..) => 18,
+        #[cfg(target_os = "linux")]
+        Diff::Whiteouts(..) => 19,
+        #[cfg(target_os = "linux")]
+        Diff::OpaqueDirs(..) => 20,
+        #[cfg(target_os = "linux")]
+        Diff::ExtentSharing(..) => 21,
+        #[cfg(target_os = "linux")]
+        Diff::SparseLayout(..) => 22,
+        #[cfg(target_os = "linux")]
+        Diff::Xattrs(..) => 23,
+        #[cfg(target_os = "linux")]
+        Diff::Compression(..) => 24,
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[cfg(unix)]
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as u8);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.0.extend_from_slice(bytes);
+    }
+
+    #[cfg(unix)]
+    fn path(&mut self, path: &Path) {
+        self.bytes(path.as_os_str().as_bytes());
+    }
+
+    #[cfg(windows)]
+    fn path(&mut self, path: &Path) {
+        self.bytes(path.to_string_lossy().as_bytes());
+    }
+
+    fn optional_path(&mut self, path: Option<&Path>) {
+        match path {
+            Some(path) => {
+                self.0.push(1);
+                self.path(path);
+            }
+            None => self.0.push(0),
+        }
+    }
+
+    #[cfg(unix)]
+    fn paths<'a>(&mut self, paths: impl ExactSizeIterator<Item = &'a PathBuf>) {
+        self.u32(paths.len() as u32);
+        for path in paths {
+            self.path(path);
+        }
+    }
+
+    fn payload(&mut self, diff: &Diff) {
+        match diff {
+            #[cfg(unix)]
+            Diff::Modes(first, second)
+            | Diff::Types(first, second)
+            | Diff::Uids(first, second)
+            | Diff::Gids(first, second) => {
+                self.u32(*first);
+                self.u32(*second);
+            }
+            #[cfg(target_os = "linux")]
+            Diff::InodeFlags(first, second) => {
+                self.u32(*first);
+                self.u32(*second);
+            }
+            #[cfg(unix)]
+            Diff::Nlinks(first, second)
+            | Diff::IoBlockSize(first, second)
+            | Diff::EntryCounts(first, second)
+            | Diff::TrailingPadding(first, second)
+            | Diff::DeviceTypes(first, second) => {
+                self.u64(*first);
+                self.u64(*second);
+            }
+            #[cfg(target_os = "linux")]
+            Diff::ExtentSharing(first, second) => {
+                self.u64(*first);
+                self.u64(*second);
+            }
+            #[cfg(unix)]
+            Diff::Mtimes(first, second) | Diff::Sizes(first, second) => {
+                self.i64(*first);
+                self.i64(*second);
+            }
+            #[cfg(unix)]
+            Diff::Inodes(first, second) => {
+                self.optional_path(first.as_deref());
+                self.optional_path(second.as_deref());
+            }
+            Diff::Contents(block, offset, first, second)
+            | Diff::RelocatedContents(block, offset, first, second) => {
+                self.u64(*block);
+                self.u64(*offset);
+                self.bytes(first);
+                self.bytes(second);
+            }
+            #[cfg(unix)]
+            Diff::ContentHash(algorithm, first, second) => {
+                self.0.push(match algorithm {
+                    HashAlgorithm::Sha256 => 0,
+                    HashAlgorithm::Sha512 => 1,
+                    #[cfg(feature = "blake3")]
+                    HashAlgorithm::Blake3 => 2,
+                });
+                self.bytes(first);
+                self.bytes(second);
+            }
+            #[cfg(unix)]
+            Diff::LinkTarget(first, second) => {
+                self.path(first);
+                self.path(second);
+            }
+            #[cfg(unix)]
+            Diff::DirContents(first, second) => {
+                for names in &[first, second] {
+                    let mut names: Vec<_> = names.iter().collect();
+                    names.sort();
+                    self.paths(names.into_iter());
+                }
+            }
+            #[cfg(unix)]
+            Diff::DirOrder(first, second) => {
+                self.paths(first.iter());
+                self.paths(second.iter());
+            }
+            #[cfg(target_os = "linux")]
+            Diff::Whiteouts(first, second)
+            | Diff::OpaqueDirs(first, second)
+            | Diff::Compression(first, second) => {
+                self.bool(*first);
+                self.bool(*second);
+            }
+            #[cfg(target_os = "linux")]
+            Diff::SparseLayout(first, second) => {
+                for regions in &[first, second] {
+                    self.u32(regions.len() as u32);
+                    for (start, end) in regions.iter() {
+                        self.u64(*start);
+                        self.u64(*end);
+                    }
+                }
+            }
+            #[cfg(target_os = "linux")]
+            Diff::Xattrs(first, second) => {
+                for xattrs in &[first, second] {
+                    self.u32(xattrs.len() as u32);
+                    for (name, value) in xattrs.iter() {
+                        self.bytes(name.as_bytes());
+                        self.bytes(value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Fallible<&'a [u8]> {
+        if self.0.len() < count {
+            bail!("Truncated binary record");
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Fallible<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Fallible<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Fallible<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    #[cfg(unix)]
+    fn i64(&mut self) -> Fallible<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bool(&mut self) -> Fallible<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    fn bytes(&mut self) -> Fallible<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    #[cfg(unix)]
+    fn path(&mut self) -> Fallible<PathBuf> {
+        Ok(OsStr::from_bytes(self.bytes()?).into())
+    }
+
+    #[cfg(windows)]
+    fn path(&mut self) -> Fallible<PathBuf> {
+        Ok(std::str::from_utf8(self.bytes()?)?.into())
+    }
+
+    fn optional_path(&mut self) -> Fallible<Option<PathBuf>> {
+        Ok(match self.u8()? {
+            0 => None,
+            _ => Some(self.path()?),
+        })
+    }
+
+    /// Elements decoded by `element`, after their count
+    #[cfg(unix)]
+    fn sequence<T, C: FromIterator<T>>(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Fallible<T>,
+    ) -> Fallible<C> {
+        let count = self.u32()?;
+        (0..count).map(|_| element(self)).collect()
+    }
+
+    fn payload(&mut self, kind: u8) -> Fallible<Diff> {
+        Ok(match kind {
+            #[cfg(unix)]
+            0 => Diff::Modes(self.u32()?, self.u32()?),
+            #[cfg(unix)]
+            1 => Diff::Types(self.u32()?, self.u32()?),
+            #[cfg(unix)]
+            2 => Diff::Nlinks(self.u64()?, self.u64()?),
+            #[cfg(unix)]
+            3 => Diff::Mtimes(self.i64()?, self.i64()?),
+            #[cfg(unix)]
+            4 => Diff::IoBlockSize(self.u64()?, self.u64()?),
+            #[cfg(unix)]
+            5 => Diff::EntryCounts(self.u64()?, self.u64()?),
+            #[cfg(unix)]
+            6 => Diff::Uids(self.u32()?, self.u32()?),
+            #[cfg(unix)]
+            7 => Diff::Gids(self.u32()?, self.u32()?),
+            #[cfg(unix)]
+            8 => Diff::Inodes(self.optional_path()?, self.optional_path()?),
+            #[cfg(unix)]
+            9 => Diff::Sizes(self.i64()?, self.i64()?),
+            #[cfg(unix)]
+            10 => Diff::TrailingPadding(self.u64()?, self.u64()?),
+            11 => Diff::Contents(self.u64()?, self.u64()?, self.bytes()?.into(), self.bytes()?.into()),
+            12 => Diff::RelocatedContents(self.u64()?, self.u64()?, self.bytes()?.into(), self.bytes()?.into()),
+            #[cfg(unix)]
+            13 => {
+                let algorithm = match self.u8()? {
+                    0 => HashAlgorithm::Sha256,
+                    1 => HashAlgorithm::Sha512,
+                    #[cfg(feature = "blake3")]
+                    2 => HashAlgorithm::Blake3,
+                    algorithm => bail!("Unknown hash algorithm {} in binary record", algorithm),
+                };
+                Diff::ContentHash(algorithm, self.bytes()?.into(), self.bytes()?.into())
+            }
+            #[cfg(unix)]
+            14 => Diff::DeviceTypes(self.u64()?, self.u64()?),
+            #[cfg(unix)]
+            15 => Diff::LinkTarget(self.path()?, self.path()?),
+            #[cfg(unix)]
+            16 => Diff::DirContents(
+                self.sequence::<_, HashSet<_>>(Self::path)?,
+                self.sequence::<_, HashSet<_>>(Self::path)?,
+            ),
+            #[cfg(unix)]
+            17 => Diff::DirOrder(self.sequence(Self::path)?, self.sequence(Self::path)?),
+            #[cfg(target_os = "linux")]
+            18 => Diff::InodeFlags(self.u32()?, self.u32()?),
+            #[cfg(target_os = "linux")]
+            19 => Diff::Whiteouts(self.bool()?, self.bool()?),
+            #[cfg(target_os = "linux")]
+            20 => Diff::OpaqueDirs(self.bool()?, self.bool()?),
+            #[cfg(target_os = "linux")]
+            24 => Diff::Compression(self.bool()?, self.bool()?),
+            #[cfg(target_os = "linux")]
+            21 => Diff::ExtentSharing(self.u64()?, self.u64()?),
+            #[cfg(target_os = "linux")]
+            22 => {
+                let region = |decoder: &mut Self| Ok((decoder.u64()?, decoder.u64()?));
+                Diff::SparseLayout(self.sequence(region)?, self.sequence(region)?)
+            }
+            #[cfg(target_os = "linux")]
+            23 => {
+                let xattr = |decoder: &mut Self| {
+                    let name = String::from_utf8(decoder.bytes()?.into())?;
+                    Ok((name, decoder.bytes()?.into()))
+                };
+                Diff::Xattrs(
+                    self.sequence::<_, BTreeMap<_, _>>(xattr)?,
+                    self.sequence::<_, BTreeMap<_, _>>(xattr)?,
+                )
+            }
+            _ => bail!("Unknown kind of difference {} in binary record", kind),
+        })
+    }
+}
@@ -0,0 +1,28 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+
+/// Fold the case of a file name with Unicode full case folding, leaving names which aren't UTF-8 as they are
+///
+/// With `turkic` the dotted and dotless I fold as their Turkish and Azerbaijani pairs, instead of "I" folding to "i".
+pub fn fold(name: &OsStr, turkic: bool) -> OsString {
+    match name.to_str() {
+        Some(name) if turkic => caseless::default_case_fold_str(&name.replace('I', "ı").replace('İ', "i")).into(),
+        Some(name) => caseless::default_case_fold_str(name).into(),
+        None => name.to_os_string(),
+    }
+}
+
+/// Whether the character classification locale is Turkish or Azerbaijani, going by the environment like setlocale()
+pub fn is_turkic_locale() -> bool {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|locale| !locale.is_empty());
+    match locale {
+        Some(locale) => {
+            let language = locale.split(&['_', '.', '@'][..]).next().unwrap();
+            language == "tr" || language == "az"
+        }
+        None => false,
+    }
+}
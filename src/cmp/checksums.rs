@@ -0,0 +1,225 @@
+//! Verification of a tree against a checksum list as written by `sha256sum` or `sha512sum`
+//!
+//! Each line of a list holds the hash of a file in hex, a space, a space or `*` (text or binary mode, which hash
+//! the same) and the file's path relative to the tree:
+//!
+//! ```text
+//! e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  directory/regular_file
+//! ```
+//!
+//! The algorithm of each hash is told by its length. Lines starting with a backslash have their paths escaped,
+//! newlines as `\n`, carriage returns as `\r` and backslashes as `\\`.
+
+use super::manifest::unhex;
+use super::{first_unequal, Comparison, ContentSource, Decision, Diff, EntryInfo, FSCmp, HashAlgorithm};
+use failure::{bail, Fallible, ResultExt};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+impl FSCmp {
+    /// Compare the first tree against the checksum list given as the second argument
+    ///
+    /// Listed files missing from the tree are reported as differences in the contents of their directories, as are
+    /// regular files of the tree which aren't listed when `strict`. Listed entries which aren't regular files are
+    /// reported as differing in type, and files whose contents hash differently with differing hashes. Differences
+    /// are found in the order of their paths, all of them being reported with `with_report_all`.
+    pub fn check_sums(&self, strict: bool) -> Fallible<Comparison> {
+        let list = fs::read(&self.second).with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+        let listed = parse_sums(&list).with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+        let live: HashMap<_, _> = self
+            .with_watchdog(|| self.sum_entries(EntryInfo::dir(&self.first)?))?
+            .into_iter()
+            .collect();
+
+        let mut live_children: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+        let mut listed_children: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+        for path in listed.keys() {
+            let (parent, name) = split_path(path);
+            if live.contains_key(path) {
+                live_children.entry(parent.clone()).or_default().insert(name.clone());
+            }
+            listed_children.entry(parent).or_default().insert(name);
+        }
+        for (path, entry) in &live {
+            if listed.contains_key(path) {
+                continue;
+            }
+            if strict && file_type(entry) == libc::S_IFREG {
+                let (parent, name) = split_path(path);
+                live_children.entry(parent).or_default().insert(name);
+            } else {
+                // Not verified against the list, so recorded as skipped
+                self.decided(Some(path), Some(file_type(entry)), Decision::Skipped, None);
+            }
+        }
+        let mut decisions: Vec<_> = listed_children
+            .keys()
+            .chain(live_children.keys())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|parent| {
+                let live_names = live_children.get(parent).cloned().unwrap_or_default();
+                let listed_names = listed_children.get(parent).cloned().unwrap_or_default();
+                if live_names == listed_names {
+                    None
+                } else {
+                    let diff = Diff::DirContents(live_names, listed_names);
+                    Some((parent.clone(), libc::S_IFDIR, Some(diff)))
+                }
+            })
+            .collect();
+
+        let hash_diffs = listed
+            .par_iter()
+            .filter_map(|(path, (algorithm, hash))| Some((path, live.get(path)?, *algorithm, hash)))
+            .map(|(path, entry, algorithm, hash)| self.sum_diff(entry, algorithm, hash).map(|diff| (path, diff)))
+            .collect::<Fallible<Vec<_>>>()?;
+        decisions.extend(
+            hash_diffs
+                .into_iter()
+                .map(|(path, diff)| (path.clone(), file_type(&live[path]), diff)),
+        );
+        decisions.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut result = Comparison::Equal;
+        for (path, file_type, diff) in decisions {
+            let comp = match diff {
+                Some(diff) => self.report(diff, Some(path), Some(file_type)),
+                None => {
+                    self.decided(Some(&path), Some(file_type), Decision::Equal, None);
+                    Comparison::Equal
+                }
+            };
+            if comp != Comparison::Equal && !self.report_all {
+                return Ok(comp);
+            }
+            result = first_unequal(result, comp);
+        }
+        Ok(result)
+    }
+
+    /// Entries of a tree other than directories by relative path, for looking up the files of a checksum list
+    fn sum_entries(&self, entry: EntryInfo) -> Fallible<Vec<(PathBuf, EntryInfo)>> {
+        self.check_interrupted()?;
+        if file_type(&entry) != libc::S_IFDIR {
+            return Ok(vec![(entry.relative_path(), entry)]);
+        }
+        if self.ignore_files {
+            self.load_ignore_files(&entry, &entry)?;
+        }
+        let names: Vec<PathBuf> = self.retry(&entry.path, || Ok(self.list_dir(&entry)?))?;
+        let children = names
+            .par_iter()
+            .map(|name| {
+                let child = self.retry(&entry.path.join(name), || entry.child_entry(name))?;
+                self.sum_entries(child)
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        Ok(children.into_iter().flatten().collect())
+    }
+
+    /// The difference between a listed entry and its listed hash, if any
+    fn sum_diff(&self, entry: &EntryInfo, algorithm: HashAlgorithm, hash: &[u8]) -> Fallible<Option<Diff>> {
+        self.check_interrupted()?;
+        let file_type = file_type(entry);
+        if file_type != libc::S_IFREG {
+            return Ok(Some(Diff::Types(file_type, libc::S_IFREG)));
+        }
+        let size = entry.metadata.len();
+        let live_hash = algorithm.contents_hash(&ContentSource::open(entry)?, 0, size)?;
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+        Ok(if live_hash == hash {
+            None
+        } else {
+            Some(Diff::ContentHash(algorithm, live_hash, hash.to_vec()))
+        })
+    }
+}
+
+fn file_type(entry: &EntryInfo) -> u32 {
+    entry.metadata.stat().st_mode & libc::S_IFMT
+}
+
+/// The directory of a relative path, "." for the root, and its name
+fn split_path(path: &Path) -> (PathBuf, PathBuf) {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+        _ => ".".into(),
+    };
+    (parent, path.file_name().unwrap_or_default().into())
+}
+
+/// The listed hashes by relative path, the last one counting for paths listed more than once
+fn parse_sums(list: &[u8]) -> Fallible<BTreeMap<PathBuf, (HashAlgorithm, Vec<u8>)>> {
+    let mut sums = BTreeMap::new();
+    for (index, line) in list.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let (escaped, line) = match line.strip_prefix(b"\\") {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (hex, path) = match line.iter().position(|b| *b == b' ') {
+            Some(space) if matches!(line.get(space + 1), Some(b' ') | Some(b'*')) && line.len() > space + 2 => {
+                (&line[..space], &line[space + 2..])
+            }
+            _ => bail!("Line {} isn't a hash followed by a path", index + 1),
+        };
+        let hash = std::str::from_utf8(hex).ok().map(unhex).unwrap_or_default();
+        if hash.len() * 2 != hex.len() {
+            bail!("Line {} holds a hash with invalid hex digits", index + 1);
+        }
+        let algorithm = match hash.len() {
+            32 => HashAlgorithm::Sha256,
+            64 => HashAlgorithm::Sha512,
+            _ => bail!(
+                "Line {} doesn't hold a SHA-256 or SHA-512 hash, the only ones supported",
+                index + 1
+            ),
+        };
+        let path = if escaped { unescape(path) } else { path.to_vec() };
+        let path = Path::new(OsStr::from_bytes(&path));
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!(
+                "Line {} lists \"{}\", which isn't relative and inside the tree",
+                index + 1,
+                path.display()
+            );
+        }
+        let path: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+        if path.as_os_str().is_empty() {
+            bail!("Line {} lists the tree itself rather than a file in it", index + 1);
+        }
+        sums.insert(path, (algorithm, hash));
+    }
+    Ok(sums)
+}
+
+fn unescape(path: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        let escaped = match (b, bytes.peek()) {
+            (b'\\', Some(b'n')) => b'\n',
+            (b'\\', Some(b'r')) => b'\r',
+            (b'\\', Some(b'\\')) => b'\\',
+            _ => {
+                unescaped.push(b);
+                continue;
+            }
+        };
+        unescaped.push(escaped);
+        bytes.next();
+    }
+    unescaped
+}
@@ -1,12 +1,23 @@
 #[cfg(unix)]
-use std::collections::HashSet;
+use super::digest::HashAlgorithm;
+#[cfg(target_os = "linux")]
+use super::ioctl::INODE_FLAGS;
+use serde::Serialize;
+#[cfg(unix)]
+use serde::Serializer;
+use std::collections::BTreeMap;
+#[cfg(unix)]
+use std::collections::{BTreeSet, HashSet};
 use std::fmt;
 use std::path::PathBuf;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
 pub enum Comparison {
     Equal,
     Unequal {
+        #[serde(flatten)]
         diff: Diff,
         first: PathBuf,
         second: PathBuf,
@@ -14,12 +25,25 @@ pub enum Comparison {
     },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "payload")]
 pub enum Diff {
     #[cfg(unix)]
     Modes(u32, u32),
+    /// File types, as the `S_IFMT` bits of the modes
+    #[cfg(unix)]
+    Types(u32, u32),
     #[cfg(unix)]
     Nlinks(u64, u64),
+    /// Modification times, in nanoseconds since the epoch
+    #[cfg(unix)]
+    Mtimes(i64, i64),
+    /// Preferred I/O block sizes, which reflect the filesystems rather than the files
+    #[cfg(unix)]
+    IoBlockSize(u64, u64),
+    /// Entries under a directory, recursively and counting the directory itself
+    #[cfg(unix)]
+    EntryCounts(u64, u64),
     #[cfg(unix)]
     Uids(u32, u32),
     #[cfg(unix)]
@@ -28,18 +52,344 @@ pub enum Diff {
     Inodes(Option<PathBuf>, Option<PathBuf>),
     #[cfg(unix)]
     Sizes(i64, i64),
-    Contents(u64, Vec<u8>, Vec<u8>),
+    /// Sizes of files whose contents only differ by trailing zeroes in the longer one
+    #[cfg(unix)]
+    TrailingPadding(u64, u64),
+    /// The differing block, the offset of the first differing byte and the blocks read around it
+    Contents(u64, u64, Vec<u8>, Vec<u8>),
+    /// Differing blocks of contents compared at different offsets, numbered from the start of each side
+    RelocatedContents(u64, u64, Vec<u8>, Vec<u8>),
+    /// Hashes of the whole compared contents of each side
+    #[cfg(unix)]
+    ContentHash(
+        HashAlgorithm,
+        #[serde(serialize_with = "serialize_hex")] Vec<u8>,
+        #[serde(serialize_with = "serialize_hex")] Vec<u8>,
+    ),
     #[cfg(unix)]
     DeviceTypes(u64, u64),
     #[cfg(unix)]
     LinkTarget(PathBuf, PathBuf),
     #[cfg(unix)]
-    DirContents(HashSet<PathBuf>, HashSet<PathBuf>),
+    DirContents(
+        #[serde(serialize_with = "serialize_sorted")] HashSet<PathBuf>,
+        #[serde(serialize_with = "serialize_sorted")] HashSet<PathBuf>,
+    ),
+    /// Entries present on both sides in the order each directory lists them
+    #[cfg(unix)]
+    DirOrder(Vec<PathBuf>, Vec<PathBuf>),
+    #[cfg(target_os = "linux")]
+    InodeFlags(u32, u32),
+    /// Whether each side is an OverlayFS whiteout, the other side being absent or not a whiteout
+    #[cfg(target_os = "linux")]
+    Whiteouts(bool, bool),
+    /// Whether each directory is an opaque OverlayFS directory
+    #[cfg(target_os = "linux")]
+    OpaqueDirs(bool, bool),
+    /// Bytes physically shared by identical files, out of their size
+    #[cfg(target_os = "linux")]
+    ExtentSharing(u64, u64),
+    /// Start and end offsets of the regions holding data, in files with identical contents
+    #[cfg(target_os = "linux")]
+    SparseLayout(Vec<(u64, u64)>, Vec<(u64, u64)>),
+    /// Values of the extended attributes which differ by name, those not set on a side being left out of it
+    #[cfg(target_os = "linux")]
+    Xattrs(BTreeMap<String, Vec<u8>>, BTreeMap<String, Vec<u8>>),
+    /// Whether each side is marked for transparent compression by its filesystem
+    #[cfg(target_os = "linux")]
+    Compression(bool, bool),
+}
+
+impl Diff {
+    /// Name of this kind of difference, the one it's serialized with
+    #[cfg(any(unix, feature = "structured-log"))]
+    pub(super) fn kind_name(&self) -> Option<String> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(mut object)) => match object.remove("kind") {
+                Some(serde_json::Value::String(kind)) => Some(kind),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Singular and plural names of what's counted for this kind of difference in a summary
+    fn summary_names(&self) -> (&'static str, &'static str) {
+        match self {
+            #[cfg(unix)]
+            Diff::Modes(..) => ("mode diff", "mode diffs"),
+            #[cfg(unix)]
+            Diff::Types(..) => ("type diff", "type diffs"),
+            #[cfg(unix)]
+            Diff::Nlinks(..) => ("hard link count diff", "hard link count diffs"),
+            #[cfg(unix)]
+            Diff::Mtimes(..) => ("modification time diff", "modification time diffs"),
+            #[cfg(unix)]
+            Diff::IoBlockSize(..) => ("I/O block size diff", "I/O block size diffs"),
+            #[cfg(unix)]
+            Diff::EntryCounts(..) => ("entry count diff", "entry count diffs"),
+            #[cfg(unix)]
+            Diff::Uids(..) => ("UID diff", "UID diffs"),
+            #[cfg(unix)]
+            Diff::Gids(..) => ("GID diff", "GID diffs"),
+            #[cfg(unix)]
+            Diff::Inodes(..) => ("hard link diff", "hard link diffs"),
+            #[cfg(unix)]
+            Diff::Sizes(..) => ("size diff", "size diffs"),
+            #[cfg(unix)]
+            Diff::TrailingPadding(..) => ("trailing padding diff", "trailing padding diffs"),
+            Diff::Contents(..) | Diff::RelocatedContents(..) => ("content diff", "content diffs"),
+            #[cfg(unix)]
+            Diff::ContentHash(..) => ("content diff", "content diffs"),
+            #[cfg(unix)]
+            Diff::DeviceTypes(..) => ("device number diff", "device number diffs"),
+            #[cfg(unix)]
+            Diff::LinkTarget(..) => ("link target diff", "link target diffs"),
+            #[cfg(unix)]
+            Diff::DirContents(..) => ("missing entry", "missing entries"),
+            #[cfg(unix)]
+            Diff::DirOrder(..) => ("entry order diff", "entry order diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::InodeFlags(..) => ("inode flags diff", "inode flags diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::Whiteouts(..) => ("whiteout diff", "whiteout diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::OpaqueDirs(..) => ("opaque directory diff", "opaque directory diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::ExtentSharing(..) => ("extent sharing diff", "extent sharing diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::SparseLayout(..) => ("sparse layout diff", "sparse layout diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::Xattrs(..) => ("xattr diff", "xattr diffs"),
+            #[cfg(target_os = "linux")]
+            Diff::Compression(..) => ("compression diff", "compression diffs"),
+        }
+    }
+
+    /// How much this difference counts for in a summary, directory contents counting each missing entry
+    fn summary_count(&self) -> u64 {
+        match self {
+            #[cfg(unix)]
+            Diff::DirContents(first, second) => first.symmetric_difference(second).count() as u64,
+            _ => 1,
+        }
+    }
+}
+
+/// Tally of differences by kind, e.g. "3 content diffs, 1 mode diff, 2 missing entries"
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    counts: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+impl DiffSummary {
+    pub fn add(&mut self, comp: &Comparison) {
+        if let Comparison::Unequal { diff, .. } = comp {
+            *self.counts.entry(diff.summary_names()).or_default() += diff.summary_count();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, ((singular, plural), count)) in self.counts.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", count, if *count == 1 { singular } else { plural })?;
+        }
+        Ok(())
+    }
+}
+
+/// How much work a comparison took
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ComparisonStats {
+    /// Bytes of contents read, from both sides together
+    pub bytes_read: u64,
+    /// Entries of the trees compared, the roots included
+    pub entries_compared: u64,
+    pub elapsed: Duration,
+}
+
+/// Reading contents averaging this many bytes per entry is taken to be most of a comparison's work
+const PROGRESS_BYTES_PER_ENTRY: u64 = 256 * 1024;
+
+/// Work done so far by a running comparison, out of the work it's estimated to take
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Bytes of contents read, from both sides together
+    pub bytes_read: u64,
+    /// Bytes the files to compare are expected to take reading, `None` if unknown
+    pub bytes_total: Option<u64>,
+    pub entries_compared: u64,
+    /// Entries counted in the first tree before comparing, `None` if unknown
+    pub entries_total: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Fraction of the work done, measured in bytes if reading contents is most of it and in entries otherwise
+    pub fn fraction(&self) -> Option<f64> {
+        let by_bytes = match (self.bytes_total, self.entries_total) {
+            (Some(_), None) => true,
+            (Some(bytes), Some(entries)) => bytes / entries.max(1) >= PROGRESS_BYTES_PER_ENTRY,
+            _ => false,
+        };
+        let (done, total) = if by_bytes {
+            (self.bytes_read, self.bytes_total?)
+        } else {
+            (self.entries_compared, self.entries_total?)
+        };
+        Some(if total == 0 {
+            1.0
+        } else {
+            (done as f64 / total as f64).min(1.0)
+        })
+    }
+
+    /// Time left at the pace of the work done so far
+    pub fn eta(&self) -> Option<Duration> {
+        match self.fraction()? {
+            fraction if fraction > 0.0 => Some(self.elapsed.mul_f64((1.0 - fraction) / fraction)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.entries_total {
+            Some(entries_total) => write!(f, "{} of {} entries compared, ", self.entries_compared, entries_total)?,
+            None if self.entries_compared > 0 => write!(f, "{} entries compared, ", self.entries_compared)?,
+            None => (),
+        }
+        write!(f, "{} bytes read", self.bytes_read)?;
+        if let Some(fraction) = self.fraction() {
+            write!(f, " ({:.0}%)", fraction * 100.0)?;
+        }
+        if let Some(eta) = self.eta() {
+            let secs = eta.as_secs();
+            write!(f, ", ETA {}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a comparison decided about an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Equal,
+    Unequal,
+    /// Excluded from the comparison by the ignored paths or ignore files
+    Ignored,
+    /// Missing on one side
+    Skipped,
+    /// Contents compared equal, counting the chunks read
+    ContentsEqual,
+    /// Differing, but modified during the comparison and so not reported
+    Changed,
+    /// Differing where differences are allowed
+    Allowed,
+    /// Unchanged on both sides relative to the reference tree, and so not compared
+    Unchanged,
+    /// Regular files whose metadata compared equal, their contents being out of the compared size range
+    OutOfSizeRange,
+}
+
+/// A decision about an entry as passed to the entry handler, with the difference found if any
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntryDecision<'a> {
+    pub decision: Decision,
+    pub diff: Option<&'a Diff>,
+}
+
+/// How differences are rendered as text
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DisplayOptions {
+    hex_width: usize,
+    // Only metadata differences, which don't exist on Windows, are colored
+    #[cfg_attr(windows, allow(dead_code))]
+    color: bool,
+}
+
+impl Default for DisplayOptions {
+    fn default() -> Self {
+        Self {
+            hex_width: 32,
+            color: false,
+        }
+    }
+}
+
+impl DisplayOptions {
+    /// Bytes per line of differing blocks, 32 by default
+    pub fn with_hex_width(mut self, hex_width: usize) -> Self {
+        assert!(hex_width > 0, "Hex width must be positive");
+        self.hex_width = hex_width;
+        self
+    }
+
+    /// Highlight the values of metadata differences with ANSI colors, aligning them in a column
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+}
+
+/// A comparison rendered with non-default `DisplayOptions`
+pub struct ComparisonDisplay<'a> {
+    comp: &'a Comparison,
+    options: DisplayOptions,
+}
+
+/// A comparison rendered on a single line, e.g. `MODE path/to/file 0o644 0o600`
+///
+/// The line is the kind of difference, the path it was found at ("-" if unknown) and the values on each side.
+/// Content differences found at the same offset of both sides end with the offset of the first differing byte.
+pub struct OnelineDisplay<'a>(&'a Comparison);
+
+impl Comparison {
+    pub fn display(&self, options: DisplayOptions) -> ComparisonDisplay<'_> {
+        ComparisonDisplay { comp: self, options }
+    }
+
+    pub fn oneline(&self) -> OnelineDisplay<'_> {
+        OnelineDisplay(self)
+    }
 }
 
 impl fmt::Display for Comparison {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
+        self.display(DisplayOptions::default()).fmt(f)
+    }
+}
+
+#[cfg(unix)]
+impl<'a> ComparisonDisplay<'a> {
+    fn sides<'p, T: fmt::Display>(
+        &self,
+        first_path: &'p str,
+        first: T,
+        second_path: &'p str,
+        second: T,
+    ) -> SidesFormat<'p, T> {
+        SidesFormat {
+            first: (first_path, first),
+            second: (second_path, second),
+            color: self.options.color,
+        }
+    }
+}
+
+impl<'a> fmt::Display for ComparisonDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.comp {
             Comparison::Equal => Ok(()),
             Comparison::Unequal {
                 diff,
@@ -58,26 +408,55 @@ impl fmt::Display for Comparison {
                     #[cfg(unix)]
                     Diff::Modes(first, second) => write!(
                         f,
-                        "File mode\nFrom \"{}\": 0o{:o}\nFrom \"{}\": 0o{:o}",
-                        first_path, first, second_path, second
+                        "File mode\n{}",
+                        self.sides(
+                            &first_path,
+                            format!("0o{:o} ({})", first, ModeFormat(*first)),
+                            &second_path,
+                            format!("0o{:o} ({})", second, ModeFormat(*second))
+                        )
+                    ),
+                    #[cfg(unix)]
+                    Diff::Types(first, second) => write!(
+                        f,
+                        "File type\n{}",
+                        self.sides(&first_path, TypeFormat(*first), &second_path, TypeFormat(*second))
                     ),
                     #[cfg(unix)]
                     Diff::Nlinks(first, second) => write!(
                         f,
-                        "Hard links number\nFrom \"{}\": {}\nFrom \"{}\": {}",
-                        first_path, first, second_path, second
+                        "Hard links number\n{}",
+                        self.sides(&first_path, first, &second_path, second)
+                    ),
+                    #[cfg(unix)]
+                    Diff::Mtimes(first, second) => write!(
+                        f,
+                        "Modification time\n{}",
+                        self.sides(&first_path, TimeFormat(*first), &second_path, TimeFormat(*second))
+                    ),
+                    #[cfg(unix)]
+                    Diff::IoBlockSize(first, second) => write!(
+                        f,
+                        "Preferred I/O block size\n{}",
+                        self.sides(&first_path, first, &second_path, second)
+                    ),
+                    #[cfg(unix)]
+                    Diff::EntryCounts(first, second) => write!(
+                        f,
+                        "Recursive entry count\n{}",
+                        self.sides(&first_path, first, &second_path, second)
                     ),
                     #[cfg(unix)]
                     Diff::Uids(first, second) => write!(
                         f,
-                        "UID\nFrom \"{}\": {}\nFrom \"{}\": {}",
-                        first_path, first, second_path, second
+                        "UID\n{}",
+                        self.sides(&first_path, first, &second_path, second)
                     ),
                     #[cfg(unix)]
                     Diff::Gids(first, second) => write!(
                         f,
-                        "GID\nFrom \"{}\": {}\nFrom \"{}\": {}",
-                        first_path, first, second_path, second
+                        "GID\n{}",
+                        self.sides(&first_path, first, &second_path, second)
                     ),
                     #[cfg(unix)]
                     Diff::Inodes(first, second) => write!(
@@ -91,23 +470,53 @@ impl fmt::Display for Comparison {
                     #[cfg(unix)]
                     Diff::Sizes(first, second) => write!(
                         f,
-                        "Size\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        "Size\n{}",
+                        self.sides(&first_path, first, &second_path, second)
+                    ),
+                    #[cfg(unix)]
+                    Diff::TrailingPadding(first, second) => write!(
+                        f,
+                        "Trailing zeroes only\nFrom \"{}\": {} bytes\nFrom \"{}\": {} bytes",
                         first_path, first, second_path, second
                     ),
-                    Diff::Contents(lba, first, second) => write!(
+                    Diff::Contents(lba, offset, first, second) => write!(
                         f,
-                        "Block {}\nFrom \"{}\":\n{}\nFrom \"{}\":\n{}",
+                        "Block {}, first differing at byte {}\nFrom \"{}\":\n{}\nFrom \"{}\":\n{}",
                         lba,
+                        offset,
+                        first_path,
+                        BlockFormat(first, self.options.hex_width),
+                        second_path,
+                        BlockFormat(second, self.options.hex_width)
+                    ),
+                    Diff::RelocatedContents(first_lba, second_lba, first, second) => write!(
+                        f,
+                        "Blocks {} and {}\nFrom \"{}\":\n{}\nFrom \"{}\":\n{}",
+                        first_lba,
+                        second_lba,
+                        first_path,
+                        BlockFormat(first, self.options.hex_width),
+                        second_path,
+                        BlockFormat(second, self.options.hex_width)
+                    ),
+                    #[cfg(unix)]
+                    Diff::ContentHash(algorithm, first, second) => write!(
+                        f,
+                        "Contents {}\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        algorithm,
                         first_path,
-                        BlockFormat(first),
+                        hex(first),
                         second_path,
-                        BlockFormat(second)
+                        hex(second)
                     ),
                     #[cfg(unix)]
                     Diff::DeviceTypes(first, second) => write!(
                         f,
                         "Device type\nFrom \"{}\": {}\nFrom \"{}\": {}",
-                        first_path, first, second_path, second
+                        first_path,
+                        DeviceFormat(*first),
+                        second_path,
+                        DeviceFormat(*second)
                     ),
                     #[cfg(unix)]
                     Diff::LinkTarget(first, second) => write!(
@@ -121,22 +530,302 @@ impl fmt::Display for Comparison {
                     #[cfg(unix)]
                     Diff::DirContents(first, second) => write!(
                         f,
-                        "Dir contents\nFrom \"{}\": {:#?}\nFrom \"{}\": {:#?}",
+                        "Dir contents\n\"{}\" has {} {}, \"{}\" has {}\nOnly in \"{}\": {:#?}\nOnly in \"{}\": {:#?}",
+                        first_path,
+                        first.len(),
+                        if first.len() == 1 { "entry" } else { "entries" },
+                        second_path,
+                        second.len(),
+                        first_path,
+                        first.difference(second).collect::<BTreeSet<_>>(),
+                        second_path,
+                        second.difference(first).collect::<BTreeSet<_>>()
+                    ),
+                    #[cfg(unix)]
+                    Diff::DirOrder(first, second) => write!(
+                        f,
+                        "Dir order\nFrom \"{}\": {:#?}\nFrom \"{}\": {:#?}",
                         first_path, first, second_path, second
                     ),
+                    #[cfg(target_os = "linux")]
+                    Diff::InodeFlags(first, second) => write!(
+                        f,
+                        "Inode flags\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        first_path,
+                        InodeFlagsFormat(*first),
+                        second_path,
+                        InodeFlagsFormat(*second)
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::Whiteouts(first, second) => write!(
+                        f,
+                        "Overlay whiteout\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        first_path,
+                        if *first { "whiteout" } else { "no whiteout" },
+                        second_path,
+                        if *second { "whiteout" } else { "no whiteout" }
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::OpaqueDirs(first, second) => write!(
+                        f,
+                        "Overlay opaque directory\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        first_path,
+                        if *first { "opaque" } else { "not opaque" },
+                        second_path,
+                        if *second { "opaque" } else { "not opaque" }
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::Compression(first, second) => write!(
+                        f,
+                        "Compression\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        first_path,
+                        if *first { "compressed" } else { "not compressed" },
+                        second_path,
+                        if *second { "compressed" } else { "not compressed" }
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::ExtentSharing(shared, size) => write!(
+                        f,
+                        "Extent sharing\n\"{}\" and \"{}\" share {} of {} bytes",
+                        first_path, second_path, shared, size
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::SparseLayout(first, second) => write!(
+                        f,
+                        "Sparse layout\nData of \"{}\": {}\nData of \"{}\": {}",
+                        first_path,
+                        regions(first, ", "),
+                        second_path,
+                        regions(second, ", ")
+                    ),
+                    #[cfg(target_os = "linux")]
+                    Diff::Xattrs(first, second) => write!(
+                        f,
+                        "Extended attributes\nFrom \"{}\": {}\nFrom \"{}\": {}",
+                        first_path,
+                        xattrs(first),
+                        second_path,
+                        xattrs(second)
+                    ),
                 }
             }
         }
     }
 }
 
-struct BlockFormat<'a>(&'a [u8]);
+impl<'a> fmt::Display for OnelineDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (diff, path) = match self.0 {
+            Comparison::Equal => return Ok(()),
+            Comparison::Unequal { diff, path, .. } => (diff, path),
+        };
+        let path = path.as_ref().map_or("-".into(), |path| path.to_string_lossy());
+        match diff {
+            #[cfg(unix)]
+            Diff::Modes(first, second) => write!(f, "MODE {} 0o{:o} 0o{:o}", path, first, second),
+            #[cfg(unix)]
+            Diff::Types(first, second) => write!(
+                f,
+                "TYPE {} {} {}",
+                path,
+                TypeFormat(*first).to_string().replace(' ', "-"),
+                TypeFormat(*second).to_string().replace(' ', "-")
+            ),
+            #[cfg(unix)]
+            Diff::Nlinks(first, second) => write!(f, "NLINK {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::Mtimes(first, second) => write!(f, "MTIME {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::IoBlockSize(first, second) => write!(f, "BLKSIZE {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::EntryCounts(first, second) => write!(f, "COUNT {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::Uids(first, second) => write!(f, "UID {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::Gids(first, second) => write!(f, "GID {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::Inodes(first, second) => {
+                let link = |link: &Option<PathBuf>| {
+                    link.as_ref()
+                        .map_or("-".to_string(), |link| link.to_string_lossy().into_owned())
+                };
+                write!(f, "HARDLINK {} {} {}", path, link(first), link(second))
+            }
+            #[cfg(unix)]
+            Diff::Sizes(first, second) => write!(f, "SIZE {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::TrailingPadding(first, second) => write!(f, "PADDING {} {} {}", path, first, second),
+            Diff::Contents(lba, offset, ..) => write!(f, "CONTENT {} {} {} {}", path, lba, lba, offset),
+            Diff::RelocatedContents(first_lba, second_lba, ..) => {
+                write!(f, "CONTENT {} {} {}", path, first_lba, second_lba)
+            }
+            #[cfg(unix)]
+            Diff::ContentHash(algorithm, first, second) => write!(
+                f,
+                "HASH {} {}:{} {}:{}",
+                path,
+                algorithm,
+                hex(first),
+                algorithm,
+                hex(second)
+            ),
+            #[cfg(unix)]
+            Diff::DeviceTypes(first, second) => write!(f, "DEVICE {} {} {}", path, first, second),
+            #[cfg(unix)]
+            Diff::LinkTarget(first, second) => write!(
+                f,
+                "LINK {} {} {}",
+                path,
+                first.to_string_lossy(),
+                second.to_string_lossy()
+            ),
+            #[cfg(unix)]
+            Diff::DirContents(first, second) => {
+                let only_in = |contents: &HashSet<PathBuf>, other: &HashSet<PathBuf>| {
+                    let names: Vec<_> = sorted(contents)
+                        .into_iter()
+                        .filter(|name| !other.contains(*name))
+                        .map(|name| name.to_string_lossy())
+                        .collect();
+                    if names.is_empty() {
+                        "-".to_string()
+                    } else {
+                        names.join(",")
+                    }
+                };
+                write!(
+                    f,
+                    "ENTRIES {} {} {}",
+                    path,
+                    only_in(first, second),
+                    only_in(second, first)
+                )
+            }
+            #[cfg(unix)]
+            Diff::DirOrder(first, second) => {
+                let order = |names: &[PathBuf]| {
+                    let names: Vec<_> = names.iter().map(|name| name.to_string_lossy()).collect();
+                    names.join(",")
+                };
+                write!(f, "ORDER {} {} {}", path, order(first), order(second))
+            }
+            #[cfg(target_os = "linux")]
+            Diff::InodeFlags(first, second) => write!(f, "FLAGS {} {:#x} {:#x}", path, first, second),
+            #[cfg(target_os = "linux")]
+            Diff::Whiteouts(first, second) => write!(f, "WHITEOUT {} {} {}", path, first, second),
+            #[cfg(target_os = "linux")]
+            Diff::Compression(first, second) => write!(f, "COMPRESSION {} {} {}", path, first, second),
+            #[cfg(target_os = "linux")]
+            Diff::OpaqueDirs(first, second) => write!(f, "OPAQUE {} {} {}", path, first, second),
+            #[cfg(target_os = "linux")]
+            Diff::ExtentSharing(shared, size) => write!(f, "EXTENTS {} {} {}", path, shared, size),
+            #[cfg(target_os = "linux")]
+            Diff::SparseLayout(first, second) => {
+                write!(f, "SPARSE {} {} {}", path, regions(first, ","), regions(second, ","))
+            }
+            #[cfg(target_os = "linux")]
+            Diff::Xattrs(first, second) => {
+                let names = |xattrs: &BTreeMap<String, Vec<u8>>| {
+                    if xattrs.is_empty() {
+                        "-".to_string()
+                    } else {
+                        xattrs.keys().cloned().collect::<Vec<_>>().join(",")
+                    }
+                };
+                write!(f, "XATTRS {} {} {}", path, names(first), names(second))
+            }
+        }
+    }
+}
 
-impl<'a> fmt::Display for BlockFormat<'a> {
+/// Data regions as `start-end` offsets, or `none` for a file that's all holes
+#[cfg(target_os = "linux")]
+fn regions(regions: &[(u64, u64)], separator: &str) -> String {
+    if regions.is_empty() {
+        return "none".into();
+    }
+    regions
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Extended attributes as `name="value"`, values being escaped where they aren't printable UTF-8, or `none`
+#[cfg(target_os = "linux")]
+fn xattrs(xattrs: &BTreeMap<String, Vec<u8>>) -> String {
+    if xattrs.is_empty() {
+        return "none".into();
+    }
+    xattrs
+        .iter()
+        .map(|(name, value)| format!("{}={:?}", name, String::from_utf8_lossy(value)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Directory entries in a stable order, for reproducible output
+#[cfg(unix)]
+fn sorted(paths: &HashSet<PathBuf>) -> BTreeSet<&PathBuf> {
+    paths.iter().collect()
+}
+
+#[cfg(unix)]
+fn serialize_sorted<S: Serializer>(paths: &HashSet<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+    sorted(paths).serialize(serializer)
+}
+
+/// Bytes as pairs of lowercase hex digits
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(unix)]
+fn serialize_hex<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex(bytes))
+}
+
+/// The values of a metadata difference on each side, on lines of their own
+#[cfg(unix)]
+struct SidesFormat<'a, T> {
+    first: (&'a str, T),
+    second: (&'a str, T),
+    color: bool,
+}
+
+#[cfg(unix)]
+impl<'a, T: fmt::Display> fmt::Display for SidesFormat<'a, T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        const BYTES_IN_LINE: usize = 32;
+        let ((first_path, first), (second_path, second)) = (&self.first, &self.second);
+        if !self.color {
+            return write!(
+                f,
+                "From \"{}\": {}\nFrom \"{}\": {}",
+                first_path, first, second_path, second
+            );
+        }
+        let (first_label, second_label) = (
+            format!("From \"{}\":", first_path),
+            format!("From \"{}\":", second_path),
+        );
+        let width = first_label.chars().count().max(second_label.chars().count());
+        write!(
+            f,
+            "{:<width$} \x1b[1;31m{}\x1b[0m\n{:<width$} \x1b[1;32m{}\x1b[0m",
+            first_label,
+            first,
+            second_label,
+            second,
+            width = width
+        )
+    }
+}
+
+struct BlockFormat<'a>(&'a [u8], usize);
 
-        for chunk in self.0.chunks(BYTES_IN_LINE) {
+impl<'a> fmt::Display for BlockFormat<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.chunks(self.1) {
             for b in chunk {
                 write!(f, "{:02x} ", b)?;
             }
@@ -147,6 +836,86 @@ impl<'a> fmt::Display for BlockFormat<'a> {
     }
 }
 
+/// Nanoseconds since the epoch as seconds, e.g. "1571234567.000000001"
+#[cfg(unix)]
+struct TimeFormat(i64);
+
+#[cfg(unix)]
+impl fmt::Display for TimeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:09}",
+            self.0.div_euclid(1_000_000_000),
+            self.0.rem_euclid(1_000_000_000)
+        )
+    }
+}
+
+#[cfg(unix)]
+struct TypeFormat(u32);
+
+#[cfg(unix)]
+impl fmt::Display for TypeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.0 as libc::mode_t {
+            libc::S_IFDIR => "directory",
+            libc::S_IFREG => "regular file",
+            libc::S_IFLNK => "symbolic link",
+            libc::S_IFBLK => "block device",
+            libc::S_IFCHR => "character device",
+            libc::S_IFIFO => "FIFO",
+            libc::S_IFSOCK => "socket",
+            _ => return write!(f, "0o{:o}", self.0),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A mode as `ls -l` shows it, e.g. "-rw-r--r--"
+#[cfg(unix)]
+struct ModeFormat(u32);
+
+#[cfg(unix)]
+impl fmt::Display for ModeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = self.0 as libc::mode_t;
+        let file_type = match mode & libc::S_IFMT {
+            libc::S_IFDIR => 'd',
+            libc::S_IFREG => '-',
+            libc::S_IFLNK => 'l',
+            libc::S_IFBLK => 'b',
+            libc::S_IFCHR => 'c',
+            libc::S_IFIFO => 'p',
+            libc::S_IFSOCK => 's',
+            _ => '?',
+        };
+        write!(f, "{}", file_type)?;
+        // Each class's execute bit shares its place with a special bit
+        for (shift, special, set) in &[
+            (6, libc::S_ISUID, 's'),
+            (3, libc::S_ISGID, 's'),
+            (0, libc::S_ISVTX, 't'),
+        ] {
+            let bits = (mode >> shift) & 0o7;
+            let execute = match (bits & 0o1 != 0, mode & special != 0) {
+                (false, false) => '-',
+                (true, false) => 'x',
+                (false, true) => set.to_ascii_uppercase(),
+                (true, true) => *set,
+            };
+            write!(
+                f,
+                "{}{}{}",
+                if bits & 0o4 != 0 { 'r' } else { '-' },
+                if bits & 0o2 != 0 { 'w' } else { '-' },
+                execute
+            )?;
+        }
+        Ok(())
+    }
+}
+
 struct OptionFormat<'a, T>(&'a Option<T>);
 
 impl<'a> fmt::Display for OptionFormat<'a, PathBuf> {
@@ -157,3 +926,46 @@ impl<'a> fmt::Display for OptionFormat<'a, PathBuf> {
         }
     }
 }
+
+/// A raw device number, followed by its major and minor numbers where they can be decoded
+#[cfg(unix)]
+struct DeviceFormat(u64);
+
+#[cfg(unix)]
+impl fmt::Display for DeviceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        #[cfg(target_os = "linux")]
+        write!(
+            f,
+            " ({}:{})",
+            nix::sys::stat::major(self.0),
+            nix::sys::stat::minor(self.0)
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct InodeFlagsFormat(u32);
+
+#[cfg(target_os = "linux")]
+impl fmt::Display for InodeFlagsFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x} (", self.0)?;
+        let mut names = INODE_FLAGS
+            .iter()
+            .filter(|(flag, _)| self.0 & flag != 0)
+            .map(|(_, name)| name);
+        match names.next() {
+            None => write!(f, "none")?,
+            Some(name) => {
+                write!(f, "{}", name)?;
+                for name in names {
+                    write!(f, ", {}", name)?;
+                }
+            }
+        }
+        write!(f, ")")
+    }
+}
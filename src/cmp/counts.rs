@@ -0,0 +1,105 @@
+//! Comparison of how many entries are under each directory, a quick check of trees expected to be copies
+//!
+//! The entries of each tree are counted without comparing them, and where the counts diverge is found by
+//! descending from the roots into the directories whose counts differ.
+
+use super::{Comparison, Diff, EntryInfo, FSCmp};
+use failure::{Fallible, ResultExt};
+use log::info;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The entries under a directory, or a single entry for anything else
+struct Tally {
+    /// Entries under it, recursively and counting itself
+    entries: u64,
+    /// Entries directly in it
+    children: u64,
+    /// Tallies of its subdirectories by name
+    subdirs: HashMap<PathBuf, Tally>,
+}
+
+impl FSCmp {
+    /// Compare how many entries are under each directory, instead of comparing the entries themselves
+    ///
+    /// Trees counted differently are reported at the first directory, by name, found by descending into the first
+    /// subdirectory whose count differs as long as there are as many entries directly in each side. The difference
+    /// is a `Diff::EntryCounts` of that directory, the roots being reported with the totals of the trees. Trees
+    /// with the same totals are equal, even if entries differ between their subtrees.
+    pub fn count_entries(&self) -> Fallible<Comparison> {
+        self.warn_if_same()?;
+        self.with_watchdog(|| {
+            let (first, second) = (EntryInfo::dir(&self.first)?, EntryInfo::dir(&self.second)?);
+            let (first_tally, second_tally) = rayon::join(|| self.tally(&first), || self.tally(&second));
+            let (first_tally, second_tally) = (first_tally.context("first")?, second_tally.context("second")?);
+            info!(
+                "\"{}\" has {} entries, \"{}\" has {}",
+                self.first.display(),
+                first_tally.entries,
+                self.second.display(),
+                second_tally.entries
+            );
+            Ok(match divergence(&first_tally, &second_tally, PathBuf::new()) {
+                Some((path, first, second)) => {
+                    // The root is "." which shouldn't prefix its children
+                    let path = if path.as_os_str().is_empty() { ".".into() } else { path };
+                    self.report(Diff::EntryCounts(first, second), Some(path), Some(libc::S_IFDIR))
+                }
+                None => Comparison::Equal,
+            })
+        })
+    }
+
+    fn tally(&self, entry: &EntryInfo) -> Fallible<Tally> {
+        self.check_interrupted()?;
+        let mut tally = Tally {
+            entries: 1,
+            children: 0,
+            subdirs: HashMap::new(),
+        };
+        if entry.metadata.stat().st_mode & libc::S_IFMT != libc::S_IFDIR {
+            return Ok(tally);
+        }
+        if self.ignore_files {
+            self.load_ignore_files(entry, entry)?;
+        }
+        let names: Vec<PathBuf> = self.retry(&entry.path, || Ok(self.list_dir(entry)?))?;
+        let children = names
+            .par_iter()
+            .map(|name| {
+                let child = self.retry(name, || entry.child_entry(name))?;
+                let is_dir = child.metadata.stat().st_mode & libc::S_IFMT == libc::S_IFDIR;
+                Ok((name, is_dir, self.tally(&child)?))
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        tally.children = children.len() as u64;
+        for (name, is_dir, child) in children {
+            tally.entries += child.entries;
+            if is_dir {
+                tally.subdirs.insert(name.clone(), child);
+            }
+        }
+        Ok(tally)
+    }
+}
+
+/// Where the counts of directories at `path` diverge, with the counts of the directory there
+fn divergence(first: &Tally, second: &Tally, path: PathBuf) -> Option<(PathBuf, u64, u64)> {
+    if first.entries == second.entries {
+        return None;
+    }
+    if first.children == second.children {
+        let mut names: Vec<_> = first
+            .subdirs
+            .iter()
+            .filter_map(|(name, first)| Some((name, first, second.subdirs.get(name)?)))
+            .filter(|(_, first, second)| first.entries != second.entries)
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        if let Some((name, first, second)) = names.first() {
+            return divergence(first, second, path.join(name));
+        }
+    }
+    Some((path, first.entries, second.entries))
+}
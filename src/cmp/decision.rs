@@ -0,0 +1,83 @@
+//! Comparison decisions logged as JSON objects, one per record, for log consumers to parse
+//!
+//! ```text
+//! {"path":"directory/regular_file","decision":"unequal","diff":"Contents"}
+//! ```
+//!
+//! The diff kind is the one `Diff` is serialized with, and both it and the path are left out when unknown.
+
+use super::{Decision, Diff};
+use log::debug;
+use serde::Serialize;
+use std::path::Path;
+
+/// Log target of the debug level records of comparison decisions
+pub const DECISION_LOG_TARGET: &str = "fscmp::decision";
+
+#[derive(Serialize)]
+struct Record<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    decision: Decision,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<u64>,
+}
+
+pub fn log(path: Option<&Path>, decision: Decision, diff: Option<&Diff>) {
+    log_record(Record {
+        path,
+        decision,
+        diff: diff.and_then(Diff::kind_name),
+        chunks: None,
+    });
+}
+
+pub fn log_contents(path: Option<&Path>, chunks: u64) {
+    log_record(Record {
+        path,
+        decision: Decision::ContentsEqual,
+        diff: None,
+        chunks: Some(chunks),
+    });
+}
+
+fn log_record(record: Record) {
+    if log::log_enabled!(target: DECISION_LOG_TARGET, log::Level::Debug) {
+        match serde_json::to_string(&record) {
+            Ok(json) => debug!(target: DECISION_LOG_TARGET, "{}", json),
+            Err(e) => debug!(target: DECISION_LOG_TARGET, "Failed serializing decision: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let record = Record {
+            path: Some(Path::new("directory/regular_file")),
+            decision: Decision::Unequal,
+            diff: Diff::Contents(0, 0, vec![0], vec![1]).kind_name(),
+            chunks: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"path":"directory/regular_file","decision":"unequal","diff":"Contents"}"#
+        );
+
+        let record = Record {
+            path: None,
+            decision: Decision::ContentsEqual,
+            diff: None,
+            chunks: Some(3),
+        };
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"decision":"contents_equal","chunks":3}"#
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Digests of whole trees, for comparing trees out-of-band
+
+use super::{ContentSource, EntryInfo, FSCmp, BUF_SIZE_U64};
+use crate::range_chunks::ChunkableRange;
+use failure::Fallible;
+use rayon::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Hash algorithms for fingerprinting the contents of differing files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Hash of `size` bytes of a source from `offset`
+    pub(super) fn contents_hash(self, source: &ContentSource, offset: u64, size: u64) -> Fallible<Vec<u8>> {
+        Ok(match self {
+            HashAlgorithm::Sha256 => hash_source::<Sha256>(source, offset, size)?.result().to_vec(),
+            HashAlgorithm::Sha512 => hash_source::<Sha512>(source, offset, size)?.result().to_vec(),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                read_source(source, offset, size, |data| {
+                    hasher.update(data);
+                })?;
+                hasher.finalize().as_bytes().to_vec()
+            }
+        })
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Sha512 => write!(f, "sha512"),
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha512" => Ok(HashAlgorithm::Sha512),
+            #[cfg(feature = "blake3")]
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("Unknown hash algorithm \"{}\"", s)),
+        }
+    }
+}
+
+/// Hash `size` bytes of a source from `offset`, read in chunks without sampling
+fn hash_source<D: Digest>(source: &ContentSource, offset: u64, size: u64) -> Fallible<D> {
+    let mut hasher = D::new();
+    read_source(source, offset, size, |data| hasher.input(data))?;
+    Ok(hasher)
+}
+
+/// Pass `size` bytes of a source from `offset` to `consume`, chunk by chunk
+fn read_source(source: &ContentSource, offset: u64, size: u64, mut consume: impl FnMut(&[u8])) -> Fallible<()> {
+    let mut buffer = super::AlignedBuffer([0; super::BUF_SIZE]);
+    for chunk in (0..size).chunks(BUF_SIZE_U64) {
+        let data = &mut buffer.0[..(chunk.end - chunk.start) as usize];
+        source.read_exact_at(data, offset + chunk.start)?;
+        consume(data);
+    }
+    Ok(())
+}
+
+/// SHA-256 of the first `size` bytes of a file
+pub(super) fn contents_sha256(entry: &EntryInfo, size: u64) -> Fallible<[u8; 32]> {
+    if size == 0 {
+        return Ok(Sha256::new().result().into());
+    }
+    Ok(hash_source::<Sha256>(&ContentSource::open(entry)?, 0, size)?
+        .result()
+        .into())
+}
+
+impl FSCmp {
+    /// A digest of the tree at `root` which is the same for any identical tree
+    ///
+    /// The digest is the SHA-256 of the concatenated digests of its entries (the root included), ordered by
+    /// relative path as compared component by component. The digest of an entry is the SHA-256 of:
+    ///
+    /// - The relative path's bytes followed by a null byte, "." being the root
+    /// - The file type (`S_IFMT` bits), as a big-endian u32
+    /// - The permission bits (`0o7777`), UID and GID, each as a big-endian u32, or zeroes for the root (unless
+    ///   compared with `with_compare_root_meta`) or when only contents are compared
+    /// - For regular files, the size as a big-endian u64 followed by the SHA-256 of the contents
+    /// - For symbolic links, the length of the target as a big-endian u64 followed by the target's bytes
+    /// - For block and character devices, the device number as a big-endian u64
+    ///
+    /// Hard link counts aren't part of the digest, since they depend on links from outside of the tree.
+    ///
+    /// Entries ignored by the comparison are left out of the digest, but contents are never sampled.
+    pub fn tree_digest(&self, root: &Path) -> Fallible<[u8; 32]> {
+        self.with_watchdog(|| {
+            let mut digests = self.entry_digests(&EntryInfo::dir(root)?)?;
+            digests.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut hasher = Sha256::new();
+            for (_, digest) in digests {
+                hasher.input(digest);
+            }
+            Ok(hasher.result().into())
+        })
+    }
+
+    /// Digests of an entry and everything under it, by relative path
+    fn entry_digests(&self, entry: &EntryInfo) -> Fallible<Vec<(PathBuf, [u8; 32])>> {
+        self.check_interrupted()?;
+        let stat = entry.metadata.stat();
+        let file_type = stat.st_mode & libc::S_IFMT;
+        let relative_path = entry.relative_path();
+
+        let mut hasher = Sha256::new();
+        hasher.input(relative_path.as_os_str().as_bytes());
+        hasher.input([0]);
+        hasher.input(file_type.to_be_bytes());
+        if self.content_only || (entry.path == Path::new(".") && !self.compare_root_meta) {
+            hasher.input([0; 12]);
+        } else {
+            hasher.input((stat.st_mode & 0o7777).to_be_bytes());
+            hasher.input(stat.st_uid.to_be_bytes());
+            hasher.input(stat.st_gid.to_be_bytes());
+        }
+
+        let mut digests = vec![];
+        match file_type {
+            libc::S_IFREG => {
+                let size = entry.metadata.len();
+                hasher.input(size.to_be_bytes());
+                hasher.input(contents_sha256(entry, size)?);
+            }
+            libc::S_IFLNK => {
+                let target = entry.parent.read_link(&entry.path)?;
+                let target = target.as_os_str().as_bytes();
+                hasher.input((target.len() as u64).to_be_bytes());
+                hasher.input(target);
+            }
+            libc::S_IFBLK | libc::S_IFCHR => {
+                // dev_t isn't u64 on every platform
+                #[allow(clippy::unnecessary_cast)]
+                hasher.input((stat.st_rdev as u64).to_be_bytes());
+            }
+            libc::S_IFDIR => {
+                if self.ignore_files {
+                    self.load_ignore_files(entry, entry)?;
+                }
+                let names: Vec<_> = self.list_dir(entry)?;
+                let children = names
+                    .par_iter()
+                    .map(|name| self.entry_digests(&entry.child_entry(name)?))
+                    .collect::<Fallible<Vec<_>>>()?;
+                digests.extend(children.into_iter().flatten());
+            }
+            _ => (),
+        }
+        digests.push((relative_path, hasher.result().into()));
+        Ok(digests)
+    }
+}
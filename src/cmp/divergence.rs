@@ -0,0 +1,48 @@
+//! Ranking of the top-level entries of the trees by how much they differ, for finding where big differences are
+//!
+//! Every difference is attributed to the entry directly in the roots it's under, directories counting the
+//! differences of everything under them, and entries missing on one side each counting as a difference.
+
+use super::{Comparison, Diff, FSCmp};
+use failure::Fallible;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+impl FSCmp {
+    /// Compare directories, counting the differing entries under each top-level entry of the roots
+    ///
+    /// Along with the first difference found, returns the counts of the top-level entries which differ, the most
+    /// differing first. Differences of the roots themselves are counted for ".".
+    pub fn top_divergence(self) -> Fallible<(Comparison, Vec<(PathBuf, u64)>)> {
+        let mut first_diff = Comparison::Equal;
+        let mut counts: HashMap<PathBuf, u64> = HashMap::new();
+        for comp in self.iter_diffs() {
+            let comp = comp?;
+            if let Comparison::Unequal { diff, path, .. } = &comp {
+                let path = path.clone().unwrap_or_else(|| ".".into());
+                match diff {
+                    Diff::DirContents(first_contents, second_contents) => {
+                        for name in first_contents.symmetric_difference(second_contents) {
+                            *counts.entry(top_level(&path.join(name))).or_default() += 1;
+                        }
+                    }
+                    _ => *counts.entry(top_level(&path)).or_default() += 1,
+                }
+            }
+            if first_diff == Comparison::Equal {
+                first_diff = comp;
+            }
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok((first_diff, counts))
+    }
+}
+
+/// The entry directly in the roots a relative path is under, "." for the roots themselves
+fn top_level(path: &Path) -> PathBuf {
+    path.components()
+        .find(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str().into())
+        .unwrap_or_else(|| ".".into())
+}
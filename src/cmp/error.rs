@@ -0,0 +1,38 @@
+use failure::Fail;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FsCmpError {
+    Timeout,
+    Cancelled,
+    /// An entry whose file type (`S_IFMT` bits of its mode) isn't one this tool knows how to compare
+    UnsupportedType(PathBuf, u32),
+    /// A compared root which is a symbolic link to a missing target
+    BrokenSymlink(PathBuf, PathBuf),
+    /// A regular file which ended before the size it had when its contents started being read
+    ChangedDuringComparison(PathBuf),
+}
+
+impl Fail for FsCmpError {}
+
+impl fmt::Display for FsCmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsCmpError::Timeout => write!(f, "Comparison timed out"),
+            FsCmpError::Cancelled => write!(f, "Comparison cancelled"),
+            FsCmpError::UnsupportedType(path, file_type) => {
+                write!(f, "\"{}\": Unsupported file type 0o{:o}", path.display(), file_type)
+            }
+            FsCmpError::BrokenSymlink(path, target) => write!(
+                f,
+                "\"{}\": Broken symbolic link to missing \"{}\"",
+                path.display(),
+                target.display()
+            ),
+            FsCmpError::ChangedDuringComparison(path) => {
+                write!(f, "\"{}\": File changed during comparison", path.display())
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+use nix::{ioctl_read, ioctl_readwrite};
+use std::cmp::{max, min};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+// Declared with a long argument, but the kernel only ever reads or writes an int
+ioctl_read!(fs_ioc_getflags, b'f', 1, libc::c_long);
+// BLKGETSIZE64, declared with a size_t argument, but the kernel always writes a u64
+ioctl_read!(blkgetsize64, 0x12, 114, libc::size_t);
+
+const FS_COMPR_FL: u32 = 0x0000_0004;
+
+/// Inode flags which can be set with `chattr`, as opposed to flags describing the filesystem's internal layout
+pub const INODE_FLAGS: &[(u32, &str)] = &[
+    (0x0000_0001, "secure-deletion"),
+    (0x0000_0002, "undeletable"),
+    (0x0000_0004, "compressed"),
+    (0x0000_0008, "synchronous"),
+    (0x0000_0010, "immutable"),
+    (0x0000_0020, "append-only"),
+    (0x0000_0040, "no-dump"),
+    (0x0000_0080, "no-atime"),
+    (0x0000_0400, "no-compression"),
+    (0x0000_4000, "data-journaling"),
+    (0x0000_8000, "no-tail-merging"),
+    (0x0001_0000, "synchronous-directory"),
+    (0x0002_0000, "top-directory"),
+    (0x0080_0000, "no-copy-on-write"),
+    (0x0200_0000, "dax"),
+    (0x2000_0000, "project-inheritance"),
+];
+
+/// The `chattr` settable flags of an open file, `None` if the filesystem doesn't support inode flags
+pub fn inode_flags(file: &File) -> nix::Result<Option<u32>> {
+    let mut flags: libc::c_long = 0;
+    match unsafe { fs_ioc_getflags(file.as_raw_fd(), &mut flags) } {
+        Ok(_) => {
+            let mask = INODE_FLAGS.iter().fold(0, |mask, (flag, _)| mask | flag);
+            Ok(Some(flags as u32 & mask))
+        }
+        Err(nix::Error::Sys(Errno::ENOTTY)) | Err(nix::Error::Sys(Errno::EOPNOTSUPP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether an open file is marked for transparent compression, `None` if the filesystem doesn't support inode flags
+///
+/// Btrfs sets the flag for files compressed by a `compression` property or mount option as well as by `chattr +c`.
+pub fn compressed(file: &File) -> nix::Result<Option<bool>> {
+    Ok(inode_flags(file)?.map(|flags| flags & FS_COMPR_FL != 0))
+}
+
+/// The size of an open block device in bytes
+pub fn block_device_size(file: &File) -> nix::Result<u64> {
+    let mut size: u64 = 0;
+    unsafe { blkgetsize64(file.as_raw_fd(), &mut size as *mut u64 as *mut libc::size_t)? };
+    Ok(size)
+}
+
+/// The regions of an open file holding data, as start and end offsets, found by seeking past its holes
+///
+/// Filesystems not tracking holes report the whole file as a single data region.
+pub fn data_regions(file: &File, size: u64) -> nix::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = match lseek(fd, offset as libc::off_t, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // No data past the offset
+            Err(nix::Error::Sys(Errno::ENXIO)) => break,
+            Err(e) => return Err(e),
+        };
+        let end = min(lseek(fd, start as libc::off_t, Whence::SeekHole)? as u64, size);
+        regions.push((start, end));
+        offset = end;
+    }
+    Ok(regions)
+}
+
+const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+// The physical location of these extents is meaningless for comparing sharing
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x0000_0004;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x0000_0200;
+const FIEMAP_EXTENTS_PER_CALL: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+/// `struct fiemap` followed by room for its flexible array of extents
+#[repr(C)]
+struct FiemapBuffer {
+    fiemap: Fiemap,
+    extents: [FiemapExtent; FIEMAP_EXTENTS_PER_CALL],
+}
+
+ioctl_readwrite!(fs_ioc_fiemap, b'f', 11, Fiemap);
+
+/// A mapped range of a file, in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+}
+
+/// The extents of an open file ordered by logical offset, `None` if the filesystem doesn't support FIEMAP
+pub fn extents(file: &File) -> nix::Result<Option<Vec<Extent>>> {
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut buffer = FiemapBuffer {
+            fiemap: Fiemap {
+                fm_start: start,
+                fm_length: u64::MAX - start,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_extent_count: FIEMAP_EXTENTS_PER_CALL as u32,
+                ..Default::default()
+            },
+            extents: [Default::default(); FIEMAP_EXTENTS_PER_CALL],
+        };
+        match unsafe { fs_ioc_fiemap(file.as_raw_fd(), &mut buffer as *mut FiemapBuffer as *mut Fiemap) } {
+            Ok(_) => (),
+            Err(nix::Error::Sys(Errno::ENOTTY)) | Err(nix::Error::Sys(Errno::EOPNOTSUPP)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mapped = &buffer.extents[..buffer.fiemap.fm_mapped_extents as usize];
+        let last = match mapped.last() {
+            None => return Ok(Some(extents)),
+            Some(last) => *last,
+        };
+        extents.extend(
+            mapped
+                .iter()
+                .filter(|e| {
+                    e.fe_flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_DATA_INLINE) == 0
+                })
+                .map(|e| Extent {
+                    logical: e.fe_logical,
+                    physical: e.fe_physical,
+                    length: e.fe_length,
+                }),
+        );
+        if last.fe_flags & FIEMAP_EXTENT_LAST != 0 {
+            return Ok(Some(extents));
+        }
+        start = last.fe_logical + last.fe_length;
+    }
+}
+
+/// Number of bytes mapped to the same physical location at the same logical offset in both files
+pub fn shared_bytes(first: &[Extent], second: &[Extent]) -> u64 {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < first.len() && j < second.len() {
+        let (a, b) = (&first[i], &second[j]);
+        let start = max(a.logical, b.logical);
+        let end = min(a.logical + a.length, b.logical + b.length);
+        if start < end && a.physical.wrapping_sub(a.logical) == b.physical.wrapping_sub(b.logical) {
+            shared += end - start;
+        }
+        if a.logical + a.length <= b.logical + b.length {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    shared
+}
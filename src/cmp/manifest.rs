@@ -0,0 +1,272 @@
+//! Manifests of a tree's entries and the hashes of its files, for verifying a tree while reading it only once
+//!
+//! A manifest is a JSON object recording the sampling its hashes were read with, and the entries (the root "."
+//! included) by relative path:
+//!
+//! ```text
+//! {"version":1,"sampling":{"full_compare_limit":null,"sample_percent":null,"sample_chunks":null},
+//!  "entries":{".":{"type":16384},"regular_file":{"type":32768,"size":1024,"sha256":"<hex>"}}}
+//! ```
+//!
+//! Types are the `S_IFMT` bits of the modes. The hash of a file is the SHA-256 of the chunks a comparison with the
+//! recorded sampling reads, so a tree is verified sampling its files the way its manifest was written.
+
+use super::comparison::hex;
+use super::HashAlgorithm;
+use super::{
+    first_unequal, plan_chunks, with_chunk_buffers, Comparison, ContentSource, Decision, Diff, EntryInfo, FSCmp,
+};
+use failure::{bail, Fallible, ResultExt};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Sampling {
+    full_compare_limit: Option<u64>,
+    sample_percent: Option<f64>,
+    sample_chunks: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ManifestEntry {
+    #[serde(rename = "type")]
+    file_type: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    sampling: Sampling,
+    entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl FSCmp {
+    /// Write a manifest of the first tree to `path`, hashing its files with the configured sampling
+    pub fn write_manifest(&self, path: &Path) -> Fallible<()> {
+        let sampling = self.sampling();
+        let entries = self.with_watchdog(|| self.manifest_entries(&EntryInfo::dir(&self.first)?, sampling))?;
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            sampling,
+            entries: entries.into_iter().collect(),
+        };
+        let file = File::create(path).with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &manifest)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Compare the first tree against the manifest given as the second argument, reading the tree only once
+    ///
+    /// Files are hashed with the sampling the manifest records rather than the configured one. Differences are
+    /// found in the order of their paths, all of them being reported with `with_report_all`.
+    pub fn verify_manifest(&self) -> Fallible<Comparison> {
+        let file = File::open(&self.second).with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+        let manifest: Manifest = serde_json::from_reader(BufReader::new(file))
+            .with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!(
+                "\"{}\" is a version {} manifest, only version {} is supported",
+                self.second.display(),
+                manifest.version,
+                MANIFEST_VERSION
+            );
+        }
+        let live: BTreeMap<_, _> = self
+            .with_watchdog(|| self.manifest_entries(&EntryInfo::dir(&self.first)?, manifest.sampling))?
+            .into_iter()
+            .collect();
+
+        // Entries in both, by path, with their differences if any
+        let mut decisions: BTreeMap<_, _> = live
+            .iter()
+            .filter_map(|(path, entry)| Some((path.clone(), entry_diff(entry, manifest.entries.get(path)?))))
+            .collect();
+        for (path, diff) in dir_contents_diffs(&live, &manifest.entries) {
+            decisions.insert(path, Some(diff));
+        }
+
+        let mut result = Comparison::Equal;
+        for (path, diff) in decisions {
+            let file_type = live.get(&path).map(|entry| entry.file_type);
+            let comp = match diff {
+                Some(diff) => self.report(diff, Some(path), file_type),
+                None => {
+                    self.decided(Some(&path), file_type, Decision::Equal, None);
+                    Comparison::Equal
+                }
+            };
+            if comp != Comparison::Equal && !self.report_all {
+                return Ok(comp);
+            }
+            result = first_unequal(result, comp);
+        }
+        Ok(result)
+    }
+
+    fn sampling(&self) -> Sampling {
+        Sampling {
+            full_compare_limit: self.full_compare_limit,
+            sample_percent: self.sample_percent,
+            sample_chunks: self.sample_chunks,
+        }
+    }
+
+    /// Manifest entries of an entry and everything under it, by relative path
+    fn manifest_entries(&self, entry: &EntryInfo, sampling: Sampling) -> Fallible<Vec<(PathBuf, ManifestEntry)>> {
+        self.check_interrupted()?;
+        let file_type = entry.metadata.stat().st_mode & libc::S_IFMT;
+        let mut manifest_entry = ManifestEntry {
+            file_type,
+            size: None,
+            sha256: None,
+            target: None,
+        };
+
+        let mut entries = vec![];
+        match file_type {
+            libc::S_IFREG => {
+                let size = entry.metadata.len();
+                manifest_entry.size = Some(size);
+                manifest_entry.sha256 = Some(hex(&self.sampled_sha256(entry, size, sampling)?));
+            }
+            libc::S_IFLNK => {
+                manifest_entry.target = Some(entry.parent.read_link(&entry.path)?);
+            }
+            libc::S_IFDIR => {
+                if self.ignore_files {
+                    self.load_ignore_files(entry, entry)?;
+                }
+                let names: Vec<_> = self.list_dir(entry)?;
+                let children = names
+                    .par_iter()
+                    .map(|name| {
+                        let child = self.retry(&entry.path.join(name), || entry.child_entry(name))?;
+                        self.manifest_entries(&child, sampling)
+                    })
+                    .collect::<Fallible<Vec<_>>>()?;
+                entries.extend(children.into_iter().flatten());
+            }
+            _ => (),
+        }
+        entries.push((entry.relative_path(), manifest_entry));
+        Ok(entries)
+    }
+
+    /// SHA-256 of the chunks of a file's first `size` bytes a comparison with `sampling` reads
+    fn sampled_sha256(&self, entry: &EntryInfo, size: u64, sampling: Sampling) -> Fallible<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        if size > 0 {
+            let source = ContentSource::open(entry)?;
+            let (chunks, chunk_count) = plan_chunks(
+                size,
+                sampling.full_compare_limit,
+                sampling.sample_percent,
+                sampling.sample_chunks,
+            );
+            for chunk in (0..chunk_count).filter_map(|i| chunks.get(i)) {
+                self.check_interrupted()?;
+                with_chunk_buffers(|data, _| -> Fallible<()> {
+                    let data = &mut data[..(chunk.end - chunk.start) as usize];
+                    self.retry(&entry.path, || source.read_exact_at(data, chunk.start))?;
+                    self.bytes_read.fetch_add(chunk.end - chunk.start, Ordering::Relaxed);
+                    hasher.input(&data[..]);
+                    Ok(())
+                })?;
+            }
+        }
+        Ok(hasher.result().to_vec())
+    }
+}
+
+/// Differences in the children of directories present in both the live tree and the manifest, by directory
+fn dir_contents_diffs(
+    live: &BTreeMap<PathBuf, ManifestEntry>,
+    stored: &BTreeMap<PathBuf, ManifestEntry>,
+) -> Vec<(PathBuf, Diff)> {
+    let children = |entries: &BTreeMap<PathBuf, ManifestEntry>| {
+        let mut children: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+        for path in entries.keys().filter(|path| *path != Path::new(".")) {
+            let parent = match path.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => ".".into(),
+            };
+            if let Some(name) = path.file_name() {
+                children.entry(parent).or_default().insert(name.into());
+            }
+        }
+        children
+    };
+    let (mut live_children, mut stored_children) = (children(live), children(stored));
+    live.iter()
+        .filter(|(path, entry)| {
+            entry.file_type == libc::S_IFDIR
+                && stored
+                    .get(*path)
+                    .is_some_and(|stored| stored.file_type == libc::S_IFDIR)
+        })
+        .filter_map(|(path, _)| {
+            let live_names = live_children.remove(path).unwrap_or_default();
+            let stored_names = stored_children.remove(path).unwrap_or_default();
+            if live_names == stored_names {
+                None
+            } else {
+                Some((path.clone(), Diff::DirContents(live_names, stored_names)))
+            }
+        })
+        .collect()
+}
+
+/// The difference between an entry of the live tree and the one recorded for its path
+fn entry_diff(live: &ManifestEntry, stored: &ManifestEntry) -> Option<Diff> {
+    if live.file_type != stored.file_type {
+        return Some(Diff::Types(live.file_type, stored.file_type));
+    }
+    if live.size != stored.size {
+        return Some(Diff::Sizes(
+            live.size.unwrap_or(0) as i64,
+            stored.size.unwrap_or(0) as i64,
+        ));
+    }
+    if live.sha256 != stored.sha256 {
+        let unhex = |hash: &Option<String>| hash.as_deref().map(unhex).unwrap_or_default();
+        return Some(Diff::ContentHash(
+            HashAlgorithm::Sha256,
+            unhex(&live.sha256),
+            unhex(&stored.sha256),
+        ));
+    }
+    if live.target != stored.target {
+        return Some(Diff::LinkTarget(
+            live.target.clone().unwrap_or_default(),
+            stored.target.clone().unwrap_or_default(),
+        ));
+    }
+    None
+}
+
+/// Bytes of a hex string, as far as it holds valid pairs of hex digits
+pub(super) fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len() / 2)
+        .map_while(|i| {
+            s.get(2 * i..2 * i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
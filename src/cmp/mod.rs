@@ -1,46 +1,169 @@
+#[cfg(unix)]
+mod audit;
+mod binary;
+#[cfg(unix)]
+mod case_fold;
+#[cfg(unix)]
+mod checksums;
 mod comparison;
+#[cfg(unix)]
+mod counts;
+#[cfg(feature = "structured-log")]
+mod decision;
+#[cfg(unix)]
+mod digest;
+#[cfg(unix)]
+mod divergence;
+mod error;
+#[cfg(target_os = "linux")]
+mod ioctl;
+#[cfg(unix)]
+mod manifest;
+#[cfg(unix)]
+mod owners;
+mod resume;
+#[cfg(unix)]
+mod retry;
+#[cfg(all(target_os = "linux", feature = "tar"))]
+mod tar;
+#[cfg(target_os = "linux")]
+mod xattr;
 
-pub use self::comparison::{Comparison, Diff};
-use failure::{Fallible, ResultExt};
 #[cfg(unix)]
-use libc;
+use self::audit::AuditLog;
+pub use self::binary::{decode_binary, BINARY_FORMAT_VERSION};
+pub use self::comparison::{
+    hex, Comparison, ComparisonDisplay, ComparisonStats, Decision, Diff, DiffSummary, DisplayOptions,
+    EntryDecision, OnelineDisplay, Progress,
+};
+#[cfg(feature = "structured-log")]
+pub use self::decision::DECISION_LOG_TARGET;
+#[cfg(unix)]
+pub use self::digest::HashAlgorithm;
+pub use self::error::FsCmpError;
+#[cfg(unix)]
+use self::owners::OwnerNames;
+use self::resume::ResumeLog;
+use crate::range_chunks::{ChunkableRange, RangeChunks};
+use failure::{bail, Fallible, ResultExt};
+#[cfg(unix)]
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+#[cfg(unix)]
+use ignore::overrides::{Override, OverrideBuilder};
+#[cfg(unix)]
+use ignore::Match;
 use log::debug;
 #[cfg(unix)]
+use log::info;
+use log::warn;
+#[cfg(unix)]
 use nix::fcntl;
 #[cfg(unix)]
+use nix::poll::{poll, PollFd, PollFlags};
+#[cfg(unix)]
 use nix::sys::stat::Mode;
 #[cfg(unix)]
+use nix::unistd;
+#[cfg(unix)]
 use openat::{self, Dir};
 use rayon::prelude::*;
+use std::alloc::{self, Layout};
+use std::cell::RefCell;
 use std::cmp::{max, min};
 #[cfg(unix)]
 use std::collections::hash_map;
+#[cfg(target_os = "linux")]
+use std::collections::BTreeMap;
 #[cfg(unix)]
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
 use std::fs::File;
 #[cfg(windows)]
 use std::fs::OpenOptions;
-#[cfg(unix)]
 use std::io;
 #[cfg(unix)]
-use std::os::unix::fs::FileExt;
+use std::io::{BufRead, BufReader, Read};
+#[cfg(unix)]
+use std::iter::FromIterator;
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, MetadataExt};
 #[cfg(unix)]
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::fs::FileExt;
 #[cfg(windows)]
 use std::os::windows::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::path::Component;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::Mutex;
+use std::thread;
 #[cfg(unix)]
-use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
 const BLOCK_SIZE: usize = 512;
+/// Name of the files holding gitignore-style rules for the directory they're in
+#[cfg(unix)]
+const IGNORE_FILE_NAME: &str = ".fscmpignore";
+/// Log target of the info level records listing every entry compared equal
+pub const EQUAL_LOG_TARGET: &str = "fscmp::equal";
+/// Log target of the warning level records meant for the user, e.g. about roots which are the same directory
+#[cfg(unix)]
+pub const WARNING_LOG_TARGET: &str = "fscmp::warning";
 const BUF_SIZE: usize = 256 * 1024;
+/// How long reading a FIFO waits for data by default, see `FSCmp::with_fifo_timeout`
+#[cfg(unix)]
+pub const DEFAULT_FIFO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of differences `FSCmp::iter_diffs` queues before waiting for them to be consumed
+pub const DIFF_QUEUE_SIZE: usize = 64;
 const BUF_SIZE_U64: u64 = BUF_SIZE as u64;
 
 #[repr(align(512))]
 struct AlignedBuffer([u8; BUF_SIZE]);
 
+thread_local! {
+    /// Buffers for the chunks compared on each thread, reused across chunks rather than zeroed for each one
+    static CHUNK_BUFFERS: RefCell<Option<Box<[AlignedBuffer; 2]>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with this thread's chunk buffers, holding whatever was read into them last, or with new ones if
+/// they're already in use
+fn with_chunk_buffers<T>(f: impl FnOnce(&mut [u8; BUF_SIZE], &mut [u8; BUF_SIZE]) -> T) -> T {
+    // Allocated zeroed in place, as buffers this large could overflow the stack on their way to the heap
+    let new_buffers = || {
+        let layout = Layout::new::<[AlignedBuffer; 2]>();
+        // Zeroes are valid buffers, and the allocation has the layout a box of them needs
+        unsafe {
+            let buffers = alloc::alloc_zeroed(layout) as *mut [AlignedBuffer; 2];
+            if buffers.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            Box::from_raw(buffers)
+        }
+    };
+    CHUNK_BUFFERS.with(|buffers| match buffers.try_borrow_mut() {
+        Ok(mut buffers) => {
+            let [buffer1, buffer2] = &mut **buffers.get_or_insert_with(new_buffers);
+            f(&mut buffer1.0, &mut buffer2.0)
+        }
+        Err(_) => {
+            let [buffer1, buffer2] = &mut *new_buffers();
+            f(&mut buffer1.0, &mut buffer2.0)
+        }
+    })
+}
+
+#[cfg(unix)]
+#[repr(align(512))]
+struct BlockBuffer([u8; BLOCK_SIZE]);
+
 trait SliceRange {
     fn subslice(&self, start: usize, size: usize) -> &Self;
 }
@@ -62,6 +185,12 @@ struct EntryInfo {
     metadata: openat::Metadata,
 }
 
+type DiffHandler = Box<dyn Fn(&Comparison) + Send + Sync>;
+type ProgressHandler = Box<dyn Fn(&Progress) + Send + Sync>;
+type EntryHandler = Box<dyn Fn(&Path, &EntryDecision) + Send + Sync>;
+#[cfg(unix)]
+type UnknownTypeHandler = Box<dyn Fn(&Path, &Path) -> Fallible<Comparison> + Send + Sync>;
+
 #[derive(Default)]
 pub struct FSCmp {
     first: PathBuf,
@@ -71,14 +200,135 @@ pub struct FSCmp {
     #[cfg(unix)]
     ignored_dirs: HashSet<PathBuf>,
     #[cfg(unix)]
-    inode_maps: Mutex<[HashMap<libc::ino_t, PathBuf>; 2]>,
+    ignored_paths: HashSet<PathBuf>,
+    #[cfg(unix)]
+    allowed_diffs: HashSet<PathBuf>,
+    #[cfg(unix)]
+    ignore_files: bool,
+    #[cfg(unix)]
+    include_globs: Option<Override>,
+    #[cfg(unix)]
+    subpath: Option<PathBuf>,
+    #[cfg(unix)]
+    reference: Option<PathBuf>,
+    #[cfg(unix)]
+    audit_log: Option<AuditLog>,
+    #[cfg(unix)]
+    ignore_case: bool,
+    #[cfg(unix)]
+    turkic_case: bool,
+    #[cfg(unix)]
+    owner_names: Option<OwnerNames>,
+    #[cfg(unix)]
+    content_only: bool,
+    #[cfg(unix)]
+    compare_root_meta: bool,
+    #[cfg(unix)]
+    compare_mtimes: bool,
+    #[cfg(unix)]
+    timestamps_files_only: bool,
+    #[cfg(unix)]
+    compare_blksize: bool,
+    #[cfg(unix)]
+    inode_tracking: bool,
+    #[cfg(unix)]
+    ignore_nlink: bool,
+    #[cfg(unix)]
+    compare_dir_order: bool,
+    #[cfg(unix)]
+    strict_stable: bool,
+    #[cfg(unix)]
+    ignore_changed: bool,
+    #[cfg(unix)]
+    started: Mutex<Option<SystemTime>>,
+    #[cfg(unix)]
+    read_fifos: Option<u64>,
+    #[cfg(unix)]
+    fifo_timeout: Duration,
+    #[cfg(unix)]
+    hash_on_mismatch: Option<HashAlgorithm>,
+    #[cfg(unix)]
+    retries: u32,
+    #[cfg(target_os = "linux")]
+    warn_acls: bool,
+    #[cfg(target_os = "linux")]
+    acls_warned: AtomicBool,
+    #[cfg(unix)]
+    sample_chunks: Option<u64>,
+    #[cfg(unix)]
+    sample_percent: Option<f64>,
+    #[cfg(unix)]
+    min_size: Option<u64>,
+    #[cfg(unix)]
+    max_size: Option<u64>,
+    #[cfg(unix)]
+    strict_special: bool,
+    #[cfg(target_os = "linux")]
+    compare_flags: bool,
+    #[cfg(target_os = "linux")]
+    compare_compression: bool,
+    #[cfg(target_os = "linux")]
+    compare_xattrs: bool,
+    #[cfg(target_os = "linux")]
+    ignored_xattrs: Option<Override>,
+    #[cfg(target_os = "linux")]
+    compare_extents: bool,
+    #[cfg(target_os = "linux")]
+    compare_sparse_map: bool,
+    #[cfg(target_os = "linux")]
+    overlay_aware: bool,
+    #[cfg(unix)]
+    missing_ok: bool,
+    #[cfg(unix)]
+    deterministic: bool,
+    #[cfg(unix)]
+    allow_same: bool,
+    #[cfg(unix)]
+    warn_mounts: bool,
+    #[cfg(unix)]
+    report_all: bool,
+    #[cfg(unix)]
+    log_equal: bool,
+    #[cfg(unix)]
+    log_coverage: bool,
+    #[cfg(unix)]
+    ignore_trailing_zeros: bool,
+    #[cfg(unix)]
+    trailing_zeros_equal: bool,
+    /// Shown instead of the paths in the differences reported
+    labels: (Option<PathBuf>, Option<PathBuf>),
+    diff_handler: Option<DiffHandler>,
+    entry_handler: Option<EntryHandler>,
+    progress_handler: Option<(Duration, ProgressHandler)>,
+    #[cfg(unix)]
+    unknown_type_handler: Option<UnknownTypeHandler>,
+    first_diff_only: bool,
+    bench_read_only: bool,
+    summary_only: bool,
+    context_blocks: usize,
+    offsets: (u64, u64),
+    resume_file: Option<PathBuf>,
+    verify_openable: bool,
+    clamp_size: bool,
+    timeout: Option<Duration>,
+    timed_out: AtomicBool,
+    bytes_read: AtomicU64,
+    entries_compared: AtomicU64,
+    cancel: Option<Arc<AtomicBool>>,
+    #[cfg(unix)]
+    inode_maps: Mutex<[HashMap<(libc::dev_t, libc::ino_t), PathBuf>; 2]>,
+    /// Rules of the ignore files found so far, by the relative path of their directory
+    #[cfg(unix)]
+    ignore_rules: Mutex<HashMap<PathBuf, Arc<Gitignore>>>,
 }
 
 impl EntryInfo {
     #[cfg(unix)]
     fn dir(path: &Path) -> Fallible<EntryInfo> {
-        assert!(path.is_dir());
-        let path = path.canonicalize()?;
+        let path = canonicalize_root(path)?;
+        if !path.is_dir() {
+            bail!("\"{}\" isn't a directory", path.display());
+        }
         let dir = Dir::open(&path)?;
         let path = ".".to_string().into();
         let metadata = dir.metadata(&path)?;
@@ -90,11 +340,27 @@ impl EntryInfo {
         })
     }
 
+    /// The root of a comparison from a directory file descriptor, which is duplicated rather than taken over
+    #[cfg(unix)]
+    fn dir_at(fd: RawFd) -> Fallible<EntryInfo> {
+        let dir = unsafe { Dir::from_raw_fd(unistd::dup(fd)?) };
+        let path = ".".to_string().into();
+        let metadata = dir.metadata(&path)?;
+        Ok(EntryInfo {
+            parent: Arc::new(dir),
+            parent_path: Default::default(),
+            path,
+            metadata,
+        })
+    }
+
     fn file(path: &Path) -> Fallible<EntryInfo> {
-        assert!(!path.is_dir());
+        if path.is_dir() {
+            bail!("\"{}\" is a directory", path.display());
+        }
 
         #[cfg(unix)]
-        let path = path.canonicalize()?;
+        let path = canonicalize_root(path)?;
         #[cfg(windows)]
         let path = match path.canonicalize() {
             Ok(path) => path,
@@ -102,24 +368,109 @@ impl EntryInfo {
             _ => path.to_path_buf(),
         };
         #[cfg(unix)]
-        let (dir, path) = (
+        let (dir, name): (Dir, PathBuf) = (
             Dir::open(path.parent().unwrap())?,
             // In UNIX only the filename is needed, as openat() is used, unlike Windows - where we open() the file using its full path
             path.file_name().unwrap().to_os_string().into(),
         );
         #[cfg(unix)]
-        let metadata = dir.metadata(&path)?;
+        let metadata = dir.metadata(&name)?;
+        #[cfg(unix)]
+        {
+            // Block devices report a size of 0, so contents are always read up to the size requested by the caller
+            let file_type = metadata.stat().st_mode & libc::S_IFMT;
+            if file_type != libc::S_IFREG && file_type != libc::S_IFBLK {
+                bail!("\"{}\" is neither a regular file nor a block device", path.display());
+            }
+        }
         Ok(EntryInfo {
             #[cfg(unix)]
             parent: Arc::new(dir),
             #[cfg(unix)]
             parent_path: Default::default(),
+            #[cfg(unix)]
+            path: name,
+            #[cfg(windows)]
             path,
             #[cfg(unix)]
             metadata,
         })
     }
 
+    /// The size of a regular file or block device, `None` if it can't be told
+    #[cfg(unix)]
+    fn file_size(&self) -> Fallible<Option<u64>> {
+        match self.metadata.stat().st_mode & libc::S_IFMT {
+            libc::S_IFREG => Ok(Some(self.metadata.len())),
+            #[cfg(target_os = "linux")]
+            libc::S_IFBLK => {
+                let file = open_file(self).with_context(|e| format!("\"{}\": {}", self.path.display(), e))?;
+                let size = ioctl::block_device_size(&file)
+                    .with_context(|e| format!("\"{}\": {}", self.path.display(), e))?;
+                Ok(Some(size))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The size of a regular file, `None` for devices, which report no size
+    #[cfg(windows)]
+    fn file_size(&self) -> Fallible<Option<u64>> {
+        Ok(fs::metadata(&self.path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len()))
+    }
+
+    #[cfg(unix)]
+    fn is_block_device(&self) -> bool {
+        self.metadata.stat().st_mode & libc::S_IFMT == libc::S_IFBLK
+    }
+
+    /// Whether this is a device rather than a regular file, as Windows device access paths report no metadata
+    #[cfg(windows)]
+    fn is_block_device(&self) -> bool {
+        !fs::metadata(&self.path).is_ok_and(|metadata| metadata.is_file())
+    }
+
+    /// Path relative to the root of the comparison, "." being the root itself
+    #[cfg(unix)]
+    fn relative_path(&self) -> PathBuf {
+        let path: PathBuf = self
+            .parent_path
+            .join(&self.path)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect();
+        if path.as_os_str().is_empty() {
+            ".".into()
+        } else {
+            path
+        }
+    }
+
+    /// OverlayFS marks deleted entries with a character device numbered 0/0
+    #[cfg(target_os = "linux")]
+    fn is_whiteout(&self) -> bool {
+        let stat = self.metadata.stat();
+        stat.st_mode & libc::S_IFMT == libc::S_IFCHR && stat.st_rdev == 0
+    }
+
+    /// Open a regular file or directory for inspecting its metadata, without following symlinks or blocking
+    #[cfg(target_os = "linux")]
+    fn open_nofollow(&self) -> nix::Result<File> {
+        let flags =
+            fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_NONBLOCK | fcntl::OFlag::O_NOFOLLOW | fcntl::OFlag::O_CLOEXEC;
+        unsafe {
+            Ok(File::from_raw_fd(fcntl::openat(
+                self.parent.as_raw_fd(),
+                &self.path,
+                flags,
+                Mode::empty(),
+            )?))
+        }
+    }
+
     #[cfg(unix)]
     fn child_entry(&self, name: &Path) -> Fallible<EntryInfo> {
         let path = if self.path.starts_with(".") {
@@ -128,7 +479,8 @@ impl EntryInfo {
             self.path.join(name)
         };
 
-        Ok(if path.as_os_str().len() > libc::PATH_MAX as usize {
+        // PATH_MAX includes the terminating null byte
+        Ok(if path.as_os_str().len() >= libc::PATH_MAX as usize {
             let path_parent = path.parent().unwrap();
             let parent_path = self.parent_path.join(path_parent);
             let dir = self.parent.sub_dir(path_parent)?;
@@ -164,9 +516,36 @@ macro_rules! compare_metadata_field {
             ));
         }
     };
+    // With --report-all the difference is kept in `$comp` and the comparison continues
+    ($self:ident, $comp:ident, $first:ident, $second:ident, $field:ident, $err_type:path) => {
+        if $first.metadata.stat().$field != $second.metadata.stat().$field {
+            let unequal = $self.unequal(
+                $err_type($first.metadata.stat().$field, $second.metadata.stat().$field),
+                &$first,
+                &$second,
+            );
+            if !$self.report_all {
+                return Ok(unequal);
+            }
+            $comp = first_unequal($comp, unequal);
+        }
+    };
+}
+
+/// Merges the result of an additional check into `$comp`, returning early unless all differences are reported
+#[cfg(unix)]
+macro_rules! merge_comparison {
+    ($self:ident, $comp:ident, $check:expr) => {
+        let check = $check;
+        if check != Comparison::Equal && !$self.report_all {
+            return Ok(check);
+        }
+        $comp = first_unequal($comp, check);
+    };
 }
 
 impl FSCmp {
+    /// The roots are reported as given, up to trailing and repeated separators, so that e.g. "dir/" reports as "dir"
     pub fn new(
         first: PathBuf,
         second: PathBuf,
@@ -174,427 +553,5376 @@ impl FSCmp {
         #[cfg(unix)] ignored_dirs: HashSet<PathBuf>,
     ) -> Self {
         Self {
-            first,
-            second,
+            first: first.components().collect(),
+            second: second.components().collect(),
             #[cfg(unix)]
             full_compare_limit,
             #[cfg(unix)]
             ignored_dirs,
             #[cfg(unix)]
+            ignored_paths: HashSet::new(),
+            #[cfg(unix)]
+            allowed_diffs: HashSet::new(),
+            #[cfg(unix)]
+            ignore_files: false,
+            #[cfg(unix)]
+            include_globs: None,
+            #[cfg(unix)]
+            subpath: None,
+            #[cfg(unix)]
+            reference: None,
+            #[cfg(unix)]
+            audit_log: None,
+            #[cfg(unix)]
+            ignore_case: false,
+            #[cfg(unix)]
+            turkic_case: false,
+            #[cfg(unix)]
+            owner_names: None,
+            #[cfg(unix)]
+            content_only: false,
+            #[cfg(unix)]
+            compare_root_meta: false,
+            #[cfg(unix)]
+            compare_mtimes: false,
+            #[cfg(unix)]
+            timestamps_files_only: false,
+            #[cfg(unix)]
+            compare_blksize: false,
+            #[cfg(unix)]
+            inode_tracking: true,
+            #[cfg(unix)]
+            ignore_nlink: false,
+            #[cfg(unix)]
+            compare_dir_order: false,
+            #[cfg(unix)]
+            strict_stable: false,
+            #[cfg(unix)]
+            ignore_changed: false,
+            #[cfg(unix)]
+            started: Mutex::new(None),
+            #[cfg(unix)]
+            read_fifos: None,
+            #[cfg(unix)]
+            fifo_timeout: DEFAULT_FIFO_TIMEOUT,
+            #[cfg(unix)]
+            hash_on_mismatch: None,
+            #[cfg(unix)]
+            retries: 0,
+            #[cfg(target_os = "linux")]
+            warn_acls: false,
+            #[cfg(target_os = "linux")]
+            acls_warned: AtomicBool::new(false),
+            #[cfg(unix)]
+            sample_chunks: None,
+            #[cfg(unix)]
+            sample_percent: None,
+            #[cfg(unix)]
+            min_size: None,
+            #[cfg(unix)]
+            max_size: None,
+            #[cfg(unix)]
+            strict_special: false,
+            #[cfg(target_os = "linux")]
+            compare_flags: false,
+            #[cfg(target_os = "linux")]
+            compare_compression: false,
+            #[cfg(target_os = "linux")]
+            compare_xattrs: false,
+            #[cfg(target_os = "linux")]
+            ignored_xattrs: None,
+            #[cfg(target_os = "linux")]
+            compare_extents: false,
+            #[cfg(target_os = "linux")]
+            compare_sparse_map: false,
+            #[cfg(target_os = "linux")]
+            overlay_aware: false,
+            #[cfg(unix)]
+            missing_ok: false,
+            #[cfg(unix)]
+            deterministic: false,
+            #[cfg(unix)]
+            allow_same: false,
+            #[cfg(unix)]
+            warn_mounts: false,
+            #[cfg(unix)]
+            report_all: false,
+            #[cfg(unix)]
+            log_equal: false,
+            #[cfg(unix)]
+            log_coverage: false,
+            #[cfg(unix)]
+            ignore_trailing_zeros: false,
+            #[cfg(unix)]
+            trailing_zeros_equal: false,
+            labels: (None, None),
+            diff_handler: None,
+            entry_handler: None,
+            progress_handler: None,
+            #[cfg(unix)]
+            unknown_type_handler: None,
+            first_diff_only: false,
+            bench_read_only: false,
+            summary_only: false,
+            context_blocks: 0,
+            offsets: (0, 0),
+            resume_file: None,
+            verify_openable: false,
+            clamp_size: false,
+            timeout: None,
+            timed_out: AtomicBool::new(false),
+            bytes_read: AtomicU64::new(0),
+            entries_compared: AtomicU64::new(0),
+            cancel: None,
+            #[cfg(unix)]
             inode_maps: Default::default(),
+            #[cfg(unix)]
+            ignore_rules: Default::default(),
         }
     }
 
+    /// Ignore entries by their path relative to the root, unlike ignored dirs which match a name anywhere
     #[cfg(unix)]
-    pub fn dirs(&self) -> Fallible<Comparison> {
-        self.entry_eq(&EntryInfo::dir(&self.first)?, &EntryInfo::dir(&self.second)?)
+    pub fn with_ignored_paths(mut self, ignored_paths: HashSet<PathBuf>) -> Self {
+        self.ignored_paths = ignored_paths
+            .iter()
+            .map(|path| path.components().filter(|c| *c != Component::CurDir).collect())
+            .collect();
+        self
     }
 
-    pub fn contents(&self, size: u64) -> Fallible<Comparison> {
-        self.contents_eq(&EntryInfo::file(&self.first)?, &EntryInfo::file(&self.second)?, size)
+    /// Accept differences at these paths relative to the root, warning about them instead of reporting them
+    ///
+    /// Differences are matched by the path they're found at, so an entry missing on one side is matched by its
+    /// directory's path, "." being the root. Ignored paths aren't compared at all, so they have no differences
+    /// to allow.
+    #[cfg(unix)]
+    pub fn with_allowed_diffs(mut self, allowed_diffs: HashSet<PathBuf>) -> Self {
+        self.allowed_diffs = allowed_diffs
+            .iter()
+            .map(|path| {
+                let path: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+                if path.as_os_str().is_empty() {
+                    ".".into()
+                } else {
+                    path
+                }
+            })
+            .collect();
+        self
     }
 
+    /// Ignore entries matching the gitignore-style rules of `.fscmpignore` files in the trees
+    ///
+    /// Rules apply to the subtree of the directory holding the file, and those found on either side apply to both.
     #[cfg(unix)]
-    fn unequal(&self, diff: Diff, first: &EntryInfo, second: &EntryInfo) -> Comparison {
-        let comp = Comparison::Unequal {
-            diff,
-            first: self.first.clone(),
-            second: self.second.clone(),
-            path: if first.path == second.path {
-                Some(first.parent_path.join(&first.path))
-            } else {
-                None
-            },
-        };
-        debug!("{}", comp);
-        comp
+    pub fn with_ignore_files(mut self, ignore_files: bool) -> Self {
+        self.ignore_files = ignore_files;
+        self
     }
 
-    #[cfg(windows)]
-    fn unequal(&self, diff: Diff, _first: &EntryInfo, _second: &EntryInfo) -> Comparison {
-        let comp = Comparison::Unequal {
-            diff,
-            first: self.first.clone(),
-            second: self.second.clone(),
-            path: Some(self.first.clone()),
-        };
-        debug!("{}", comp);
-        comp
+    /// Compare only entries matching any of the gitignore-style `globs`, failing if one isn't a valid glob
+    ///
+    /// Directories are still descended into to find matches, and ignored entries stay ignored even if they match.
+    #[cfg(unix)]
+    pub fn with_include_globs(mut self, globs: &[String]) -> Fallible<Self> {
+        self.include_globs = globs_override(globs)?;
+        Ok(self)
     }
 
+    /// Compare owners by user and group names rather than IDs, falling back to IDs for names which don't resolve
     #[cfg(unix)]
-    fn entry_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        debug!(
-            "Comparing \"{}\" and \"{}\"",
-            first.path.display(),
-            second.path.display()
-        );
+    pub fn with_owner_by_name(mut self, owner_by_name: bool) -> Self {
+        self.owner_names = if owner_by_name { Some(Default::default()) } else { None };
+        self
+    }
 
-        match *self.inode_maps.lock().unwrap() {
-            [ref mut first_map, ref mut second_map] => {
-                let first_entry = first_map.entry(first.metadata.stat().st_ino);
-                let second_entry = second_map.entry(second.metadata.stat().st_ino);
+    /// Compare only this path relative to both roots, still reporting paths relative to the roots
+    #[cfg(unix)]
+    pub fn with_subpath(mut self, subpath: Option<PathBuf>) -> Self {
+        self.subpath = subpath;
+        self
+    }
 
-                let is_new = {
-                    let first_value = entry_get(&first_entry);
-                    let second_value = entry_get(&second_entry);
+    /// Skip entries other than directories which are unchanged on both sides relative to this reference tree
+    ///
+    /// An entry is unchanged when the entry at its path in the reference has the same mode, owners, size,
+    /// modification time and device number, as the trees and the reference are usually copies of a common base, so
+    /// only entries which changed in either copy are compared. Their hard links are still compared.
+    #[cfg(unix)]
+    pub fn with_reference(mut self, reference: Option<PathBuf>) -> Self {
+        self.reference = reference;
+        self
+    }
 
-                    if first_value != second_value {
-                        return Ok(self.unequal(
-                            Diff::Inodes(first_value.cloned(), second_value.cloned()),
-                            &first,
-                            &second,
-                        ));
-                    }
+    /// Match directory entries by their Unicode case folded names, the Turkic way in Turkish and Azerbaijani locales
+    #[cfg(unix)]
+    pub fn with_ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self.turkic_case = ignore_case && case_fold::is_turkic_locale();
+        self
+    }
 
-                    first_value.is_none()
-                };
+    /// Compare the mode and ownership of the roots too, which are ignored by default
+    #[cfg(unix)]
+    pub fn with_compare_root_meta(mut self, compare_root_meta: bool) -> Self {
+        self.compare_root_meta = compare_root_meta;
+        self
+    }
 
-                if is_new {
-                    first_entry.or_insert_with(|| first.path.clone());
-                    second_entry.or_insert_with(|| second.path.clone());
-                } else {
-                    return Ok(Comparison::Equal);
-                }
-            }
-        }
+    /// Compare modification times too
+    #[cfg(unix)]
+    pub fn with_compare_mtimes(mut self, compare_mtimes: bool) -> Self {
+        self.compare_mtimes = compare_mtimes;
+        self
+    }
 
-        if first.path != Path::new(".") {
-            compare_metadata_field!(self, first, second, st_mode, Diff::Modes);
-            compare_metadata_field!(self, first, second, st_uid, Diff::Uids);
-            compare_metadata_field!(self, first, second, st_gid, Diff::Gids);
-        }
-        compare_metadata_field!(self, first, second, st_nlink, Diff::Nlinks);
+    /// Compare modification times only of regular files and symbolic links, as directories' churn with their entries
+    #[cfg(unix)]
+    pub fn with_timestamps_files_only(mut self, timestamps_files_only: bool) -> Self {
+        self.timestamps_files_only = timestamps_files_only;
+        self
+    }
 
-        let file_type = first.metadata.stat().st_mode & libc::S_IFMT;
-        match file_type {
-            libc::S_IFDIR => self.dir_eq(first, second),
-            libc::S_IFREG => self.file_eq(first, second),
-            libc::S_IFLNK => self.symlink_eq(first, second),
-            libc::S_IFBLK => self.block_device_eq(first, second),
-            libc::S_IFCHR => self.char_device_eq(first, second),
-            libc::S_IFIFO => self.fifo_eq(first, second),
-            libc::S_IFSOCK => self.socket_eq(first, second),
-            _ => panic!("Cannot compare, unknown type {:#o}", file_type),
-        }
+    /// Compare the preferred I/O block sizes (`st_blksize`) too, which depend on the filesystems rather than on the
+    /// files' contents, e.g. to tell why copies perform differently
+    #[cfg(unix)]
+    pub fn with_compare_blksize(mut self, compare_blksize: bool) -> Self {
+        self.compare_blksize = compare_blksize;
+        self
     }
 
+    /// Track inodes to compare hard links, on by default
+    ///
+    /// Without tracking, memory no longer grows with the number of entries, but hard link differences go unnoticed
+    /// and hard linked files are compared again at each of their paths.
     #[cfg(unix)]
-    fn entry_filter_map(&self, path_res: io::Result<openat::Entry>) -> Option<io::Result<PathBuf>> {
-        match path_res {
-            Ok(path) => {
-                let path = Path::new(path.file_name());
-                if self.ignored_dirs.contains::<Path>(path) {
-                    None
-                } else {
-                    Some(Ok(PathBuf::from(path)))
-                }
-            }
-            Err(e) => Some(Err(e)),
-        }
+    pub fn with_inode_tracking(mut self, inode_tracking: bool) -> Self {
+        self.inode_tracking = inode_tracking;
+        self
     }
 
+    /// Don't compare link counts, e.g. against copies made without preserving hard links
+    ///
+    /// Hard links are still compared along with `with_inode_tracking`.
     #[cfg(unix)]
-    fn list_dir(&self, entry: &EntryInfo) -> io::Result<HashSet<PathBuf>> {
-        entry
-            .parent
-            .list_dir(&entry.path)?
-            .filter_map(|p| self.entry_filter_map(p))
-            .collect::<Result<_, _>>()
+    pub fn with_ignore_nlink(mut self, ignore_nlink: bool) -> Self {
+        self.ignore_nlink = ignore_nlink;
+        self
     }
 
+    /// Report files which only differ by one having trailing zeroes past the other's end as `Diff::TrailingPadding`
     #[cfg(unix)]
-    fn dir_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        let first_contents: HashSet<_> = self.list_dir(first).context("first")?;
-        let second_contents: HashSet<_> = self.list_dir(second).context("second")?;
+    pub fn with_ignore_trailing_zeros(mut self, ignore_trailing_zeros: bool) -> Self {
+        self.ignore_trailing_zeros = ignore_trailing_zeros;
+        self
+    }
 
-        if first_contents.len() != second_contents.len() {
-            return Ok(self.unequal(Diff::DirContents(first_contents, second_contents), &first, &second));
-        }
+    /// Consider files which only differ by trailing zeroes equal, along with `with_ignore_trailing_zeros`
+    #[cfg(unix)]
+    pub fn with_trailing_zeros_equal(mut self, trailing_zeros_equal: bool) -> Self {
+        self.trailing_zeros_equal = trailing_zeros_equal;
+        self
+    }
 
-        first_contents
-            .par_iter()
-            .map(|name| {
-                if second_contents.contains(name) {
-                    let first = first.child_entry(&name)?;
-                    let second = second.child_entry(&name)?;
-                    self.entry_eq(&first, &second)
-                } else {
-                    Ok(self.unequal(
-                        Diff::DirContents(first_contents.clone(), second_contents.clone()),
-                        &first,
-                        &second,
-                    ))
-                }
-            })
-            .find_any(|r| r.as_ref().ok() != Some(&Comparison::Equal))
-            .unwrap_or(Ok(Comparison::Equal))
+    /// Ignore mode, ownership and hard link count, comparing only contents and directory entries
+    #[cfg(unix)]
+    pub fn with_content_only(mut self, content_only: bool) -> Self {
+        self.content_only = content_only;
+        self
     }
 
+    /// Sample exactly this many chunks of each file, overriding the full compare limit
     #[cfg(unix)]
-    fn file_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        compare_metadata_field!(self, first, second, st_size, Diff::Sizes);
+    pub fn with_sample_chunks(mut self, sample_chunks: Option<u64>) -> Self {
+        self.sample_chunks = sample_chunks;
+        self
+    }
 
-        let metadata_len = first.metadata.len();
-        self.contents_eq(first, second, metadata_len)
+    /// Limit the full compare of each file to this percentage of its size, or to the full compare limit if that's
+    /// less
+    #[cfg(unix)]
+    pub fn with_sample_percent(mut self, sample_percent: Option<f64>) -> Self {
+        self.sample_percent = sample_percent;
+        self
     }
 
-    fn contents_eq(&self, first: &EntryInfo, second: &EntryInfo, size: u64) -> Fallible<Comparison> {
-        #[cfg(unix)]
-        fn open_file(info: &EntryInfo) -> nix::Result<File> {
-            unsafe {
-                Ok(File::from_raw_fd(fcntl::openat(
-                    info.parent.as_raw_fd(),
-                    &info.path,
-                    #[cfg(not(test))]
-                    fcntl::OFlag::O_DIRECT,
-                    #[cfg(test)]
-                    fcntl::OFlag::empty(),
-                    Mode::empty(),
-                )?))
-            }
-        }
+    /// Skip the contents of regular files smaller than `min_size`, still comparing their metadata
+    ///
+    /// Files of differing sizes are still read when trailing zeroes are ignored, to tell whether they only differ
+    /// by them. Directories and other file types aren't affected.
+    #[cfg(unix)]
+    pub fn with_min_size(mut self, min_size: Option<u64>) -> Self {
+        self.min_size = min_size;
+        self
+    }
 
-        #[cfg(windows)]
-        fn open_file(info: &EntryInfo) -> std::io::Result<File> {
-            Ok(OpenOptions::new()
-                .read(true)
-                .custom_flags(winapi::um::winbase::FILE_FLAG_NO_BUFFERING)
-                .open(&info.path)?)
-        }
+    /// Skip the contents of regular files larger than `max_size`, like `with_min_size` skips smaller ones
+    #[cfg(unix)]
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
 
-        if size == 0 {
-            return Ok(Comparison::Equal);
-        }
+    /// Warn about special files (FIFOs and sockets) which are only compared by their metadata
+    #[cfg(unix)]
+    pub fn with_strict_special(mut self, strict_special: bool) -> Self {
+        self.strict_special = strict_special;
+        self
+    }
 
-        debug!(
-            "Comparing contents of \"{}\" and \"{}\" of size {}",
-            first.path.display(),
-            second.path.display(),
-            size
-        );
+    /// Compare the contents of FIFOs by reading up to `limit` bytes from both, e.g. in test harnesses writing fixed
+    /// data to them
+    ///
+    /// This consumes the data, and waits for writers to open the FIFOs and write each chunk for up to the FIFO
+    /// timeout, failing once it expires.
+    #[cfg(unix)]
+    pub fn with_read_fifos(mut self, limit: Option<u64>) -> Self {
+        self.read_fifos = limit;
+        self
+    }
 
-        let file1 = open_file(first)?;
-        let file2 = open_file(second)?;
+    /// How long reading a FIFO waits for data, `DEFAULT_FIFO_TIMEOUT` by default
+    #[cfg(unix)]
+    pub fn with_fifo_timeout(mut self, fifo_timeout: Duration) -> Self {
+        self.fifo_timeout = fifo_timeout;
+        self
+    }
 
-        #[cfg(unix)]
-        let limit = self.full_compare_limit.map(|limit| min(limit, size)).unwrap_or(size);
+    /// Compare inode flags (as set by `chattr`) of regular files and directories
+    #[cfg(target_os = "linux")]
+    pub fn with_compare_flags(mut self, compare_flags: bool) -> Self {
+        self.compare_flags = compare_flags;
+        self
+    }
 
-        #[cfg(windows)]
-        let limit = size;
-        let leap = calc_leap(size, limit, BUF_SIZE_U64);
-
-        debug!("Comparing {} chunks", calc_chunk_count(limit, BUF_SIZE_U64));
-        (0..calc_chunk_count(limit, BUF_SIZE_U64))
-            .into_par_iter()
-            .map(|i| ((i * leap)..min(size, i * leap + BUF_SIZE_U64)))
-            .map(|chunk| {
-                debug!(
-                    "Comparing range [{}:{}) of \"{}\" and \"{}\"",
-                    chunk.start,
-                    chunk.end,
-                    first.path.display(),
-                    second.path.display()
-                );
+    /// Compare whether regular files and directories are marked for transparent compression, e.g. on Btrfs
+    ///
+    /// Entries on filesystems without inode flags are considered equal in this respect.
+    #[cfg(target_os = "linux")]
+    pub fn with_compare_compression(mut self, compare_compression: bool) -> Self {
+        self.compare_compression = compare_compression;
+        self
+    }
 
-                let mut buffer1 = AlignedBuffer(unsafe { std::mem::MaybeUninit::uninit().assume_init() });
-                let mut buffer2 = AlignedBuffer(unsafe { std::mem::MaybeUninit::uninit().assume_init() });
-                let data1 = &mut buffer1.0;
-                let data2 = &mut buffer2.0;
+    /// Compare the extended attributes of entries, by name and value
+    #[cfg(target_os = "linux")]
+    pub fn with_compare_xattrs(mut self, compare_xattrs: bool) -> Self {
+        self.compare_xattrs = compare_xattrs;
+        self
+    }
 
-                let mut chunked_data1 = &mut data1[..(chunk.end - chunk.start) as usize];
-                let mut chunked_data2 = &mut data2[..(chunk.end - chunk.start) as usize];
+    /// Leave extended attributes whose names match any of `globs` (e.g. `security.selinux` or `user.*`) out of their
+    /// comparison
+    #[cfg(target_os = "linux")]
+    pub fn with_ignored_xattrs(mut self, globs: &[String]) -> Fallible<Self> {
+        self.ignored_xattrs = globs_override(globs)?;
+        Ok(self)
+    }
 
-                #[cfg(unix)]
-                file1
-                    .read_exact_at(&mut chunked_data1, chunk.start)
-                    .with_context(|e| format!("\"{}\": {}", first.path.display().to_string(), e))?;
-                #[cfg(unix)]
-                file2
-                    .read_exact_at(&mut chunked_data2, chunk.start)
-                    .with_context(|e| format!("\"{}\": {}", second.path.display().to_string(), e))?;
+    /// Check that identical regular files also share their physical extents, as reflinked or deduplicated files do
+    #[cfg(target_os = "linux")]
+    pub fn with_compare_extents(mut self, compare_extents: bool) -> Self {
+        self.compare_extents = compare_extents;
+        self
+    }
 
-                #[cfg(windows)]
-                file1
-                    .seek_read(&mut chunked_data1, chunk.start)
-                    .with_context(|e| format!("\"{}\": {}", first.path.display().to_string(), e))?;
-                #[cfg(windows)]
-                file2
-                    .seek_read(&mut chunked_data2, chunk.start)
-                    .with_context(|e| format!("\"{}\": {}", second.path.display().to_string(), e))?;
+    /// Check that identical regular files also have their holes at the same offsets
+    #[cfg(target_os = "linux")]
+    pub fn with_compare_sparse_map(mut self, compare_sparse_map: bool) -> Self {
+        self.compare_sparse_map = compare_sparse_map;
+        self
+    }
 
-                Ok(if chunked_data1 == chunked_data2 {
-                    Comparison::Equal
-                } else {
-                    let diff_index = get_diff_index(chunked_data1, chunked_data2);
-                    let local_lba = diff_index / BLOCK_SIZE * BLOCK_SIZE;
-                    let lba = ((chunk.start as usize) + diff_index) / BLOCK_SIZE;
-                    self.unequal(
-                        Diff::Contents(
-                            lba as u64,
-                            chunked_data1.subslice(local_lba, BLOCK_SIZE).to_vec(),
-                            chunked_data2.subslice(local_lba, BLOCK_SIZE).to_vec(),
-                        ),
-                        &first,
-                        &second,
-                    )
-                })
-            })
-            .find_any(|r| r.as_ref().ok() != Some(&Comparison::Equal))
-            .unwrap_or_else(|| {
-                debug!(
-                    "Compare of \"{}\" and \"{}\" finished",
-                    first.path.display(),
-                    second.path.display()
-                );
-                Ok(Comparison::Equal)
-            })
+    /// Treat the trees as OverlayFS upper layers, reporting whiteouts and opaque directories as such
+    #[cfg(target_os = "linux")]
+    pub fn with_overlay_aware(mut self, overlay_aware: bool) -> Self {
+        self.overlay_aware = overlay_aware;
+        self
     }
 
+    /// Skip entries present on only one side instead of reporting a difference in directory contents
+    ///
+    /// The trees are then only known to agree on their common entries, not to be identical.
     #[cfg(unix)]
-    fn symlink_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        let first_target = first.parent.read_link(&first.path)?;
-        let second_target = second.parent.read_link(&second.path)?;
-        if first_target != second_target {
-            return Ok(self.unequal(Diff::LinkTarget(first_target, second_target), &first, &second));
+    pub fn with_missing_ok(mut self, missing_ok: bool) -> Self {
+        self.missing_ok = missing_ok;
+        self
+    }
+
+    /// Report the hashes of the whole compared contents of files which differ, rather than their first differing block
+    #[cfg(unix)]
+    pub fn with_hash_on_mismatch(mut self, hash_on_mismatch: Option<HashAlgorithm>) -> Self {
+        self.hash_on_mismatch = hash_on_mismatch;
+        self
+    }
+
+    /// Fail with `FsCmpError::ChangedDuringComparison` as soon as a file is found to have shrunk while being read
+    ///
+    /// Otherwise sizes are checked again, and a size difference is reported if they have come to differ.
+    #[cfg(unix)]
+    pub fn with_strict_stable(mut self, strict_stable: bool) -> Self {
+        self.strict_stable = strict_stable;
+        self
+    }
+
+    /// Don't report content differences of files modified since the comparison started, e.g. a live source compared
+    /// with its snapshot, only logging them
+    #[cfg(unix)]
+    pub fn with_ignore_changed(mut self, ignore_changed: bool) -> Self {
+        self.ignore_changed = ignore_changed;
+        self
+    }
+
+    /// Compare the order in which directories list their common entries, e.g. to check archives extract reproducibly
+    #[cfg(unix)]
+    pub fn with_compare_dir_order(mut self, compare_dir_order: bool) -> Self {
+        self.compare_dir_order = compare_dir_order;
+        self
+    }
+
+    /// Report the first difference in path order rather than whichever is found first, at some cost in parallelism
+    #[cfg(unix)]
+    pub fn with_deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Don't warn when both paths are the same file or directory
+    #[cfg(unix)]
+    pub fn with_allow_same(mut self, allow_same: bool) -> Self {
+        self.allow_same = allow_same;
+        self
+    }
+
+    /// Warn once if an entry has an access ACL, as its mask then takes the place of the group permissions in the mode
+    #[cfg(target_os = "linux")]
+    pub fn with_warn_acls(mut self, warn_acls: bool) -> Self {
+        self.warn_acls = warn_acls;
+        self
+    }
+
+    /// Warn about entries which are mount points on only one side
+    #[cfg(unix)]
+    pub fn with_warn_mounts(mut self, warn_mounts: bool) -> Self {
+        self.warn_mounts = warn_mounts;
+        self
+    }
+
+    /// Keep comparing after a difference is found, so that every difference is passed to the diff handler
+    #[cfg(unix)]
+    pub fn with_report_all(mut self, report_all: bool) -> Self {
+        self.report_all = report_all;
+        self
+    }
+
+    /// Log the relative path of every entry compared equal at info level, under `EQUAL_LOG_TARGET`
+    #[cfg(unix)]
+    pub fn with_log_equal(mut self, log_equal: bool) -> Self {
+        self.log_equal = log_equal;
+        self
+    }
+
+    /// Log how much of the contents of files compared equal by sampling was read and where, at info level under
+    /// `EQUAL_LOG_TARGET`, as sampled contents are only known to be equal where they were read
+    #[cfg(unix)]
+    pub fn with_log_coverage(mut self, log_coverage: bool) -> Self {
+        self.log_coverage = log_coverage;
+        self
+    }
+
+    /// Report differences with `label` as their first path instead of the first path given to `new`
+    pub fn with_first_label(mut self, label: PathBuf) -> Self {
+        self.labels.0 = Some(label);
+        self
+    }
+
+    /// Report differences with `label` as their second path instead of the second path given to `new`
+    pub fn with_second_label(mut self, label: PathBuf) -> Self {
+        self.labels.1 = Some(label);
+        self
+    }
+
+    /// Call `diff_handler` with each difference as soon as it is found, possibly from multiple threads
+    pub fn with_diff_handler(mut self, diff_handler: impl Fn(&Comparison) + Send + Sync + 'static) -> Self {
+        self.diff_handler = Some(Box::new(diff_handler));
+        self
+    }
+
+    /// Call `entry_handler` with the decision about each entry as soon as it's made, possibly from multiple threads
+    ///
+    /// Entries are given by their paths relative to the roots. Besides being equal or unequal, entries may be
+    /// ignored, skipped as missing on one side, or differ where it's allowed or because they changed while compared.
+    pub fn with_entry_handler(
+        mut self,
+        entry_handler: impl Fn(&Path, &EntryDecision) + Send + Sync + 'static,
+    ) -> Self {
+        self.entry_handler = Some(Box::new(entry_handler));
+        self
+    }
+
+    /// Call `progress_handler` every `interval` while comparing, with the work done out of the work estimated
+    ///
+    /// Comparing directories starts by counting the entries of the first tree and the sizes of its files.
+    pub fn with_progress_handler(
+        mut self,
+        interval: Duration,
+        progress_handler: impl Fn(&Progress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress_handler = Some((interval, Box::new(progress_handler)));
+        self
+    }
+
+    /// Compare entries of file types this crate doesn't know (e.g. Solaris doors) with `handler`, given the paths of
+    /// both entries, instead of failing with `FsCmpError::UnsupportedType`
+    #[cfg(unix)]
+    pub fn with_unknown_type_handler(
+        mut self,
+        handler: impl Fn(&Path, &Path) -> Fallible<Comparison> + Send + Sync + 'static,
+    ) -> Self {
+        self.unknown_type_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Compare file contents a chunk at a time in order, stopping at the first difference, instead of in parallel
+    pub fn with_first_diff_only(mut self, first_diff_only: bool) -> Self {
+        self.first_diff_only = first_diff_only;
+        self
+    }
+
+    /// Read file contents as usual but skip comparing them, treating them as equal
+    ///
+    /// Only meant for measuring read throughput: differing contents are never reported.
+    pub fn with_bench_read_only(mut self, bench_read_only: bool) -> Self {
+        self.bench_read_only = bench_read_only;
+        self
+    }
+
+    /// Leave the differing blocks out of content differences, for callers only interested in the outcome
+    pub fn with_summary_only(mut self, summary_only: bool) -> Self {
+        self.summary_only = summary_only;
+        self
+    }
+
+    /// Include up to `context_blocks` blocks before and after the differing block in content differences
+    ///
+    /// The context is limited to the chunk read along with the differing block, so it's cut short at chunk
+    /// boundaries rather than read separately.
+    pub fn with_context_blocks(mut self, context_blocks: usize) -> Self {
+        self.context_blocks = context_blocks;
+        self
+    }
+
+    /// Compare the contents of `contents` and `contents_pattern` starting at this byte offset of both paths
+    pub fn with_offset(self, offset: u64) -> Self {
+        self.with_offsets(offset, offset)
+    }
+
+    /// Compare the contents of `contents` starting at a different byte offset of each path, e.g. relocated partitions
+    ///
+    /// Offsets have to be multiples of the block size, as contents are opened with `O_DIRECT`.
+    pub fn with_offsets(mut self, first_offset: u64, second_offset: u64) -> Self {
+        self.offsets = (first_offset, second_offset);
+        self
+    }
+
+    /// Record every decision about an entry in this file, including the entries which weren't compared and why
+    #[cfg(unix)]
+    pub fn with_audit_log(mut self, audit_log: Option<&Path>) -> Fallible<Self> {
+        self.audit_log = audit_log.map(AuditLog::create).transpose()?;
+        Ok(self)
+    }
+
+    /// Record the regions `contents` finds equal in this sidecar file, skipping regions it already records unless the
+    /// first path no longer holds what they were recorded with
+    pub fn with_resume_file(mut self, resume_file: Option<PathBuf>) -> Self {
+        self.resume_file = resume_file;
+        self
+    }
+
+    /// Open files even when they're empty, so that files which can't be read aren't considered equal
+    pub fn with_verify_openable(mut self, verify_openable: bool) -> Self {
+        self.verify_openable = verify_openable;
+        self
+    }
+
+    /// Compare only as much of the contents as both files have when a size larger than either is requested,
+    /// warning about it instead of failing
+    pub fn with_clamp_size(mut self, clamp_size: bool) -> Self {
+        self.clamp_size = clamp_size;
+        self
+    }
+
+    /// Retry listing directories, querying metadata, opening and reading up to `retries` times on errors which network
+    /// filesystems return transiently, with exponential backoff
+    #[cfg(unix)]
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Fail with `FsCmpError::Timeout` if the comparison takes longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Fail with `FsCmpError::Cancelled` once `cancel` is set
+    ///
+    /// Cancellation is cooperative: the flag is checked before each entry and each chunk is compared, so a chunk
+    /// that is already being read is finished first.
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn dirs(&self) -> Fallible<Comparison> {
+        self.warn_if_same()?;
+        self.with_watchdog(|| {
+            self.with_progress(
+                || self.tree_totals(),
+                || self.roots_eq(EntryInfo::dir(&self.first)?, EntryInfo::dir(&self.second)?),
+            )
+        })
+    }
+
+    /// Compare directories like `dirs`, also returning how much work the comparison took
+    #[cfg(unix)]
+    pub fn dirs_with_stats(&self) -> Fallible<(Comparison, ComparisonStats)> {
+        self.with_stats(|| self.dirs())
+    }
+
+    /// Compare files like `contents`, also returning how much work the comparison took
+    pub fn contents_with_stats(&self, size: u64) -> Fallible<(Comparison, ComparisonStats)> {
+        self.with_stats(|| self.contents(size))
+    }
+
+    /// Run the comparison `f` and measure its work, counted from scratch
+    fn with_stats(&self, f: impl FnOnce() -> Fallible<Comparison>) -> Fallible<(Comparison, ComparisonStats)> {
+        self.bytes_read.store(0, Ordering::Relaxed);
+        self.entries_compared.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        let comp = f()?;
+        Ok((
+            comp,
+            ComparisonStats {
+                bytes_read: self.bytes_read.load(Ordering::Relaxed),
+                entries_compared: self.entries_compared.load(Ordering::Relaxed),
+                elapsed: start.elapsed(),
+            },
+        ))
+    }
+
+    /// Compare directories given as open file descriptors (possibly `O_PATH`), which stay owned by the caller
+    ///
+    /// The paths given to `new` only label the reported differences.
+    #[cfg(unix)]
+    pub fn dirs_at(&self, first_fd: RawFd, second_fd: RawFd) -> Fallible<Comparison> {
+        let first = EntryInfo::dir_at(first_fd).context("first")?;
+        let second = EntryInfo::dir_at(second_fd).context("second")?;
+        let (first_stat, second_stat) = (first.metadata.stat(), second.metadata.stat());
+        // dev_t and ino_t aren't u64 on every platform
+        #[allow(clippy::unnecessary_cast)]
+        self.warn_if_same_inode(
+            (first_stat.st_dev as u64, first_stat.st_ino as u64),
+            (second_stat.st_dev as u64, second_stat.st_ino as u64),
+        );
+        self.with_watchdog(|| self.roots_eq(first, second))
+    }
+
+    /// Compare directories in a background thread, yielding every difference as soon as it is found
+    ///
+    /// Differences come in no particular order, as with `with_report_all`, followed by the error that stopped the
+    /// comparison if any. At most `DIFF_QUEUE_SIZE` differences are queued, after which the traversal waits for them
+    /// to be consumed. Dropping the iterator cancels the comparison, setting the flag given to `with_cancel` if any.
+    #[cfg(unix)]
+    pub fn iter_diffs(mut self) -> impl Iterator<Item = Fallible<Comparison>> {
+        let (sender, receiver) = mpsc::sync_channel(DIFF_QUEUE_SIZE);
+        let cancel = self.cancel.get_or_insert_with(Default::default).clone();
+        let diff_handler = self.diff_handler.take();
+        let diff_sender = sender.clone();
+        let fscmp = self.with_report_all(true).with_diff_handler(move |comp| {
+            if let Some(diff_handler) = &diff_handler {
+                diff_handler(comp);
+            }
+            if diff_sender.send(Ok(comp.clone())).is_err() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        thread::spawn(move || {
+            if let Err(e) = fscmp.dirs() {
+                let _ = sender.send(Err(e));
+            }
+        });
+        receiver.into_iter()
+    }
+
+    /// Compare the roots, or their subpaths if one was given
+    #[cfg(unix)]
+    fn roots_eq(&self, mut first: EntryInfo, mut second: EntryInfo) -> Fallible<Comparison> {
+        if let Some(subpath) = &self.subpath {
+            if subpath
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                bail!(
+                    "Subpath \"{}\" must be relative and inside the roots",
+                    subpath.display()
+                );
+            }
+            for name in subpath.components().filter(|c| *c != Component::CurDir) {
+                first = first.child_entry(name.as_ref()).context("first")?;
+                second = second.child_entry(name.as_ref()).context("second")?;
+            }
+            let first_type = first.metadata.stat().st_mode & libc::S_IFMT;
+            let second_type = second.metadata.stat().st_mode & libc::S_IFMT;
+            if first_type != second_type {
+                bail!("\"{}\" isn't of the same type in both trees", subpath.display());
+            }
         }
+        let comp = self.entry_eq(&first, &second)?;
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.check()?;
+        }
+        Ok(comp)
+    }
 
-        Ok(Comparison::Equal)
+    pub fn contents(&self, size: u64) -> Fallible<Comparison> {
+        #[cfg(unix)]
+        self.warn_if_same()?;
+        self.with_watchdog(|| {
+            let (first, second) = (EntryInfo::file(&self.first)?, EntryInfo::file(&self.second)?);
+            let size = self.checked_size(&[(&first, self.offsets.0), (&second, self.offsets.1)], size)?;
+            let resume = match &self.resume_file {
+                Some(path) => Some(ResumeLog::open(path, size, self.offsets, BUF_SIZE_U64)?),
+                None => None,
+            };
+            self.with_progress(
+                || (None, Some(2 * self.planned_bytes(size))),
+                || self.contents_eq(&first, &second, self.offsets, size, resume.as_ref()),
+            )
+        })
+    }
+
+    /// Compare the first `size` bytes of the first path against a constant byte pattern, ignoring the second path
+    pub fn contents_pattern(&self, size: u64, pattern: u8) -> Fallible<Comparison> {
+        self.with_watchdog(|| {
+            let first = EntryInfo::file(&self.first)?;
+            let size = self.checked_size(&[(&first, self.offsets.0)], size)?;
+            if size == 0 {
+                return Ok(Comparison::Equal);
+            }
+            let source = ContentSource::open(&first)?;
+            self.sources_eq(
+                &first,
+                &first,
+                &source,
+                &ContentSource::Pattern(pattern),
+                self.offsets,
+                size,
+                None,
+            )
+        })
+    }
+
+    /// Compare block devices up to their ends, with the sizes read from the devices, reporting devices of different
+    /// sizes past their offsets as a size difference
+    #[cfg(target_os = "linux")]
+    pub fn whole_devices(&self) -> Fallible<Comparison> {
+        let (first, second) = (EntryInfo::file(&self.first)?, EntryInfo::file(&self.second)?);
+        let mut sizes = [0; 2];
+        for (size, info) in sizes.iter_mut().zip(&[&first, &second]) {
+            if info.metadata.stat().st_mode & libc::S_IFMT != libc::S_IFBLK {
+                bail!(
+                    "\"{}\": Not a block device, the size to compare has to be given",
+                    info.path.display()
+                );
+            }
+            *size = info
+                .file_size()
+                .with_context(|e| format!("{}, the size to compare has to be given", e))?
+                .unwrap_or_default();
+        }
+        let available = (
+            sizes[0].saturating_sub(self.offsets.0),
+            sizes[1].saturating_sub(self.offsets.1),
+        );
+        if available.0 != available.1 {
+            return Ok(self.unequal(Diff::Sizes(sizes[0] as i64, sizes[1] as i64), &first, &second));
+        }
+        self.contents(available.0)
+    }
+
+    /// `size`, if each file has that many bytes past its offset, failing otherwise unless the size is clamped to what
+    /// they all have
+    ///
+    /// Comparing no bytes of a block device is rejected, as it would compare equal whatever the device holds, while
+    /// empty regular files are still compared. So are offsets within blocks, as contents are opened with `O_DIRECT`.
+    fn checked_size(&self, files: &[(&EntryInfo, u64)], size: u64) -> Fallible<u64> {
+        if let Some((info, offset)) = files.iter().find(|(_, offset)| offset % BLOCK_SIZE as u64 != 0) {
+            bail!(
+                "\"{}\": Can't compare from offset {}, which isn't a multiple of the {} bytes block size",
+                info.path.display(),
+                offset,
+                BLOCK_SIZE
+            );
+        }
+        if size == 0 {
+            if let Some((info, _)) = files.iter().find(|(info, _)| info.is_block_device()) {
+                bail!(
+                    "\"{}\": Can't compare 0 bytes of a block device, the size to compare has to be given",
+                    info.path.display()
+                );
+            }
+        }
+        let mut checked = size;
+        for (info, offset) in files {
+            let file_size = match info.file_size()? {
+                Some(file_size) => file_size,
+                None => continue,
+            };
+            let available = file_size.saturating_sub(*offset);
+            if available < checked {
+                if !self.clamp_size {
+                    bail!(
+                        "\"{}\": Can't compare {} bytes from offset {}, as it's only {} bytes in size",
+                        info.path.display(),
+                        size,
+                        offset,
+                        file_size
+                    );
+                }
+                self.warn(&format!(
+                    "Comparing {} bytes instead of {}, as \"{}\" is only {} bytes in size",
+                    available,
+                    size,
+                    info.path.display(),
+                    file_size
+                ));
+                checked = available;
+            }
+        }
+        Ok(checked)
     }
 
+    /// Compare both paths by reading them sequentially, for pipes and other inputs which can't be read at an offset
+    ///
+    /// At most `limit` bytes are compared if given, otherwise both streams are read to their end.
     #[cfg(unix)]
-    fn block_device_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        self.char_device_eq(first, second)
+    pub fn streams(&self, limit: Option<u64>) -> Fallible<Comparison> {
+        self.with_watchdog(|| {
+            let mut first =
+                File::open(&self.first).with_context(|e| format!("\"{}\": {}", self.first.display(), e))?;
+            let mut second =
+                File::open(&self.second).with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+            self.streams_eq(&mut first, &mut second, limit.unwrap_or(u64::MAX), None)
+        })
     }
 
+    /// Comparing a path to itself is usually a mistake, e.g. by a shell glob expanding to a single path
     #[cfg(unix)]
-    fn char_device_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
-        compare_metadata_field!(self, first, second, st_rdev, Diff::DeviceTypes);
+    fn warn_if_same(&self) -> Fallible<()> {
+        if self.allow_same {
+            return Ok(());
+        }
+        let first = fs::metadata(canonicalize_root(&self.first)?)?;
+        let second = fs::metadata(canonicalize_root(&self.second)?)?;
+        self.warn_if_same_inode((first.dev(), first.ino()), (second.dev(), second.ino()));
+        Ok(())
+    }
 
-        Ok(Comparison::Equal)
+    /// Warn if both `(device, inode)` pairs are the same, unless allowed
+    #[cfg(unix)]
+    fn warn_if_same_inode(&self, first: (u64, u64), second: (u64, u64)) {
+        if !self.allow_same && first == second {
+            self.warn(&format!(
+                "\"{}\" and \"{}\" are the same, the comparison is trivially equal",
+                self.first.display(),
+                self.second.display()
+            ));
+        }
+    }
+
+    /// Run `f`, making the comparison fail with `FsCmpError::Timeout` once the timeout expires
+    fn with_watchdog<T: Send>(&self, f: impl FnOnce() -> Fallible<T> + Send) -> Fallible<T> {
+        #[cfg(unix)]
+        {
+            *self.started.lock().unwrap() = Some(SystemTime::now());
+        }
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return f(),
+        };
+
+        self.timed_out.store(false, Ordering::Relaxed);
+        let (done, watchdog_done) = mpsc::channel::<()>();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = watchdog_done.recv_timeout(timeout) {
+                    debug!("Timed out after {:?}", timeout);
+                    self.timed_out.store(true, Ordering::Relaxed);
+                }
+            });
+            let result = f();
+            drop(done);
+            result
+        })
+    }
+
+    /// Run `f`, reporting its progress to the progress handler if there's one, towards the entries and bytes
+    /// `totals` estimates it to take
+    fn with_progress<T: Send>(
+        &self,
+        totals: impl FnOnce() -> (Option<u64>, Option<u64>),
+        f: impl FnOnce() -> Fallible<T> + Send,
+    ) -> Fallible<T> {
+        let (interval, progress_handler) = match &self.progress_handler {
+            Some((interval, progress_handler)) => (*interval, progress_handler),
+            None => return f(),
+        };
+        let (entries_total, bytes_total) = totals();
+        let (entries_start, bytes_start) = (
+            self.entries_compared.load(Ordering::Relaxed),
+            self.bytes_read.load(Ordering::Relaxed),
+        );
+        let start = Instant::now();
+        let progress = || Progress {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed) - bytes_start,
+            bytes_total,
+            entries_compared: self.entries_compared.load(Ordering::Relaxed) - entries_start,
+            entries_total,
+            elapsed: start.elapsed(),
+        };
+
+        let (done, reporter_done) = mpsc::channel::<()>();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = reporter_done.recv_timeout(interval) {
+                    progress_handler(&progress());
+                }
+            });
+            let result = f();
+            drop(done);
+            result
+        })
     }
 
+    /// Bytes comparing the trees would read from both sides together, going by the files of the first tree and
+    /// without reading any
     #[cfg(unix)]
-    fn fifo_eq(&self, _first: &EntryInfo, _second: &EntryInfo) -> Fallible<Comparison> {
-        Ok(Comparison::Equal)
+    pub fn estimate_dirs(&self) -> u64 {
+        self.tree_totals().1.unwrap_or_default()
+    }
+
+    /// Bytes comparing `size` bytes of the files would read from both together, without reading any
+    pub fn estimate_contents(&self, size: u64) -> Fallible<u64> {
+        let (first, second) = (EntryInfo::file(&self.first)?, EntryInfo::file(&self.second)?);
+        let size = self.checked_size(&[(&first, self.offsets.0), (&second, self.offsets.1)], size)?;
+        Ok(2 * self.planned_bytes(size))
     }
 
+    /// The entries of the first tree, counting its root, and the bytes comparing them would read, found by walking
+    /// it without comparing anything
     #[cfg(unix)]
-    fn socket_eq(&self, _first: &EntryInfo, _second: &EntryInfo) -> Fallible<Comparison> {
-        Ok(Comparison::Equal)
+    fn tree_totals(&self) -> (Option<u64>, Option<u64>) {
+        let (mut entries, mut bytes) = (0, 0);
+        let mut pending = vec![match &self.subpath {
+            Some(subpath) => self.first.join(subpath),
+            None => self.first.clone(),
+        }];
+        while let Some(path) = pending.pop() {
+            // Only an estimate, what can't be read is left for the comparison to report
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            entries += 1;
+            let len = metadata.len();
+            if metadata.is_file() && self.is_size_compared(len) {
+                bytes += 2 * self.planned_bytes(len);
+            } else if metadata.is_dir() {
+                if let Ok(dir) = fs::read_dir(&path) {
+                    pending.extend(
+                        dir.flatten()
+                            .filter(|entry| !self.ignored_dirs.contains(Path::new(&entry.file_name())))
+                            .map(|entry| entry.path()),
+                    );
+                }
+            }
+        }
+        debug!("Counted {} entries of {} bytes to compare", entries, bytes);
+        (Some(entries), Some(bytes))
     }
-}
 
-#[cfg(unix)]
-fn entry_get<'a, K, V>(entry: &'a hash_map::Entry<K, V>) -> Option<&'a V> {
-    match entry {
-        hash_map::Entry::Vacant(_) => None,
-        hash_map::Entry::Occupied(ref oe) => Some(oe.get()),
+    /// Checked between entries and chunks, so that a comparison can be stopped before it's complete
+    fn check_interrupted(&self) -> Fallible<()> {
+        if self.timed_out.load(Ordering::Relaxed) {
+            return Err(FsCmpError::Timeout.into());
+        }
+        if let Some(cancel) = &self.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(FsCmpError::Cancelled.into());
+            }
+        }
+        Ok(())
     }
-}
 
-fn get_diff_index(first: &[u8], second: &[u8]) -> usize {
-    for (i, (x, y)) in first.iter().zip(second.iter()).enumerate() {
+    /// Run `f` on `path`, retrying transient errors
+    #[cfg(unix)]
+    fn retry<T>(&self, path: &Path, mut f: impl FnMut() -> Fallible<T>) -> Fallible<T> {
+        let mut backoff = retry::INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries => match retry::transient_errno(&e) {
+                    Some(errno) => {
+                        attempt += 1;
+                        warn!(
+                            "Retrying \"{}\" after {:?} ({}), attempt {} of {}",
+                            path.display(),
+                            nix::errno::Errno::from_i32(errno),
+                            e,
+                            attempt,
+                            self.retries
+                        );
+                        thread::sleep(backoff);
+                        backoff *= 2;
+                        self.check_interrupted()?;
+                    }
+                    None => return Err(e),
+                },
+                res => return res,
+            }
+        }
+    }
+
+    #[cfg(windows)]
+    fn retry<T>(&self, _path: &Path, f: impl FnOnce() -> Fallible<T>) -> Fallible<T> {
+        f()
+    }
+
+    #[cfg(unix)]
+    fn unequal(&self, diff: Diff, first: &EntryInfo, second: &EntryInfo) -> Comparison {
+        let path = if self.paths_eq(&first.path, &second.path) {
+            Some(first.relative_path())
+        } else {
+            None
+        };
+        self.report(diff, path, Some(first.metadata.stat().st_mode & libc::S_IFMT))
+    }
+
+    /// Whether paths are the same, up to case if it's ignored
+    #[cfg(unix)]
+    fn paths_eq(&self, first: &Path, second: &Path) -> bool {
+        first == second
+            || (self.ignore_case
+                && case_fold::fold(first.as_os_str(), self.turkic_case)
+                    == case_fold::fold(second.as_os_str(), self.turkic_case))
+    }
+
+    /// Build a difference at `path`, between entries of `file_type` if known, and pass it to the diff handler,
+    /// unless differences are allowed there
+    #[cfg(unix)]
+    fn report(&self, diff: Diff, path: Option<PathBuf>, file_type: Option<u32>) -> Comparison {
+        let (first, second) = self.reported_paths();
+        let comp = Comparison::Unequal {
+            diff,
+            first,
+            second,
+            path,
+        };
+        debug!("{}", comp);
+        if let Comparison::Unequal {
+            diff, path: Some(path), ..
+        } = &comp
+        {
+            if self.allowed_diffs.contains(path) {
+                self.warn(&format!("Allowed difference, {}", comp));
+                self.decided(Some(path), file_type, Decision::Allowed, Some(diff));
+                return Comparison::Equal;
+            }
+        }
+        if let Comparison::Unequal { diff, path, .. } = &comp {
+            self.decided(path.as_deref(), file_type, Decision::Unequal, Some(diff));
+        }
+        if let Some(diff_handler) = &self.diff_handler {
+            diff_handler(&comp);
+        }
+        comp
+    }
+
+    #[cfg(windows)]
+    fn unequal(&self, diff: Diff, _first: &EntryInfo, _second: &EntryInfo) -> Comparison {
+        let (first, second) = self.reported_paths();
+        let comp = Comparison::Unequal {
+            diff,
+            first,
+            second,
+            path: Some(self.first.clone()),
+        };
+        debug!("{}", comp);
+        if let Comparison::Unequal { diff, path, .. } = &comp {
+            self.decided(path.as_deref(), None, Decision::Unequal, Some(diff));
+        }
+        if let Some(diff_handler) = &self.diff_handler {
+            diff_handler(&comp);
+        }
+        comp
+    }
+
+    /// The paths differences are reported with, unless labeled otherwise
+    fn reported_paths(&self) -> (PathBuf, PathBuf) {
+        (
+            self.labels.0.clone().unwrap_or_else(|| self.first.clone()),
+            self.labels.1.clone().unwrap_or_else(|| self.second.clone()),
+        )
+    }
+
+    /// Pass a decision about the entry at `path` of `file_type` to the entry handler and the audit log, logging it
+    /// if structured logging is enabled
+    fn decided(&self, path: Option<&Path>, file_type: Option<u32>, decision: Decision, diff: Option<&Diff>) {
+        #[cfg(feature = "structured-log")]
+        decision::log(path, decision, diff);
+        #[cfg(unix)]
+        {
+            if let (Some(audit_log), Some(path)) = (&self.audit_log, path) {
+                audit_log.record(path, file_type, decision, diff);
+            }
+        }
+        #[cfg(windows)]
+        let _ = file_type;
+        if let (Some(entry_handler), Some(path)) = (&self.entry_handler, path) {
+            entry_handler(path, &EntryDecision { decision, diff });
+        }
+    }
+
+    #[cfg(unix)]
+    fn entry_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        self.check_interrupted()?;
+        self.entries_compared.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Comparing \"{}\" and \"{}\"",
+            first.path.display(),
+            second.path.display()
+        );
+
+        // Hard links are metadata as well. Each inode maps to the first path it was found at, and as both trees are
+        // traversed together, the maps agree on every entry exactly when paths are hard linked the same way in both.
+        // Which paths share an inode is compared rather than inode numbers, which differ between copies of a tree,
+        // reflinked ones included, and repeat across the filesystems mounted in one, so inodes are keyed by device.
+        if !self.content_only && self.inode_tracking {
+            match *self.inode_maps.lock().unwrap() {
+                [ref mut first_map, ref mut second_map] => {
+                    let (first_stat, second_stat) = (first.metadata.stat(), second.metadata.stat());
+                    let first_entry = first_map.entry((first_stat.st_dev, first_stat.st_ino));
+                    let second_entry = second_map.entry((second_stat.st_dev, second_stat.st_ino));
+
+                    let is_new = {
+                        let first_value = entry_get(&first_entry);
+                        let second_value = entry_get(&second_entry);
+
+                        let same_path = match (first_value, second_value) {
+                            (Some(first_value), Some(second_value)) => self.paths_eq(first_value, second_value),
+                            (first_value, second_value) => first_value == second_value,
+                        };
+                        if !same_path {
+                            return Ok(self.unequal(
+                                Diff::Inodes(first_value.cloned(), second_value.cloned()),
+                                first,
+                                second,
+                            ));
+                        }
+
+                        first_value.is_none()
+                    };
+
+                    if is_new {
+                        first_entry.or_insert_with(|| first.path.clone());
+                        second_entry.or_insert_with(|| second.path.clone());
+                    } else {
+                        return Ok(self.equal(first));
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if self.overlay_aware && first.is_whiteout() != second.is_whiteout() {
+                return Ok(self.unequal(
+                    Diff::Whiteouts(first.is_whiteout(), second.is_whiteout()),
+                    first,
+                    second,
+                ));
+            }
+        }
+
+        if self.reference.is_some() && self.unchanged_from_reference(first, second) {
+            let file_type = first.metadata.stat().st_mode & libc::S_IFMT;
+            self.decided(Some(&first.relative_path()), Some(file_type), Decision::Unchanged, None);
+            return Ok(Comparison::Equal);
+        }
+
+        let file_type = first.metadata.stat().st_mode & libc::S_IFMT;
+        let second_type = second.metadata.stat().st_mode & libc::S_IFMT;
+        if file_type != second_type {
+            // Entries of different types can't be compared any further
+            return Ok(self.unequal(Diff::Types(file_type, second_type), first, second));
+        }
+
+        let mut comp = Comparison::Equal;
+        if !self.content_only {
+            if first.path != Path::new(".") || self.compare_root_meta {
+                compare_metadata_field!(self, comp, first, second, st_mode, Diff::Modes);
+                #[cfg(target_os = "linux")]
+                {
+                    if self.warn_acls && !self.acls_warned.load(Ordering::Relaxed) {
+                        self.warn_if_acl(first, &self.first)?;
+                        self.warn_if_acl(second, &self.second)?;
+                    }
+                }
+                if let Some(owner_names) = &self.owner_names {
+                    let (first_stat, second_stat) = (first.metadata.stat(), second.metadata.stat());
+                    let uids = (first_stat.st_uid, second_stat.st_uid);
+                    let gids = (first_stat.st_gid, second_stat.st_gid);
+                    merge_comparison!(
+                        self,
+                        comp,
+                        self.owner_eq(uids, |uid| owner_names.user(uid), Diff::Uids, first, second)
+                    );
+                    merge_comparison!(
+                        self,
+                        comp,
+                        self.owner_eq(gids, |gid| owner_names.group(gid), Diff::Gids, first, second)
+                    );
+                } else {
+                    compare_metadata_field!(self, comp, first, second, st_uid, Diff::Uids);
+                    compare_metadata_field!(self, comp, first, second, st_gid, Diff::Gids);
+                }
+            }
+            if !self.ignore_nlink {
+                compare_metadata_field!(self, comp, first, second, st_nlink, Diff::Nlinks);
+            }
+            let is_timestamped =
+                file_type == libc::S_IFREG || file_type == libc::S_IFLNK || !self.timestamps_files_only;
+            if self.compare_mtimes && is_timestamped && (first.path != Path::new(".") || self.compare_root_meta) {
+                let mtime = |entry: &EntryInfo| {
+                    let stat = entry.metadata.stat();
+                    stat.st_mtime * 1_000_000_000 + stat.st_mtime_nsec
+                };
+                let mtimes = (mtime(first), mtime(second));
+                if mtimes.0 != mtimes.1 {
+                    merge_comparison!(
+                        self,
+                        comp,
+                        self.unequal(Diff::Mtimes(mtimes.0, mtimes.1), first, second)
+                    );
+                }
+            }
+            if self.compare_blksize {
+                // blksize_t isn't u64 on every platform
+                let blksizes = (
+                    first.metadata.stat().st_blksize as u64,
+                    second.metadata.stat().st_blksize as u64,
+                );
+                if blksizes.0 != blksizes.1 {
+                    let diff = Diff::IoBlockSize(blksizes.0, blksizes.1);
+                    merge_comparison!(self, comp, self.unequal(diff, first, second));
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if self.compare_flags && (file_type == libc::S_IFREG || file_type == libc::S_IFDIR) {
+                merge_comparison!(self, comp, self.inode_flags_eq(first, second)?);
+            }
+            if self.compare_compression && (file_type == libc::S_IFREG || file_type == libc::S_IFDIR) {
+                merge_comparison!(self, comp, self.compression_eq(first, second)?);
+            }
+            if self.compare_xattrs {
+                merge_comparison!(self, comp, self.xattrs_eq(first, second)?);
+            }
+            if self.overlay_aware && file_type == libc::S_IFDIR {
+                merge_comparison!(self, comp, self.opaque_eq(first, second)?);
+            }
+        }
+
+        let type_comp = self.type_eq(file_type, first, second)?;
+        Ok(match first_unequal(comp, type_comp) {
+            Comparison::Equal if file_type == libc::S_IFREG && !self.are_contents_compared(first, second) => {
+                self.decided(
+                    Some(&first.relative_path()),
+                    Some(file_type),
+                    Decision::OutOfSizeRange,
+                    None,
+                );
+                Comparison::Equal
+            }
+            Comparison::Equal => self.equal(first),
+            comp => {
+                if let (Some(audit_log), Comparison::Unequal { path, .. }) = (&self.audit_log, &comp) {
+                    // Directories differing only under them are recorded as well, as no decision is made about them
+                    let relative_path = first.relative_path();
+                    if file_type == libc::S_IFDIR && path.as_ref() != Some(&relative_path) {
+                        audit_log.record(&relative_path, Some(file_type), Decision::Unequal, None);
+                    }
+                }
+                comp
+            }
+        })
+    }
+
+    /// Whether entries other than directories have the metadata of the entry at their path in the reference tree
+    ///
+    /// Directories aren't skipped, as their metadata doesn't tell whether anything under them changed.
+    #[cfg(unix)]
+    #[inline(never)]
+    fn unchanged_from_reference(&self, first: &EntryInfo, second: &EntryInfo) -> bool {
+        let reference = match &self.reference {
+            Some(reference) => reference,
+            None => return false,
+        };
+        let metadata = match fs::symlink_metadata(reference.join(first.relative_path())) {
+            Ok(metadata) if !metadata.is_dir() => metadata,
+            _ => return false,
+        };
+        let unchanged = |entry: &EntryInfo| {
+            let stat = entry.metadata.stat();
+            stat.st_mode == metadata.mode()
+                && stat.st_uid == metadata.uid()
+                && stat.st_gid == metadata.gid()
+                && stat.st_size as u64 == metadata.size()
+                && stat.st_mtime == metadata.mtime()
+                && stat.st_mtime_nsec == metadata.mtime_nsec()
+                && stat.st_rdev == metadata.rdev()
+        };
+        unchanged(first) && unchanged(second)
+    }
+
+    #[cfg(unix)]
+    fn equal(&self, entry: &EntryInfo) -> Comparison {
+        if self.log_equal {
+            info!(target: EQUAL_LOG_TARGET, "\"{}\" is equal", entry.relative_path().display());
+        }
+        let file_type = entry.metadata.stat().st_mode & libc::S_IFMT;
+        self.decided(Some(&entry.relative_path()), Some(file_type), Decision::Equal, None);
+        Comparison::Equal
+    }
+
+    /// Compare entries of the same `file_type` by what's specific to that type
+    ///
+    /// The comparisons of anything but directories aren't inlined, nor are the optional checks of `entry_eq` and
+    /// `dir_eq`, keeping their locals out of the stack frames which recurse into subdirectories.
+    #[cfg(unix)]
+    fn type_eq(&self, file_type: u32, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        match file_type {
+            libc::S_IFDIR => self.dir_eq(first, second),
+            libc::S_IFREG => self.file_eq(first, second),
+            libc::S_IFLNK => self.symlink_eq(first, second),
+            libc::S_IFBLK => self.block_device_eq(first, second),
+            libc::S_IFCHR => self.char_device_eq(first, second),
+            libc::S_IFIFO => self.fifo_eq(first, second),
+            libc::S_IFSOCK => self.socket_eq(first, second),
+            _ => match &self.unknown_type_handler {
+                Some(handler) => {
+                    let relative_path = first.relative_path();
+                    handler(&self.first.join(&relative_path), &self.second.join(&relative_path))
+                }
+                None => Err(FsCmpError::UnsupportedType(first.relative_path(), file_type).into()),
+            },
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn inode_flags_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let first_flags = ioctl::inode_flags(&first.open_nofollow()?)?;
+        let second_flags = ioctl::inode_flags(&second.open_nofollow()?)?;
+        Ok(match (first_flags, second_flags) {
+            (Some(first_flags), Some(second_flags)) if first_flags != second_flags => {
+                self.unequal(Diff::InodeFlags(first_flags, second_flags), first, second)
+            }
+            _ => Comparison::Equal,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn compression_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let first_compressed = ioctl::compressed(&first.open_nofollow()?)?;
+        let second_compressed = ioctl::compressed(&second.open_nofollow()?)?;
+        Ok(match (first_compressed, second_compressed) {
+            (Some(first_compressed), Some(second_compressed)) if first_compressed != second_compressed => {
+                self.unequal(Diff::Compression(first_compressed, second_compressed), first, second)
+            }
+            _ => Comparison::Equal,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn xattrs_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let xattrs = |entry: &EntryInfo| -> Fallible<BTreeMap<String, Vec<u8>>> {
+            let dir_fd = entry.parent.as_raw_fd();
+            let mut xattrs = BTreeMap::new();
+            for name in xattr::list_at(dir_fd, &entry.path)
+                .with_context(|e| format!("\"{}\": {}", entry.path.display(), e))?
+            {
+                if self
+                    .ignored_xattrs
+                    .as_ref()
+                    .is_some_and(|ignored| ignored.matched(&name, false).is_whitelist())
+                {
+                    continue;
+                }
+                // Removed since it was listed
+                if let Some(value) = xattr::get_at(dir_fd, &entry.path, &name)
+                    .with_context(|e| format!("\"{}\": {}", entry.path.display(), e))?
+                {
+                    xattrs.insert(name, value);
+                }
+            }
+            Ok(xattrs)
+        };
+        let (mut first_xattrs, mut second_xattrs) = (xattrs(first)?, xattrs(second)?);
+        if first_xattrs == second_xattrs {
+            return Ok(Comparison::Equal);
+        }
+        let same: Vec<_> = first_xattrs
+            .iter()
+            .filter(|(name, value)| second_xattrs.get(*name) == Some(value))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in same {
+            first_xattrs.remove(&name);
+            second_xattrs.remove(&name);
+        }
+        Ok(self.unequal(Diff::Xattrs(first_xattrs, second_xattrs), first, second))
+    }
+
+    /// Compare owner IDs by their names, if both resolve
+    #[cfg(unix)]
+    fn owner_eq(
+        &self,
+        (first_id, second_id): (u32, u32),
+        name: impl Fn(u32) -> Option<String>,
+        diff: fn(u32, u32) -> Diff,
+        first: &EntryInfo,
+        second: &EntryInfo,
+    ) -> Comparison {
+        if first_id == second_id {
+            return Comparison::Equal;
+        }
+        match (name(first_id), name(second_id)) {
+            (Some(first_name), Some(second_name)) if first_name == second_name => Comparison::Equal,
+            _ => self.unequal(diff(first_id, second_id), first, second),
+        }
+    }
+
+    /// Warn if `entry` of the tree at `root` has an access ACL, unless a warning was already given
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn warn_if_acl(&self, entry: &EntryInfo, root: &Path) -> Fallible<()> {
+        let has_acl = xattr::is_set_at(entry.parent.as_raw_fd(), &entry.path, "system.posix_acl_access")
+            .with_context(|e| format!("\"{}\": {}", entry.relative_path().display(), e))?;
+        if has_acl && !self.acls_warned.swap(true, Ordering::Relaxed) {
+            self.warn(&format!(
+                "\"{}\" in \"{}\" has an ACL, whose mask replaces the group permissions of the mode, so comparing \
+                 modes doesn't compare all permissions",
+                entry.relative_path().display(),
+                root.display()
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn opaque_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let is_opaque = |entry: &EntryInfo| -> Fallible<bool> {
+            let dir = entry.open_nofollow()?;
+            // Mounts with userxattr use the user namespace instead of the trusted one
+            for name in &["trusted.overlay.opaque", "user.overlay.opaque"] {
+                if xattr::get(&dir, name)?.as_deref() == Some(b"y") {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        };
+        let (first_opaque, second_opaque) = (is_opaque(first)?, is_opaque(second)?);
+        Ok(if first_opaque != second_opaque {
+            self.unequal(Diff::OpaqueDirs(first_opaque, second_opaque), first, second)
+        } else {
+            Comparison::Equal
+        })
+    }
+
+    /// Report whiteouts present on only one side, removing them from the directory contents
+    #[cfg(target_os = "linux")]
+    #[inline(never)]
+    fn one_sided_whiteouts_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        first_contents: &mut HashSet<PathBuf>,
+        second_contents: &mut HashSet<PathBuf>,
+    ) -> Fallible<Comparison> {
+        let mut comp = Comparison::Equal;
+        let first_only: Vec<_> = first_contents.difference(second_contents).cloned().collect();
+        let second_only: Vec<_> = second_contents.difference(first_contents).cloned().collect();
+        for (entry, names, contents, is_first) in [
+            (first, first_only, &mut *first_contents, true),
+            (second, second_only, &mut *second_contents, false),
+        ] {
+            for name in names {
+                let child = entry.child_entry(&name)?;
+                if child.is_whiteout() {
+                    contents.remove(&name);
+                    let path = Some(child.relative_path());
+                    let diff = Diff::Whiteouts(is_first, !is_first);
+                    merge_comparison!(self, comp, self.report(diff, path, Some(libc::S_IFCHR)));
+                }
+            }
+        }
+        Ok(comp)
+    }
+
+    #[cfg(unix)]
+    fn entry_filter_map(
+        &self,
+        dir_path: &Path,
+        path_res: io::Result<openat::Entry>,
+    ) -> Option<io::Result<PathBuf>> {
+        match path_res {
+            Ok(entry) => {
+                let path = Path::new(entry.file_name());
+                let is_dir = entry.simple_type() == Some(openat::SimpleType::Dir);
+                if self.ignored_dirs.contains::<Path>(path)
+                    || (!self.ignored_paths.is_empty() && self.ignored_paths.contains(&dir_path.join(path)))
+                    || (self.ignore_files && self.is_ignored_by_rules(&dir_path.join(path), is_dir))
+                    || !self.is_included(entry.simple_type(), &dir_path.join(path))
+                {
+                    let file_type = match entry.simple_type() {
+                        Some(openat::SimpleType::Dir) => Some(libc::S_IFDIR),
+                        Some(openat::SimpleType::File) => Some(libc::S_IFREG),
+                        Some(openat::SimpleType::Symlink) => Some(libc::S_IFLNK),
+                        Some(openat::SimpleType::Other) | None => None,
+                    };
+                    self.decided(Some(&dir_path.join(path)), file_type, Decision::Ignored, None);
+                    None
+                } else {
+                    Some(Ok(PathBuf::from(path)))
+                }
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// Whether an entry matches the include globs, if there are any, directories and entries of unknown types
+    /// always being included so they can be looked into
+    #[cfg(unix)]
+    fn is_included(&self, simple_type: Option<openat::SimpleType>, path: &Path) -> bool {
+        match (&self.include_globs, simple_type) {
+            (Some(include_globs), Some(simple_type)) if simple_type != openat::SimpleType::Dir => {
+                include_globs.matched(path, false).is_whitelist()
+            }
+            _ => true,
+        }
+    }
+
+    /// Load the ignore files of a directory on both sides, if there are any
+    #[cfg(unix)]
+    #[inline(never)]
+    fn load_ignore_files(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<()> {
+        let dir_path = first.relative_path();
+        let mut builder = GitignoreBuilder::new(&dir_path);
+        let mut found = false;
+        for entry in &[first, second] {
+            let file = match entry.parent.open_file(&entry.path.join(IGNORE_FILE_NAME)) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            found = true;
+            for line in BufReader::new(file).lines() {
+                builder.add_line(None, &line?)?;
+            }
+        }
+        if found {
+            debug!("Loaded ignore rules of \"{}\"", dir_path.display());
+            self.ignore_rules
+                .lock()
+                .unwrap()
+                .insert(dir_path, Arc::new(builder.build()?));
+        }
+        Ok(())
+    }
+
+    /// Whether the rules of the closest ignore file to `path` with a matching rule ignore it
+    #[cfg(unix)]
+    fn is_ignored_by_rules(&self, path: &Path, is_dir: bool) -> bool {
+        let ignore_rules = self.ignore_rules.lock().unwrap();
+        for dir in path.ancestors().skip(1) {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if let Some(rules) = ignore_rules.get(dir) {
+                match rules.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => (),
+                }
+            }
+        }
+        false
+    }
+
+    /// The names of a directory's entries which aren't ignored, collected in the order they're listed in
+    #[cfg(unix)]
+    fn list_dir<C: FromIterator<PathBuf>>(&self, entry: &EntryInfo) -> io::Result<C> {
+        let relative_path = entry.relative_path();
+        // The root is "." which shouldn't prefix its children
+        let dir_path = if relative_path == Path::new(".") {
+            Path::new("")
+        } else {
+            &relative_path
+        };
+        entry
+            .parent
+            .list_dir(&entry.path)?
+            .filter_map(|p| self.entry_filter_map(dir_path, p))
+            .collect::<Result<_, _>>()
+    }
+
+    #[cfg(unix)]
+    fn dir_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        if self.ignore_files {
+            self.load_ignore_files(first, second)?;
+        }
+        let orders: Option<(Vec<_>, Vec<_>)> = if self.compare_dir_order {
+            Some((
+                self.retry(&first.path, || Ok(self.list_dir(first)?)).context("first")?,
+                self.retry(&second.path, || Ok(self.list_dir(second)?))
+                    .context("second")?,
+            ))
+        } else {
+            None
+        };
+        let (mut first_contents, mut second_contents): (HashSet<_>, HashSet<_>) = match &orders {
+            Some((first_order, second_order)) => (
+                first_order.iter().cloned().collect(),
+                second_order.iter().cloned().collect(),
+            ),
+            None => (
+                self.retry(&first.path, || Ok(self.list_dir(first)?)).context("first")?,
+                self.retry(&second.path, || Ok(self.list_dir(second)?))
+                    .context("second")?,
+            ),
+        };
+
+        #[cfg(target_os = "linux")]
+        let whiteouts = if self.overlay_aware {
+            let comp = self.one_sided_whiteouts_eq(first, second, &mut first_contents, &mut second_contents)?;
+            if comp != Comparison::Equal && !self.report_all {
+                return Ok(comp);
+            }
+            comp
+        } else {
+            Comparison::Equal
+        };
+        #[cfg(not(target_os = "linux"))]
+        let whiteouts = Comparison::Equal;
+
+        let second_names = if self.ignore_case {
+            self.match_case(first, second, &first_contents, &mut second_contents)?
+        } else {
+            HashMap::new()
+        };
+
+        if self.missing_ok && first_contents != second_contents {
+            for name in first_contents.symmetric_difference(&second_contents) {
+                debug!(
+                    "Skipping \"{}\" which is missing on one side",
+                    first.relative_path().join(name).display()
+                );
+                self.decided(Some(&first.relative_path().join(name)), None, Decision::Skipped, None);
+            }
+            first_contents = first_contents.intersection(&second_contents).cloned().collect();
+            second_contents = first_contents.clone();
+        }
+
+        let order = match orders {
+            Some((first_order, second_order)) => self.dir_order_eq(
+                first,
+                second,
+                (first_order, second_order),
+                (&first_contents, &second_contents),
+                &second_names,
+            ),
+            None => Comparison::Equal,
+        };
+        if order != Comparison::Equal && !self.report_all {
+            return Ok(order);
+        }
+
+        if self.report_all {
+            // Entries present on both sides are still compared after a mismatch in the directory's contents
+            let comp = if first_contents == second_contents {
+                Comparison::Equal
+            } else {
+                self.unequal(
+                    Diff::DirContents(first_contents.clone(), second_contents.clone()),
+                    first,
+                    second,
+                )
+            };
+            let common: Vec<_> = first_contents.intersection(&second_contents).collect();
+            let children = common
+                .par_iter()
+                .map(|name| self.child_eq(first, second, name, &second_names))
+                .try_reduce(|| Comparison::Equal, |a, b| Ok(first_unequal(a, b)))?;
+            return Ok(first_unequal(
+                first_unequal(first_unequal(whiteouts, order), comp),
+                children,
+            ));
+        }
+
+        if first_contents.len() != second_contents.len()
+            || (self.deterministic && first_contents != second_contents)
+        {
+            return Ok(self.unequal(Diff::DirContents(first_contents, second_contents), first, second));
+        }
+
+        if self.deterministic {
+            // Paths order component by component, so the first child to differ holds the first differing path
+            let mut names: Vec<_> = first_contents.iter().collect();
+            names.sort();
+            return names
+                .par_iter()
+                .map(|name| self.child_eq(first, second, name, &second_names))
+                .find_first(|r| r.as_ref().ok() != Some(&Comparison::Equal))
+                .unwrap_or(Ok(Comparison::Equal));
+        }
+
+        // Jobs split from nested parallel iterators are stolen by idle threads at any depth, so the entries of one
+        // huge subdirectory are spread over all threads without flattening the tree into a single queue
+        first_contents
+            .par_iter()
+            .map(|name| {
+                if second_contents.contains(name) {
+                    self.child_eq(first, second, name, &second_names)
+                } else {
+                    Ok(self.unequal(
+                        Diff::DirContents(first_contents.clone(), second_contents.clone()),
+                        first,
+                        second,
+                    ))
+                }
+            })
+            .find_any(|r| r.as_ref().ok() != Some(&Comparison::Equal))
+            .unwrap_or(Ok(Comparison::Equal))
+    }
+
+    /// Compare the order of the entries which are in both directories, as they were listed
+    #[cfg(unix)]
+    #[inline(never)]
+    fn dir_order_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        (first_order, second_order): (Vec<PathBuf>, Vec<PathBuf>),
+        (first_contents, second_contents): (&HashSet<PathBuf>, &HashSet<PathBuf>),
+        second_names: &HashMap<PathBuf, PathBuf>,
+    ) -> Comparison {
+        let (first_order, second_order) = common_orders(
+            (first_order, second_order),
+            (first_contents, second_contents),
+            second_names,
+        );
+        if first_order == second_order {
+            Comparison::Equal
+        } else {
+            self.unequal(Diff::DirOrder(first_order, second_order), first, second)
+        }
+    }
+
+    /// Rename entries of the second directory whose names only differ by case from the first's to the first's names
+    ///
+    /// Returns the original names of the renamed entries by their new names.
+    #[cfg(unix)]
+    #[inline(never)]
+    fn match_case(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        first_contents: &HashSet<PathBuf>,
+        second_contents: &mut HashSet<PathBuf>,
+    ) -> Fallible<HashMap<PathBuf, PathBuf>> {
+        let fold = |name: &PathBuf| case_fold::fold(name.as_os_str(), self.turkic_case);
+        let by_folded_name = |entry: &EntryInfo, contents: &HashSet<PathBuf>| -> Fallible<HashMap<_, PathBuf>> {
+            let mut names = HashMap::new();
+            for name in contents {
+                if let Some(other) = names.insert(fold(name), name.clone()) {
+                    bail!(
+                        "\"{}\" and \"{}\" in \"{}\" only differ by case",
+                        other.display(),
+                        name.display(),
+                        entry.relative_path().display()
+                    );
+                }
+            }
+            Ok(names)
+        };
+        let first_names = by_folded_name(first, first_contents)?;
+        let second_names = by_folded_name(second, second_contents)?;
+
+        let mut renamed = HashMap::new();
+        for (folded, second_name) in second_names {
+            match first_names.get(&folded) {
+                Some(first_name) if *first_name != second_name => {
+                    second_contents.remove(&second_name);
+                    second_contents.insert(first_name.clone());
+                    renamed.insert(first_name.clone(), second_name);
+                }
+                _ => (),
+            }
+        }
+        Ok(renamed)
+    }
+
+    /// Compare the entries called `name`, unless the second is called differently in `second_names`
+    #[cfg(unix)]
+    fn child_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        name: &Path,
+        second_names: &HashMap<PathBuf, PathBuf>,
+    ) -> Fallible<Comparison> {
+        let second_name = second_names.get(name).map_or(name, PathBuf::as_path);
+        // Both sides are stat'ed concurrently, halving the latency of each entry on slow network filesystems
+        let (first_child, second_child) = rayon::join(
+            || self.retry(name, || first.child_entry(name)),
+            || self.retry(second_name, || second.child_entry(second_name)),
+        );
+        let (first_child, second_child) = (first_child?, second_child?);
+        if self.warn_mounts {
+            let first_mount = first_child.metadata.stat().st_dev != first.metadata.stat().st_dev;
+            let second_mount = second_child.metadata.stat().st_dev != second.metadata.stat().st_dev;
+            if first_mount != second_mount {
+                self.warn(&format!(
+                    "\"{}\" is a mount point only in \"{}\"",
+                    first_child.relative_path().display(),
+                    if first_mount { &self.first } else { &self.second }.display()
+                ));
+            }
+        }
+        self.entry_eq(&first_child, &second_child)
+    }
+
+    /// Whether the contents of files of `size` are compared, as in the range of `with_min_size` and `with_max_size`
+    #[cfg(unix)]
+    fn is_size_compared(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min_size| size >= min_size)
+            && self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+
+    /// Whether the contents of regular files are compared, as they are unless both are of a size out of the compared
+    /// range
+    #[cfg(unix)]
+    fn are_contents_compared(&self, first: &EntryInfo, second: &EntryInfo) -> bool {
+        first.metadata.len() != second.metadata.len() || self.is_size_compared(first.metadata.len())
+    }
+
+    #[cfg(unix)]
+    #[inline(never)]
+    fn file_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        if self.ignore_trailing_zeros && first.metadata.len() != second.metadata.len() {
+            return self.padded_eq(first, second);
+        }
+        compare_metadata_field!(self, first, second, st_size, Diff::Sizes);
+
+        let metadata_len = first.metadata.len();
+        if !self.is_size_compared(metadata_len) {
+            debug!(
+                "Skipping contents of \"{}\" of size {} out of the compared range",
+                first.relative_path().display(),
+                metadata_len
+            );
+            return Ok(Comparison::Equal);
+        }
+        let comp = self.contents_eq(first, second, (0, 0), metadata_len, None)?;
+        #[cfg(target_os = "linux")]
+        {
+            if self.compare_extents && comp == Comparison::Equal {
+                let comp = self.extents_eq(first, second)?;
+                if comp != Comparison::Equal {
+                    return Ok(comp);
+                }
+            }
+            if self.compare_sparse_map && comp == Comparison::Equal {
+                return self.sparse_map_eq(first, second);
+            }
+        }
+        Ok(comp)
+    }
+
+    /// Compare files of different sizes as if the shorter one were padded with zeroes to the longer one's size
+    #[cfg(unix)]
+    fn padded_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let (first_size, second_size) = (first.metadata.len(), second.metadata.len());
+        // The longer file is never read past its end, so it can be padded as well
+        let source1 = self.retry(&first.path, || ContentSource::open_zero_padded(first))?;
+        let source2 = self.retry(&second.path, || ContentSource::open_zero_padded(second))?;
+        let comp = self.sources_eq(
+            first,
+            second,
+            &source1,
+            &source2,
+            (0, 0),
+            max(first_size, second_size),
+            None,
+        )?;
+        Ok(if comp != Comparison::Equal || self.trailing_zeros_equal {
+            comp
+        } else {
+            self.unequal(Diff::TrailingPadding(first_size, second_size), first, second)
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn extents_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let first_extents = ioctl::extents(&first.open_nofollow()?)?;
+        let second_extents = ioctl::extents(&second.open_nofollow()?)?;
+        Ok(match (first_extents, second_extents) {
+            (Some(first_extents), Some(second_extents)) if first_extents != second_extents => self.unequal(
+                Diff::ExtentSharing(
+                    ioctl::shared_bytes(&first_extents, &second_extents),
+                    first.metadata.len(),
+                ),
+                first,
+                second,
+            ),
+            _ => Comparison::Equal,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sparse_map_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let size = first.metadata.len();
+        let first_regions = ioctl::data_regions(&first.open_nofollow()?, size)
+            .with_context(|e| format!("\"{}\": {}", first.path.display(), e))?;
+        let second_regions = ioctl::data_regions(&second.open_nofollow()?, size)
+            .with_context(|e| format!("\"{}\": {}", second.path.display(), e))?;
+        Ok(if first_regions != second_regions {
+            self.unequal(Diff::SparseLayout(first_regions, second_regions), first, second)
+        } else {
+            Comparison::Equal
+        })
+    }
+
+    fn contents_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        offsets: (u64, u64),
+        size: u64,
+        resume: Option<&ResumeLog>,
+    ) -> Fallible<Comparison> {
+        if size == 0 && !self.verify_openable {
+            return Ok(Comparison::Equal);
+        }
+
+        let source1 = self.retry(&first.path, || ContentSource::open(first))?;
+        let source2 = self.retry(&second.path, || ContentSource::open(second))?;
+        if size == 0 {
+            return Ok(Comparison::Equal);
+        }
+        #[cfg(unix)]
+        {
+            // Hard links to the same file have the same contents, inode numbers being unique only within a device
+            let (first_stat, second_stat) = (first.metadata.stat(), second.metadata.stat());
+            if offsets.0 == offsets.1
+                && (first_stat.st_dev, first_stat.st_ino) == (second_stat.st_dev, second_stat.st_ino)
+            {
+                debug!(
+                    "Skipping contents of {} and {} which are the same file",
+                    source1, source2
+                );
+                self.check_interrupted()?;
+                return Ok(Comparison::Equal);
+            }
+        }
+        self.sources_eq(first, second, &source1, &source2, offsets, size, resume)
+    }
+
+    /// Compare `size` bytes of each source from its offset, `first` and `second` being the entries reported on a
+    /// difference
+    ///
+    /// Chunks recorded in `resume` are skipped if the first source still holds what they were recorded with, and
+    /// chunks found equal are recorded in it.
+    #[allow(clippy::too_many_arguments)]
+    fn sources_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        source1: &ContentSource,
+        source2: &ContentSource,
+        (offset1, offset2): (u64, u64),
+        size: u64,
+        resume: Option<&ResumeLog>,
+    ) -> Fallible<Comparison> {
+        debug!(
+            "Comparing contents of {} and {} of size {} at offsets {} and {}",
+            source1, source2, size, offset1, offset2
+        );
+
+        let (chunks, chunk_count) = self.planned_chunks(size);
+        debug!("Comparing {} chunks", chunk_count);
+        // The difference found in a chunk, reported once the comparison is done with the other chunks
+        let compare_chunk = |chunk: Range<u64>| -> Fallible<Option<Diff>> {
+            self.check_interrupted()?;
+            if let Some(resume) = resume.filter(|resume| resume.is_recorded(chunk.start)) {
+                // Only the first source is read, to tell whether it still holds what was verified
+                let is_verified = with_chunk_buffers(|data1, _| -> Fallible<bool> {
+                    let chunked_data1 = &mut data1[..(chunk.end - chunk.start) as usize];
+                    self.retry(&first.path, || {
+                        source1.read_exact_at(chunked_data1, offset1 + chunk.start)
+                    })?;
+                    self.bytes_read.fetch_add(chunk.end - chunk.start, Ordering::Relaxed);
+                    Ok(resume.is_verified(chunk.start, chunked_data1))
+                })?;
+                if is_verified {
+                    debug!("Skipping range [{}:{}) verified before", chunk.start, chunk.end);
+                    return Ok(None);
+                }
+            }
+            debug!(
+                "Comparing range [{}:{}) of {} and {}",
+                chunk.start, chunk.end, source1, source2
+            );
+
+            with_chunk_buffers(|data1, data2| {
+                // Filled in whole by the reads, so nothing left in the buffers from other chunks is compared
+                let chunked_data1 = &mut data1[..(chunk.end - chunk.start) as usize];
+                let chunked_data2 = &mut data2[..(chunk.end - chunk.start) as usize];
+
+                self.retry(&first.path, || {
+                    source1.read_exact_at(chunked_data1, offset1 + chunk.start)
+                })?;
+                self.retry(&second.path, || {
+                    source2.read_exact_at(chunked_data2, offset2 + chunk.start)
+                })?;
+                let files = [source1, source2]
+                    .iter()
+                    .filter(|source| !matches!(source, ContentSource::Pattern(_)))
+                    .count() as u64;
+                self.bytes_read
+                    .fetch_add(files * (chunk.end - chunk.start), Ordering::Relaxed);
+
+                if self.bench_read_only {
+                    return Ok(None);
+                }
+                Ok(if chunked_data1 == chunked_data2 {
+                    if let Some(resume) = resume {
+                        resume.record(chunk.start, chunked_data1)?;
+                    }
+                    None
+                } else {
+                    let diff_index = get_diff_index(chunked_data1, chunked_data2);
+                    let local_lba = diff_index / BLOCK_SIZE * BLOCK_SIZE;
+                    // Blocks are numbered from the start of the sources, not from the offsets
+                    let byte_offset = |offset: u64| offset + chunk.start + diff_index as u64;
+                    let lba = |offset: u64| byte_offset(offset) / BLOCK_SIZE as u64;
+                    let (block1, block2) = self.diff_blocks(chunked_data1, chunked_data2, local_lba);
+                    Some(if offset1 == offset2 {
+                        Diff::Contents(lba(offset1), byte_offset(offset1), block1, block2)
+                    } else {
+                        Diff::RelocatedContents(lba(offset1), lba(offset2), block1, block2)
+                    })
+                })
+            })
+        };
+
+        let is_unequal = |r: &Fallible<Option<Diff>>| !matches!(r, Ok(None));
+        let found = if self.first_diff_only {
+            // In order and stopping at the first differing chunk, without reading ahead
+            (0..chunk_count)
+                .filter_map(|i| chunks.get(i))
+                .map(compare_chunk)
+                .find(is_unequal)
+        } else {
+            let results = (0..chunk_count)
+                .into_par_iter()
+                .filter_map(|i| chunks.get(i))
+                .map(compare_chunk);
+            #[cfg(unix)]
+            let found = if self.deterministic {
+                results.find_first(is_unequal)
+            } else {
+                results.find_any(is_unequal)
+            };
+            #[cfg(windows)]
+            let found = results.find_any(is_unequal);
+            found
+        };
+        let found = match found.transpose() {
+            Ok(found) => found,
+            #[cfg(unix)]
+            Err(e) if !self.strict_stable => return self.resized_eq(first, second, source1, source2, e),
+            Err(e) => return Err(e),
+        };
+        let diff = match found.flatten() {
+            Some(diff) => diff,
+            None => {
+                debug!("Compare of {} and {} finished", source1, source2);
+                #[cfg(unix)]
+                {
+                    if self.log_coverage {
+                        log_sampled_coverage(first, &chunks, chunk_count, size);
+                    }
+                }
+                #[cfg(all(unix, feature = "structured-log"))]
+                decision::log_contents(Some(&first.relative_path()), chunk_count);
+                #[cfg(all(windows, feature = "structured-log"))]
+                decision::log_contents(Some(&first.path), chunk_count);
+                return Ok(Comparison::Equal);
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            if self.ignore_changed {
+                for (info, source) in &[(first, source1), (second, source2)] {
+                    if self.changed_during_run(info, source)? {
+                        info!(
+                            "\"{}\" changed during run, not reporting its difference",
+                            info.relative_path().display()
+                        );
+                        let file_type = info.metadata.stat().st_mode & libc::S_IFMT;
+                        self.decided(
+                            Some(&info.relative_path()),
+                            Some(file_type),
+                            Decision::Changed,
+                            Some(&diff),
+                        );
+                        return Ok(Comparison::Equal);
+                    }
+                }
+            }
+        }
+
+        #[cfg(unix)]
+        let diff = match (self.hash_on_mismatch, source1, source2) {
+            (Some(algorithm), ContentSource::File(..), ContentSource::File(..)) => Diff::ContentHash(
+                algorithm,
+                algorithm.contents_hash(source1, offset1, size)?,
+                algorithm.contents_hash(source2, offset2, size)?,
+            ),
+            _ => diff,
+        };
+        Ok(self.unequal(diff, first, second))
+    }
+
+    /// The chunks comparing `size` bytes of contents would read, all but the first `count` of them being skipped,
+    /// as limited or sampled
+    #[cfg(unix)]
+    fn planned_chunks(&self, size: u64) -> (RangeChunks, u64) {
+        plan_chunks(size, self.full_compare_limit, self.sample_percent, self.sample_chunks)
+    }
+
+    #[cfg(windows)]
+    fn planned_chunks(&self, size: u64) -> (RangeChunks, u64) {
+        (
+            (0..size).leap_chunks(BUF_SIZE_U64, calc_leap(size, size, BUF_SIZE_U64)),
+            calc_chunk_count(size, BUF_SIZE_U64),
+        )
+    }
+
+    /// Bytes comparing `size` bytes of contents would read from each side
+    fn planned_bytes(&self, size: u64) -> u64 {
+        if size == 0 {
+            return 0;
+        }
+        let (chunks, chunk_count) = self.planned_chunks(size);
+        (0..chunk_count)
+            .filter_map(|i| chunks.get(i))
+            .map(|chunk| chunk.end - chunk.start)
+            .sum()
+    }
+
+    /// Whether a file was modified since the comparison started, or its size or modification time changed since it
+    /// was traversed
+    #[cfg(unix)]
+    fn changed_during_run(&self, info: &EntryInfo, source: &ContentSource) -> Fallible<bool> {
+        let file = match source {
+            ContentSource::File(_, file) | ContentSource::ZeroPadded(_, file, _) => file,
+            ContentSource::Pattern(_) => return Ok(false),
+        };
+        let metadata = file
+            .metadata()
+            .with_context(|e| format!("\"{}\": {}", info.path.display(), e))?;
+        let stat = info.metadata.stat();
+        let started = *self.started.lock().unwrap();
+        Ok(metadata.len() != info.metadata.len()
+            || (metadata.mtime(), metadata.mtime_nsec()) != (stat.st_mtime, stat.st_mtime_nsec)
+            || started.is_some_and(|started| metadata.modified().is_ok_and(|mtime| mtime >= started)))
+    }
+
+    /// Report files which changed size while being read as a size difference if their sizes now differ, failing with
+    /// the read's error `e` otherwise
+    #[cfg(unix)]
+    fn resized_eq(
+        &self,
+        first: &EntryInfo,
+        second: &EntryInfo,
+        source1: &ContentSource,
+        source2: &ContentSource,
+        e: failure::Error,
+    ) -> Fallible<Comparison> {
+        if let Some(FsCmpError::ChangedDuringComparison(_)) = e.downcast_ref() {
+            if let (Some(size1), Some(size2)) = (source1.current_size()?, source2.current_size()?) {
+                if size1 != size2 {
+                    debug!(
+                        "Sizes of {} and {} changed to {} and {}",
+                        source1, source2, size1, size2
+                    );
+                    return Ok(self.unequal(Diff::Sizes(size1 as i64, size2 as i64), first, second));
+                }
+            }
+        }
+        Err(e)
+    }
+
+    /// Compare `limit` bytes of both streams, reporting differences at `path`
+    #[cfg(unix)]
+    fn streams_eq(
+        &self,
+        first: &mut impl Read,
+        second: &mut impl Read,
+        limit: u64,
+        path: Option<PathBuf>,
+    ) -> Fallible<Comparison> {
+        debug!(
+            "Comparing streams \"{}\" and \"{}\"",
+            self.first.display(),
+            self.second.display()
+        );
+        // Only FIFOs are compared as streams in trees
+        const FIFO: Option<u32> = Some(libc::S_IFIFO);
+        // Kept off the stack, as FIFOs are compared as streams deep in the recursion comparing trees
+        with_chunk_buffers(|buffer1, buffer2| {
+            for chunk in (0..limit).chunks(BUF_SIZE_U64) {
+                self.check_interrupted()?;
+                let chunk_len = (chunk.end - chunk.start) as usize;
+                let len1 = read_full(first, &mut buffer1[..chunk_len]).context("first")?;
+                let len2 = read_full(second, &mut buffer2[..chunk_len]).context("second")?;
+                self.bytes_read.fetch_add((len1 + len2) as u64, Ordering::Relaxed);
+                let len = min(len1, len2);
+                let (data1, data2) = (&buffer1[..len], &buffer2[..len]);
+
+                if data1 != data2 {
+                    let diff_index = get_diff_index(data1, data2);
+                    let local_lba = diff_index / BLOCK_SIZE * BLOCK_SIZE;
+                    let offset = chunk.start + diff_index as u64;
+                    let (block1, block2) = self.diff_blocks(data1, data2, local_lba);
+                    return Ok(self.report(
+                        Diff::Contents(offset / BLOCK_SIZE as u64, offset, block1, block2),
+                        path,
+                        FIFO,
+                    ));
+                }
+
+                if len1 != len2 {
+                    // One stream ended, read the rest of the other to report both sizes
+                    let (size1, size2) = (chunk.start + len1 as u64, chunk.start + len2 as u64);
+                    let (size1, size2) = if len1 > len2 {
+                        (
+                            size1 + io::copy(&mut first.take(limit - size1), &mut io::sink())?,
+                            size2,
+                        )
+                    } else {
+                        (
+                            size1,
+                            size2 + io::copy(&mut second.take(limit - size2), &mut io::sink())?,
+                        )
+                    };
+                    return Ok(self.report(Diff::Sizes(size1 as i64, size2 as i64), path, FIFO));
+                }
+
+                if len < chunk_len {
+                    break;
+                }
+            }
+            debug!("Compare of streams finished");
+            Ok(Comparison::Equal)
+        })
+    }
+
+    #[cfg(unix)]
+    #[inline(never)]
+    fn symlink_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        let first_target = first.parent.read_link(&first.path)?;
+        let second_target = second.parent.read_link(&second.path)?;
+        if first_target != second_target {
+            return Ok(self.unequal(Diff::LinkTarget(first_target, second_target), first, second));
+        }
+
+        Ok(Comparison::Equal)
+    }
+
+    #[cfg(unix)]
+    #[inline(never)]
+    fn block_device_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        self.char_device_eq(first, second)
+    }
+
+    #[cfg(unix)]
+    #[inline(never)]
+    fn char_device_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        compare_metadata_field!(self, first, second, st_rdev, Diff::DeviceTypes);
+
+        Ok(Comparison::Equal)
+    }
+
+    /// FIFOs only carry data while they're being written to, so beyond the metadata compared in `entry_eq` only
+    /// their device numbers, normally zero, are compared
+    #[cfg(unix)]
+    #[inline(never)]
+    fn fifo_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        compare_metadata_field!(self, first, second, st_rdev, Diff::DeviceTypes);
+        if let Some(limit) = self.read_fifos {
+            let open = |entry: &EntryInfo| -> Fallible<FifoReader> {
+                let file = entry
+                    .open_nofollow()
+                    .with_context(|e| format!("\"{}\": {}", entry.relative_path().display(), e))?;
+                Ok(FifoReader {
+                    file,
+                    timeout: self.fifo_timeout,
+                })
+            };
+            let (mut first_fifo, mut second_fifo) = (open(first)?, open(second)?);
+            let path = Some(first.relative_path());
+            return self
+                .streams_eq(&mut first_fifo, &mut second_fifo, limit, path)
+                .with_context(|e| format!("\"{}\": {}", first.relative_path().display(), e))
+                .map_err(Into::into);
+        }
+        if self.strict_special {
+            self.warn(&format!(
+                "Contents of FIFO \"{}\" are not compared",
+                first.relative_path().display()
+            ));
+        }
+        Ok(Comparison::Equal)
+    }
+
+    /// Sockets have no contents on the filesystem, so beyond the metadata compared in `entry_eq` only their device
+    /// numbers, normally zero, are compared
+    #[cfg(unix)]
+    #[inline(never)]
+    fn socket_eq(&self, first: &EntryInfo, second: &EntryInfo) -> Fallible<Comparison> {
+        compare_metadata_field!(self, first, second, st_rdev, Diff::DeviceTypes);
+        if self.strict_special {
+            self.warn(&format!(
+                "Socket \"{}\" is only compared by its metadata",
+                first.relative_path().display()
+            ));
+        }
+        Ok(Comparison::Equal)
+    }
+
+    /// The blocks of differing data starting at `start` along with their context, empty if only the outcome is
+    /// reported
+    fn diff_blocks(&self, data1: &[u8], data2: &[u8], start: usize) -> (Vec<u8>, Vec<u8>) {
+        if self.summary_only {
+            return (Vec::new(), Vec::new());
+        }
+        let context = self.context_blocks * BLOCK_SIZE;
+        let begin = start.saturating_sub(context);
+        let size = start - begin + BLOCK_SIZE + context;
+        (
+            data1.subslice(begin, size).to_vec(),
+            data2.subslice(begin, size).to_vec(),
+        )
+    }
+
+    fn warn(&self, message: &str) {
+        warn!(target: WARNING_LOG_TARGET, "{}", message);
+    }
+}
+
+/// The entries in both directories in the order each lists them, those of the second matched up to case to names
+/// in `second_names` going by the first's names
+#[cfg(unix)]
+fn common_orders(
+    (first_order, second_order): (Vec<PathBuf>, Vec<PathBuf>),
+    (first_contents, second_contents): (&HashSet<PathBuf>, &HashSet<PathBuf>),
+    second_names: &HashMap<PathBuf, PathBuf>,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let first_names: HashMap<_, _> = second_names
+        .iter()
+        .map(|(name, second_name)| (second_name, name))
+        .collect();
+    let is_common = |name: &PathBuf| first_contents.contains(name) && second_contents.contains(name);
+    let first_order = first_order.into_iter().filter(is_common).collect();
+    let second_order = second_order
+        .into_iter()
+        .map(|name| first_names.get(&name).map_or(name, |name| (*name).clone()))
+        .filter(is_common)
+        .collect();
+    (first_order, second_order)
+}
+
+/// Canonicalize the path of a compared root, naming it in errors and telling apart dangling symbolic links
+#[cfg(unix)]
+fn canonicalize_root(path: &Path) -> Fallible<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                if let Ok(target) = fs::read_link(path) {
+                    return Err(FsCmpError::BrokenSymlink(path.into(), target).into());
+                }
+            }
+            bail!("\"{}\": {}", path.display(), e)
+        }
+    }
+}
+
+/// Log the chunks read out of `size` bytes of an entry compared equal, if not all of them were
+#[cfg(unix)]
+fn log_sampled_coverage(entry: &EntryInfo, chunks: &RangeChunks, chunk_count: u64, size: u64) {
+    const MAX_LOGGED_RANGES: usize = 16;
+
+    let ranges = read_ranges(chunks, chunk_count);
+    let read: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+    if read >= size {
+        return;
+    }
+    let mut logged: Vec<_> = ranges
+        .iter()
+        .take(MAX_LOGGED_RANGES)
+        .map(|range| format!("[{}:{})", range.start, range.end))
+        .collect();
+    if ranges.len() > MAX_LOGGED_RANGES {
+        logged.push(format!("and {} more ranges", ranges.len() - MAX_LOGGED_RANGES));
+    }
+    info!(
+        target: EQUAL_LOG_TARGET,
+        "\"{}\" is equal where sampled, {:.2}% read ({} of {} bytes) at {}",
+        entry.relative_path().display(),
+        read as f64 * 100.0 / size as f64,
+        read,
+        size,
+        logged.join(", ")
+    );
+}
+
+/// The ranges covered by the first `chunk_count` chunks, adjacent chunks being merged
+#[cfg(unix)]
+fn read_ranges(chunks: &RangeChunks, chunk_count: u64) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = vec![];
+    for chunk in (0..chunk_count).filter_map(|i| chunks.get(i)) {
+        match ranges.last_mut() {
+            Some(last) if last.end == chunk.start => last.end = chunk.end,
+            _ => ranges.push(chunk),
+        }
+    }
+    ranges
+}
+
+#[cfg(unix)]
+fn open_file(info: &EntryInfo) -> nix::Result<File> {
+    unsafe {
+        Ok(File::from_raw_fd(fcntl::openat(
+            info.parent.as_raw_fd(),
+            &info.path,
+            #[cfg(not(test))]
+            fcntl::OFlag::O_DIRECT,
+            #[cfg(test)]
+            fcntl::OFlag::empty(),
+            Mode::empty(),
+        )?))
+    }
+}
+
+#[cfg(windows)]
+fn open_file(info: &EntryInfo) -> std::io::Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .custom_flags(winapi::um::winbase::FILE_FLAG_NO_BUFFERING)
+        .open(&info.path)?)
+}
+
+/// One side of a content comparison
+enum ContentSource<'a> {
+    File(&'a EntryInfo, File),
+    /// A file read as if it were followed by zeroes past its size
+    #[cfg(unix)]
+    ZeroPadded(&'a EntryInfo, File, u64),
+    /// Every byte has the same value, e.g. the zeroes of an erased device
+    Pattern(u8),
+}
+
+impl<'a> ContentSource<'a> {
+    fn open(info: &'a EntryInfo) -> Fallible<Self> {
+        let file = open_file(info).with_context(|e| format!("\"{}\": {}", info.path.display(), e))?;
+        Ok(ContentSource::File(info, file))
+    }
+
+    #[cfg(unix)]
+    fn open_zero_padded(info: &'a EntryInfo) -> Fallible<Self> {
+        let file = open_file(info).with_context(|e| format!("\"{}\": {}", info.path.display(), e))?;
+        Ok(ContentSource::ZeroPadded(info, file, info.metadata.len()))
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Fallible<()> {
+        match self {
+            ContentSource::File(info, file) => {
+                #[cfg(unix)]
+                let res = read_full_at(file, buf, offset);
+                #[cfg(windows)]
+                let res = file.seek_read(buf, offset);
+                let len = res.with_context(|e| format!("\"{}\": {}", info.path.display(), e))?;
+                check_read(info, len, buf.len(), offset)?;
+            }
+            #[cfg(unix)]
+            ContentSource::ZeroPadded(info, file, size) => {
+                let available = min(size.saturating_sub(offset), buf.len() as u64) as usize;
+                let len = read_full_at(file, &mut buf[..available], offset)
+                    .with_context(|e| format!("\"{}\": {}", info.path.display(), e))?;
+                check_read(info, len, available, offset)?;
+                buf[available..].fill(0);
+            }
+            ContentSource::Pattern(pattern) => buf.fill(*pattern),
+        }
+        Ok(())
+    }
+
+    /// The size of an open file now, rather than when it was first queried
+    #[cfg(unix)]
+    fn current_size(&self) -> io::Result<Option<u64>> {
+        match self {
+            ContentSource::File(_, file) | ContentSource::ZeroPadded(_, file, _) => {
+                Ok(Some(file.metadata()?.len()))
+            }
+            ContentSource::Pattern(_) => Ok(None),
+        }
+    }
+}
+
+/// Fail if `len` bytes were read out of `expected` at `offset`, regular files which end sooner than they used to
+/// having changed
+#[cfg_attr(windows, allow(unused_variables))]
+fn check_read(info: &EntryInfo, len: usize, expected: usize, offset: u64) -> Fallible<()> {
+    if len == expected {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        let is_file = info.metadata.stat().st_mode & libc::S_IFMT == libc::S_IFREG;
+        if is_file && offset + expected as u64 <= info.metadata.len() {
+            return Err(FsCmpError::ChangedDuringComparison(info.path.clone()).into());
+        }
+    }
+    let e = io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer");
+    bail!("\"{}\": {}", info.path.display(), e)
+}
+
+/// Fill `buf` from `offset` unless the end of `file` is reached first, returning the number of bytes read
+///
+/// Files opened with `O_DIRECT` can only be read in whole blocks, so the end of `buf` past its last whole block is
+/// read through a block sized buffer.
+#[cfg(unix)]
+fn read_full_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let aligned = buf.len() / BLOCK_SIZE * BLOCK_SIZE;
+    let mut len = 0;
+    while len < aligned {
+        match file.read_at(&mut buf[len..aligned], offset + len as u64) {
+            Ok(0) => return Ok(len),
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if aligned < buf.len() {
+        let mut block = BlockBuffer([0; BLOCK_SIZE]);
+        let tail = min(
+            read_full_at(file, &mut block.0, offset + aligned as u64)?,
+            buf.len() - aligned,
+        );
+        buf[aligned..aligned + tail].copy_from_slice(&block.0[..tail]);
+        len += tail;
+    }
+    Ok(len)
+}
+
+impl<'a> fmt::Display for ContentSource<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContentSource::File(info, _) => write!(f, "\"{}\"", info.path.display()),
+            #[cfg(unix)]
+            ContentSource::ZeroPadded(info, _, _) => write!(f, "\"{}\" padded with zeroes", info.path.display()),
+            ContentSource::Pattern(pattern) => write!(f, "pattern {:#04x}", pattern),
+        }
+    }
+}
+
+/// A matcher of any of the gitignore-style `globs`, if there are any, failing if one isn't a valid glob
+#[cfg(unix)]
+fn globs_override(globs: &[String]) -> Fallible<Option<Override>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = OverrideBuilder::new("");
+    for glob in globs {
+        builder.add(glob)?;
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[cfg(unix)]
+fn entry_get<'a, K, V>(entry: &'a hash_map::Entry<K, V>) -> Option<&'a V> {
+    match entry {
+        hash_map::Entry::Vacant(_) => None,
+        hash_map::Entry::Occupied(ref oe) => Some(oe.get()),
+    }
+}
+
+#[cfg(unix)]
+fn first_unequal(a: Comparison, b: Comparison) -> Comparison {
+    if a == Comparison::Equal {
+        b
+    } else {
+        a
+    }
+}
+
+/// A FIFO opened without blocking, whose reads wait for data up to a timeout
+///
+/// Writers which haven't opened the FIFO yet are waited for too, as Linux only reports a hangup once a writer has
+/// come and gone.
+#[cfg(unix)]
+struct FifoReader {
+    file: File,
+    timeout: Duration,
+}
+
+#[cfg(unix)]
+impl Read for FifoReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut fds = [PollFd::new(self.file.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, min(self.timeout.as_millis(), i32::MAX as u128) as i32) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("No data in FIFO for {:?}", self.timeout),
+                    ))
+                }
+                Ok(_) => (),
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => return Err(io::Error::other(e)),
+            }
+            match self.file.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Fill `buf` unless the end of `reader` is reached first, returning the number of bytes read
+#[cfg(unix)]
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn get_diff_index(first: &[u8], second: &[u8]) -> usize {
+    for (i, (x, y)) in first.iter().zip(second.iter()).enumerate() {
         if x != y {
             return i;
         }
     }
-    panic!();
-}
+    panic!();
+}
+
+/// The chunks of `size` bytes of contents to read, all but the first `count` of them being skipped, as limited by
+/// the full compare limit and sampled by percentage or by the number of chunks
+#[cfg(unix)]
+fn plan_chunks(
+    size: u64,
+    full_compare_limit: Option<u64>,
+    sample_percent: Option<f64>,
+    sample_chunks: Option<u64>,
+) -> (RangeChunks, u64) {
+    let limit = calc_limit(size, full_compare_limit, sample_percent);
+    let (chunk_count, leap) = sample_chunks
+        .map(|sample_chunks| calc_sample_chunks(size, sample_chunks, BUF_SIZE_U64))
+        .unwrap_or((
+            calc_chunk_count(limit, BUF_SIZE_U64),
+            calc_leap(size, limit, BUF_SIZE_U64),
+        ));
+    ((0..size).leap_chunks(BUF_SIZE_U64, leap), chunk_count)
+}
+
+/// Returns the bytes to fully compare of a file of `size`, the least of the absolute and the relative limits
+#[cfg(unix)]
+fn calc_limit(size: u64, full_compare_limit: Option<u64>, sample_percent: Option<f64>) -> u64 {
+    let percent_limit = sample_percent.map(|percent| (size as f64 * percent / 100.0).ceil() as u64);
+    [full_compare_limit, percent_limit]
+        .iter()
+        .flatten()
+        .fold(size, |limit, other| min(limit, *other))
+}
+
+fn calc_chunk_count(limit: u64, chunk_size: u64) -> u64 {
+    // Rounded up, so that the last partial chunk of a fully compared file isn't skipped
+    max(limit.div_ceil(chunk_size), 1)
+}
+
+/// Returns the chunk count and leap needed to spread `sample_chunks` chunks evenly, reading each chunk at most once
+#[cfg(unix)]
+fn calc_sample_chunks(size: u64, sample_chunks: u64, chunk_size: u64) -> (u64, u64) {
+    let chunk_count = sample_chunks.clamp(1, max(size / chunk_size, 1));
+    (chunk_count, align_leap(size / chunk_count, chunk_size))
+}
+
+fn calc_leap(size: u64, limit: u64, chunk_size: u64) -> u64 {
+    if limit < chunk_size {
+        limit
+    } else {
+        align_leap(size / limit.div_ceil(chunk_size), chunk_size)
+    }
+}
+
+/// Round a leap down to whole chunks, at least one, as contents opened with `O_DIRECT` can only be read at offsets
+/// aligned to their block size
+fn align_leap(leap: u64, chunk_size: u64) -> u64 {
+    max(leap / chunk_size, 1) * chunk_size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use nix::mount::{mount, umount, MsFlags};
+    use std::fs;
+    use std::io;
+    use std::io::prelude::*;
+    #[cfg(unix)]
+    use std::mem;
+    #[cfg(unix)]
+    use std::os::unix;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_calc_leap() {
+        assert_eq!(calc_leap(100, 50, 2), 4);
+        assert_eq!(calc_leap(50, 50, 2), 2);
+        assert_eq!(calc_leap(150, 30, 2), 10);
+        assert_eq!(calc_leap(25, 50, 2), 2);
+        assert_eq!(calc_leap(25, 1, 2), 1);
+        assert_eq!(calc_leap(2_000_000_000, 2_000_000_000, BUF_SIZE_U64), BUF_SIZE_U64);
+        assert_eq!(calc_leap(160, 30, 4), 20);
+        assert_eq!(calc_leap(150, 30, 4), 16);
+    }
+
+    #[test]
+    fn test_calc_chunk_count() {
+        assert_eq!(calc_chunk_count(1, 2), 1);
+        assert_eq!(calc_chunk_count(50, 2), 25);
+        assert_eq!(calc_chunk_count(20, 2), 10);
+        assert_eq!(calc_chunk_count(21, 2), 11);
+        assert_eq!(calc_chunk_count(BUF_SIZE_U64 + 1, BUF_SIZE_U64), 2);
+    }
+
+    #[test]
+    fn test_chunks_cover_limit() {
+        for size in 1..60 {
+            for limit in 1..=size {
+                for chunk_size in 1..20 {
+                    let chunk_count = calc_chunk_count(limit, chunk_size);
+                    let leap = calc_leap(size, limit, chunk_size);
+                    assert!(limit < chunk_size || leap.is_multiple_of(chunk_size));
+                    let chunks = (0..size).leap_chunks(chunk_size, leap);
+                    assert!(chunks.len() >= chunk_count);
+
+                    let mut covered = vec![false; size as usize];
+                    for chunk in chunks.take(chunk_count as usize) {
+                        assert!(chunk.end <= size);
+                        for i in chunk {
+                            covered[i as usize] = true;
+                        }
+                    }
+                    let covered = covered.into_iter().filter(|c| *c).count() as u64;
+                    assert!(
+                        covered >= limit,
+                        "size {} limit {} chunk size {} covered {}",
+                        size,
+                        limit,
+                        chunk_size,
+                        covered
+                    );
+                    if limit == size {
+                        assert_eq!(covered, size);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_calc_limit() {
+        assert_eq!(calc_limit(1000, None, None), 1000);
+        assert_eq!(calc_limit(1000, None, Some(10.0)), 100);
+        assert_eq!(calc_limit(1000, None, Some(100.0)), 1000);
+        assert_eq!(calc_limit(1000, None, Some(0.05)), 1);
+        assert_eq!(calc_limit(1000, Some(50), Some(10.0)), 50);
+        assert_eq!(calc_limit(1000, Some(500), Some(10.0)), 100);
+        assert_eq!(calc_limit(1000, Some(5000), None), 1000);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_calc_sample_chunks() {
+        assert_eq!(calc_sample_chunks(100, 5, 2), (5, 20));
+        assert_eq!(calc_sample_chunks(100, 50, 2), (50, 2));
+        assert_eq!(calc_sample_chunks(100, 80, 2), (50, 2));
+        assert_eq!(calc_sample_chunks(101, 3, 2), (3, 32));
+        assert_eq!(calc_sample_chunks(1, 10, 2), (1, 2));
+        assert_eq!(calc_sample_chunks(100, 0, 2), (1, 100));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_ranges() {
+        let (chunks, chunk_count) = plan_chunks(10 * BUF_SIZE_U64, None, None, None);
+        assert_eq!(read_ranges(&chunks, chunk_count), vec![0..10 * BUF_SIZE_U64]);
+
+        let (chunks, chunk_count) = plan_chunks(10 * BUF_SIZE_U64 + 1, None, None, Some(2));
+        assert_eq!(
+            read_ranges(&chunks, chunk_count),
+            vec![0..BUF_SIZE_U64, 5 * BUF_SIZE_U64..6 * BUF_SIZE_U64]
+        );
+
+        let (chunks, chunk_count) = plan_chunks(10 * BUF_SIZE_U64, Some(4 * BUF_SIZE_U64), None, None);
+        assert_eq!(
+            read_ranges(&chunks, chunk_count),
+            vec![
+                0..BUF_SIZE_U64,
+                2 * BUF_SIZE_U64..3 * BUF_SIZE_U64,
+                4 * BUF_SIZE_U64..5 * BUF_SIZE_U64,
+                6 * BUF_SIZE_U64..7 * BUF_SIZE_U64
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    fn mknod(path: PathBuf, mode: libc::mode_t, dev: libc::dev_t) -> io::Result<()> {
+        use std::ffi;
+        use std::os::unix::ffi::OsStringExt;
+
+        let path = ffi::CString::new(path.into_os_string().into_vec())?;
+        if unsafe { libc::mknod(path.as_ptr(), mode | 0o644, dev) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(unix)]
+    fn generate_tree() -> Fallible<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        for dir in &[dir.path(), &dir.path().join("directory")] {
+            fs::create_dir(dir.join("directory"))?;
+            File::create(dir.join("regular_file"))?;
+            unix::fs::symlink("symlink_target", dir.join("symlink"))?;
+            for (name, mode) in &[
+                ("block_device", libc::S_IFBLK),
+                ("char_device", libc::S_IFCHR),
+                ("fifo", libc::S_IFIFO),
+                ("socket", libc::S_IFSOCK),
+            ] {
+                match mknod(dir.join(name), *mode, 0) {
+                    // Creating devices needs privileges, trees generated without them lack those
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+                    result => result?,
+                }
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Entries of a tree including its root, as `generate_tree` creates block devices only when privileged
+    #[cfg(unix)]
+    fn tree_entries(path: &Path) -> u64 {
+        walkdir::WalkDir::new(path).into_iter().count() as u64
+    }
+
+    #[cfg(windows)]
+    fn generate_tree() -> Fallible<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        File::create(dir.path().join("regular_file"))?;
+        Ok(dir)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_simple() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir1.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        let dir2 = generate_tree()?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        File::create(dir2.path().join("new_regular_file"))?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::DirContents(..),
+            ..
+        } = fscmp.dirs()?
+        {
+        } else {
+            panic!("New file not detected");
+        }
+        fs::remove_file(dir2.path().join("new_regular_file"))?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_progress() -> Fallible<()> {
+        let dir = generate_tree()?;
+        fs::write(dir.path().join("regular_file"), [1; 512])?;
+        let fscmp = FSCmp::new(dir.path().into(), dir.path().into(), None, HashSet::new());
+        // Both sides of the regular file are read
+        assert_eq!(fscmp.tree_totals(), (Some(tree_entries(dir.path())), Some(1024)));
+        let fscmp = fscmp.with_subpath(Some("directory".into()));
+        assert_eq!(
+            fscmp.tree_totals(),
+            (Some(tree_entries(&dir.path().join("directory"))), Some(0))
+        );
+
+        // Mostly small files, measured in entries
+        let progress = Progress {
+            bytes_read: 1024,
+            bytes_total: Some(4096),
+            entries_compared: 5,
+            entries_total: Some(20),
+            elapsed: Duration::from_secs(10),
+        };
+        assert_eq!(progress.fraction(), Some(0.25));
+        assert_eq!(progress.eta(), Some(Duration::from_secs(30)));
+        assert_eq!(
+            progress.to_string(),
+            "5 of 20 entries compared, 1024 bytes read (25%), ETA 0:00:30"
+        );
+        // Mostly contents, measured in bytes
+        let progress = Progress {
+            bytes_total: Some(1 << 30),
+            bytes_read: 1 << 29,
+            ..progress
+        };
+        assert_eq!(progress.fraction(), Some(0.5));
+        let progress = Progress {
+            entries_total: None,
+            entries_compared: 0,
+            bytes_total: None,
+            ..progress
+        };
+        assert_eq!(progress.eta(), None);
+        assert_eq!(progress.to_string(), "536870912 bytes read");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_count_entries() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        // Different contents, same number of entries
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let count_entries = || FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(count_entries().count_entries()?, Comparison::Equal);
+
+        File::create(dir2.path().join("directory").join("directory").join("new_file"))?;
+        assert_eq!(
+            count_entries().count_entries()?,
+            Comparison::Unequal {
+                diff: Diff::EntryCounts(1, 2),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("directory/directory".into()),
+            }
+        );
+        // Entries directly in the root also differ
+        fs::remove_file(dir2.path().join("fifo"))?;
+        File::create(dir2.path().join("directory").join("new_file"))?;
+        let entries = tree_entries(dir1.path());
+        assert_eq!(
+            count_entries().count_entries()?,
+            Comparison::Unequal {
+                diff: Diff::EntryCounts(entries, entries + 1),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some(".".into()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_estimate() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        for path in &[&first, &second] {
+            File::create(path)?.set_len(BUF_SIZE_U64 * 10)?;
+        }
+        let fscmp = || FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+        let size = BUF_SIZE_U64 * 10;
+        assert_eq!(fscmp().estimate_contents(size)?, 2 * size);
+        assert_eq!(
+            fscmp().with_sample_chunks(Some(3)).estimate_contents(size)?,
+            6 * BUF_SIZE_U64
+        );
+        let fscmp = FSCmp::new(first.clone(), second.clone(), Some(BUF_SIZE_U64 * 2), HashSet::new());
+        assert_eq!(fscmp.estimate_contents(size)?, 4 * BUF_SIZE_U64);
+        let (_, stats) = fscmp.contents_with_stats(size)?;
+        assert_eq!(stats.bytes_read, 4 * BUF_SIZE_U64);
+
+        let fscmp = FSCmp::new(
+            dir.path().into(),
+            dir.path().into(),
+            Some(BUF_SIZE_U64 * 2),
+            HashSet::new(),
+        );
+        assert_eq!(fscmp.estimate_dirs(), 8 * BUF_SIZE_U64);
+        assert_eq!(fscmp.with_max_size(Some(BUF_SIZE_U64)).estimate_dirs(), 0);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_manifest() -> Fallible<()> {
+        let dir = generate_tree()?;
+        fs::write(dir.path().join("regular_file"), vec![1; BUF_SIZE * 4])?;
+        let manifest_dir = tempfile::tempdir()?;
+        let manifest = manifest_dir.path().join("manifest");
+        let fscmp = || FSCmp::new(dir.path().into(), manifest.clone(), None, HashSet::new());
+        fscmp().with_sample_chunks(Some(2)).write_manifest(&manifest)?;
+        let fscmp_verify = fscmp();
+        assert_eq!(fscmp_verify.verify_manifest()?, Comparison::Equal);
+        // Sampled the way the manifest was written
+        assert_eq!(fscmp_verify.bytes_read.load(Ordering::Relaxed), 2 * BUF_SIZE_U64);
+
+        // Outside of the sampled chunks
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(dir.path().join("regular_file"))?;
+        file.write_all_at(&[2], BUF_SIZE_U64 + 1)?;
+        assert_eq!(fscmp().verify_manifest()?, Comparison::Equal);
+        file.write_all_at(&[2], 1)?;
+        if let Comparison::Unequal {
+            diff: Diff::ContentHash(HashAlgorithm::Sha256, ..),
+            path: Some(path),
+            ..
+        } = fscmp().verify_manifest()?
+        {
+            assert_eq!(path, Path::new("regular_file"));
+        } else {
+            panic!("Contents difference not detected");
+        }
+
+        File::create(dir.path().join("directory/new_regular_file"))?;
+        fs::remove_file(dir.path().join("symlink"))?;
+        unix::fs::symlink("other_target", dir.path().join("symlink"))?;
+        let diffs = Arc::new(Mutex::new(vec![]));
+        let handler_diffs = diffs.clone();
+        let comp = fscmp()
+            .with_report_all(true)
+            .with_diff_handler(move |comp| {
+                if let Comparison::Unequal {
+                    diff, path: Some(path), ..
+                } = comp
+                {
+                    handler_diffs.lock().unwrap().push((path.clone(), diff.clone()));
+                }
+            })
+            .verify_manifest()?;
+        assert!(matches!(comp, Comparison::Unequal { .. }));
+        let diffs = diffs.lock().unwrap();
+        let paths: Vec<_> = diffs.iter().map(|(path, _)| path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["directory", "regular_file", "symlink"]);
+        assert_eq!(
+            diffs[2].1,
+            Diff::LinkTarget("other_target".into(), "symlink_target".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_check_sums() -> Fallible<()> {
+        use sha2::{Digest, Sha256};
+
+        let dir = generate_tree()?;
+        fs::write(dir.path().join("regular_file"), b"contents")?;
+        let hex = |data: &[u8]| -> String { Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect() };
+        let sums_dir = tempfile::tempdir()?;
+        let sums = sums_dir.path().join("sums");
+        fs::write(
+            &sums,
+            format!(
+                "{}  regular_file\n{} *./directory/regular_file\n",
+                hex(b"contents"),
+                hex(b"")
+            ),
+        )?;
+        let fscmp = || FSCmp::new(dir.path().into(), sums.clone(), None, HashSet::new()).with_report_all(true);
+        assert_eq!(fscmp().check_sums(true)?, Comparison::Equal);
+        File::create(dir.path().join("new_regular_file"))?;
+        assert_eq!(fscmp().check_sums(false)?, Comparison::Equal);
+        assert_ne!(fscmp().check_sums(true)?, Comparison::Equal);
+
+        fs::write(dir.path().join("regular_file"), b"changed")?;
+        fs::remove_file(dir.path().join("directory/regular_file"))?;
+        let diffs = Arc::new(Mutex::new(vec![]));
+        let handler_diffs = diffs.clone();
+        fscmp()
+            .with_diff_handler(move |comp| {
+                if let Comparison::Unequal {
+                    diff, path: Some(path), ..
+                } = comp
+                {
+                    handler_diffs.lock().unwrap().push((path.clone(), diff.clone()));
+                }
+            })
+            .check_sums(false)?;
+        assert_eq!(
+            *diffs.lock().unwrap(),
+            vec![
+                (
+                    "directory".into(),
+                    Diff::DirContents(HashSet::new(), vec!["regular_file".into()].into_iter().collect())
+                ),
+                (
+                    "regular_file".into(),
+                    Diff::ContentHash(
+                        HashAlgorithm::Sha256,
+                        Sha256::digest(b"changed").to_vec(),
+                        Sha256::digest(b"contents").to_vec()
+                    )
+                ),
+            ]
+        );
+
+        fs::write(&sums, "not a checksum list\n")?;
+        assert!(fscmp().check_sums(false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_include_globs() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+        File::create(dir2.path().join("directory").join("new_file"))?;
+        fs::remove_file(dir2.path().join("fifo"))?;
+
+        let include_globs = |globs: &[&str]| -> Fallible<FSCmp> {
+            let globs: Vec<String> = globs.iter().map(|glob| glob.to_string()).collect();
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_include_globs(&globs)
+        };
+        assert_eq!(include_globs(&["symlink", "*_device"])?.dirs()?, Comparison::Equal);
+        assert_eq!(
+            include_globs(&["regular_file"])?.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Sizes(0, 1),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("directory/regular_file".into()),
+            }
+        );
+        assert_eq!(include_globs(&["/regular_file"])?.dirs()?, Comparison::Equal);
+        // Excludes win over includes
+        let fscmp = include_globs(&["regular_file"])?
+            .with_ignored_paths(vec![PathBuf::from("./directory/regular_file")].into_iter().collect());
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        assert!(include_globs(&["["]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_allowed_diffs() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+        File::create(dir2.path().join("new_file"))?;
+        let allowed_diffs = |paths: &[&str]| {
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+                .with_allowed_diffs(paths.iter().map(PathBuf::from).collect())
+        };
+
+        assert_eq!(
+            allowed_diffs(&["./directory/regular_file"]).dirs()?,
+            Comparison::Unequal {
+                diff: Diff::DirContents(
+                    fs::read_dir(dir1.path())?
+                        .map(|e| Ok(e?.file_name().into()))
+                        .collect::<io::Result<_>>()?,
+                    fs::read_dir(dir2.path())?
+                        .map(|e| Ok(e?.file_name().into()))
+                        .collect::<io::Result<_>>()?,
+                ),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some(".".into()),
+            }
+        );
+        // Other differences are still reported along with allowed ones
+        assert_ne!(allowed_diffs(&["./"]).with_report_all(true).dirs()?, Comparison::Equal);
+        assert_eq!(
+            allowed_diffs(&[".", "directory/regular_file"]).dirs()?,
+            Comparison::Equal
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_top_divergence() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory/regular_file"), b"a")?;
+        fs::remove_file(dir2.path().join("directory/symlink"))?;
+        File::create(dir2.path().join("directory/directory/new_file"))?;
+        File::create(dir2.path().join("new_file"))?;
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        let (comp, counts) = fscmp.top_divergence()?;
+        assert_ne!(comp, Comparison::Equal);
+        assert_eq!(
+            counts,
+            vec![
+                (PathBuf::from("directory"), 3),
+                (PathBuf::from("new_file"), 1),
+                (PathBuf::from("regular_file"), 1),
+            ]
+        );
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir1.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.top_divergence()?, (Comparison::Equal, vec![]));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_entry_handler() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+        let decisions = Arc::new(Mutex::new(HashMap::new()));
+        let handler_decisions = decisions.clone();
+        let comp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignored_paths(vec![PathBuf::from("./fifo")].into_iter().collect())
+            .with_report_all(true)
+            .with_entry_handler(move |path, entry_decision| {
+                handler_decisions.lock().unwrap().insert(
+                    path.to_path_buf(),
+                    (entry_decision.decision, entry_decision.diff.cloned()),
+                );
+            })
+            .dirs()?;
+        assert_ne!(comp, Comparison::Equal);
+
+        let decisions = decisions.lock().unwrap();
+        assert_eq!(
+            decisions[Path::new("directory/regular_file")],
+            (Decision::Unequal, Some(Diff::Sizes(0, 1)))
+        );
+        assert_eq!(decisions[Path::new("symlink")], (Decision::Equal, None));
+        assert_eq!(decisions[Path::new("fifo")], (Decision::Ignored, None));
+        Ok(())
+    }
+
+    /// Records of an audit log by path
+    #[cfg(unix)]
+    fn audit_records(audit_log: &Path) -> Fallible<HashMap<String, serde_json::Value>> {
+        Ok(fs::read_to_string(audit_log)?
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                (record["path"].as_str().unwrap().to_string(), record)
+            })
+            .collect())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_audit_log() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+        let audit_dir = tempfile::tempdir()?;
+        let audit_log = audit_dir.path().join("audit");
+        let comp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignored_paths(vec![PathBuf::from("./fifo")].into_iter().collect())
+            .with_report_all(true)
+            .with_audit_log(Some(&audit_log))?
+            .dirs()?;
+        assert_ne!(comp, Comparison::Equal);
+
+        let records = audit_records(&audit_log)?;
+        assert_eq!(
+            records["directory/regular_file"],
+            serde_json::json!({"path": "directory/regular_file", "type": libc::S_IFREG, "decision": "unequal",
+                "diff": "Sizes"})
+        );
+        assert_eq!(records["fifo"]["decision"], "ignored");
+        assert!(records["fifo"]["reason"].is_string());
+        // Everything but the ignored FIFO was compared
+        for entry in walkdir::WalkDir::new(dir1.path()).min_depth(1) {
+            let path = entry?.path().strip_prefix(dir1.path())?.to_string_lossy().into_owned();
+            assert!(records.contains_key(&path), "\"{}\" wasn't recorded", path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_audit_log_check_sums() -> Fallible<()> {
+        use sha2::{Digest, Sha256};
+
+        let dir = generate_tree()?;
+        fs::write(dir.path().join("regular_file"), b"contents")?;
+        let other_dir = tempfile::tempdir()?;
+        let (sums, audit_log) = (other_dir.path().join("sums"), other_dir.path().join("audit"));
+        let hex: String = Sha256::digest(b"contents")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        fs::write(&sums, format!("{}  regular_file\n", hex))?;
+        let comp = FSCmp::new(dir.path().into(), sums, None, HashSet::new())
+            .with_audit_log(Some(&audit_log))?
+            .check_sums(false)?;
+        assert_eq!(comp, Comparison::Equal);
+
+        let records = audit_records(&audit_log)?;
+        assert_eq!(
+            records["regular_file"],
+            serde_json::json!({"path": "regular_file", "type": libc::S_IFREG, "decision": "equal"})
+        );
+        assert_eq!(records["directory/regular_file"]["decision"], "skipped");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "tar"))]
+    fn test_audit_log_tar() -> Fallible<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = generate_tree()?;
+        let other_dir = tempfile::tempdir()?;
+        let (archive, audit_log) = (other_dir.path().join("archive.tar"), other_dir.path().join("audit"));
+        let status = std::process::Command::new("tar")
+            .arg("-C")
+            .arg(dir.path())
+            .arg("-cf")
+            .arg(&archive)
+            .arg(".")
+            .status()?;
+        assert!(status.success());
+        let comp = FSCmp::new(dir.path().into(), archive, None, HashSet::new())
+            .with_audit_log(Some(&audit_log))?
+            .dir_with_tar()?;
+        assert_eq!(comp, Comparison::Equal);
+
+        let records = audit_records(&audit_log)?;
+        assert_eq!(records["."]["decision"], "equal");
+        // Everything but the socket, which tar can't store, was compared
+        for entry in walkdir::WalkDir::new(dir.path()).min_depth(1) {
+            let entry = entry?;
+            if !entry.file_type().is_socket() {
+                let path = entry.path().strip_prefix(dir.path())?.to_string_lossy().into_owned();
+                assert_eq!(records[&path]["decision"], "equal", "\"{}\"", path);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_device_numbers() -> Fallible<()> {
+        let dir1 = tempfile::tempdir()?;
+        let dir2 = tempfile::tempdir()?;
+        for (dir, minor) in &[(&dir1, 3), (&dir2, 5)] {
+            match mknod(
+                dir.path().join("char_device"),
+                libc::S_IFCHR,
+                nix::sys::stat::makedev(1, *minor),
+            ) {
+                // Creating devices needs privileges
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+                result => result?,
+            }
+        }
+        let comp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).dirs()?;
+        assert!(
+            matches!(
+                comp,
+                Comparison::Unequal {
+                    diff: Diff::DeviceTypes(0x103, 0x105),
+                    ..
+                }
+            ),
+            "{}",
+            comp
+        );
+        let text = comp.to_string();
+        assert!(text.contains(": 259 (1:3)\n"), "{}", text);
+        assert!(text.ends_with(": 261 (1:5)"), "{}", text);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_labels() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_first_label("SRC".into())
+            .with_second_label("DST".into());
+        let comp = fscmp.dirs()?;
+        assert_eq!(
+            comp,
+            Comparison::Unequal {
+                diff: Diff::Sizes(0, 1),
+                first: "SRC".into(),
+                second: "DST".into(),
+                path: Some("regular_file".into()),
+            }
+        );
+        assert!(comp.to_string().contains("From \"SRC\": 0"), "{}", comp);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ignored_paths() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+
+        let ignored_paths = vec![PathBuf::from("regular_file")].into_iter().collect();
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignored_paths(ignored_paths);
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+
+        let ignored_paths = vec![PathBuf::from("./directory/regular_file")].into_iter().collect();
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignored_paths(ignored_paths);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ignore_files() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for dir in &[&dir1, &dir2] {
+            fs::write(dir.path().join("directory").join(IGNORE_FILE_NAME), b"*_file\n")?;
+        }
+        fs::write(dir2.path().join("directory/regular_file"), b"a")?;
+        File::create(dir2.path().join("directory/directory/new_regular_file"))?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_files(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        // Rules don't apply outside of the subtree of their directory
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_files(true);
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_missing_ok() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        File::create(dir1.path().join("directory").join("new_regular_file"))?;
+        fs::remove_file(dir2.path().join("fifo"))?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_missing_ok(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        // Entries present on both sides are still compared
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_missing_ok(true);
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_deterministic() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for name in &["b", "c", "a"] {
+            fs::write(dir1.path().join("directory").join(name), vec![0; BUF_SIZE * 4])?;
+            let mut contents = vec![0; BUF_SIZE * 4];
+            contents[BUF_SIZE * 2..].iter_mut().for_each(|b| *b = 1);
+            fs::write(dir2.path().join("directory").join(name), contents)?;
+        }
+        for _ in 0..10 {
+            let fscmp =
+                FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_deterministic(true);
+            if let Comparison::Unequal {
+                diff: Diff::Contents(lba, ..),
+                path: Some(path),
+                ..
+            } = fscmp.dirs()?
+            {
+                assert_eq!(path, Path::new("directory/a"));
+                assert_eq!(lba as usize, BUF_SIZE * 2 / BLOCK_SIZE);
+            } else {
+                panic!("Content should be unequal");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dirs_at() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let first = File::open(dir1.path())?;
+        let second = File::open(dir2.path())?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.dirs_at(first.as_raw_fd(), second.as_raw_fd())?, Comparison::Equal);
+
+        File::create(dir2.path().join("new_regular_file"))?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::DirContents(..),
+            ..
+        } = fscmp.dirs_at(first.as_raw_fd(), second.as_raw_fd())?
+        {
+        } else {
+            panic!("New file not detected");
+        }
+
+        // The descriptors are still usable by the caller
+        first.metadata()?;
+        second.metadata()?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_streams() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&first, vec![0; BUF_SIZE + 1000])?;
+        fs::write(&second, vec![0; BUF_SIZE + 1000])?;
+        let fscmp = FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+        assert_eq!(fscmp.streams(None)?, Comparison::Equal);
+
+        fs::write(&second, vec![0; BUF_SIZE * 3])?;
+        let fscmp = FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+        assert_eq!(
+            fscmp.streams(None)?,
+            Comparison::Unequal {
+                diff: Diff::Sizes(BUF_SIZE as i64 + 1000, BUF_SIZE as i64 * 3),
+                first: first.clone(),
+                second: second.clone(),
+                path: None,
+            }
+        );
+        assert_eq!(fscmp.streams(Some(BUF_SIZE as u64))?, Comparison::Equal);
+
+        let mut contents = vec![0; BUF_SIZE * 3];
+        contents[BUF_SIZE + 600] = 1;
+        fs::write(&second, contents)?;
+        let fscmp = FSCmp::new(first, second, None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.streams(None)?
+        {
+            assert_eq!(lba as usize, (BUF_SIZE + 600) / BLOCK_SIZE);
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_hex_width() {
+        let comp = Comparison::Unequal {
+            diff: Diff::Contents(0, 0, vec![0; BLOCK_SIZE], vec![1; BLOCK_SIZE]),
+            first: "first".into(),
+            second: "second".into(),
+            path: None,
+        };
+        let hex_lines = |text: String| text.lines().filter(|line| line.starts_with("00 ")).count();
+        assert_eq!(hex_lines(comp.to_string()), BLOCK_SIZE / 32);
+        let options = DisplayOptions::default().with_hex_width(16);
+        assert_eq!(hex_lines(comp.display(options).to_string()), BLOCK_SIZE / 16);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_types() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::remove_file(dir2.path().join("regular_file"))?;
+        unix::fs::symlink("symlink_target", dir2.path().join("regular_file"))?;
+
+        for content_only in &[false, true] {
+            let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+                .with_content_only(*content_only);
+            assert_eq!(
+                fscmp.dirs()?,
+                Comparison::Unequal {
+                    diff: Diff::Types(libc::S_IFREG, libc::S_IFLNK),
+                    first: dir1.path().into(),
+                    second: dir2.path().into(),
+                    path: Some("regular_file".into()),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_owner_by_name() -> Fallible<()> {
+        let owner_names = OwnerNames::default();
+        assert_eq!(owner_names.user(0).as_deref(), Some("root"));
+        assert_eq!(owner_names.group(0).as_deref(), Some("root"));
+        assert_eq!(owner_names.user(54321), None);
+
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        match unistd::chown(
+            &dir2.path().join("regular_file"),
+            Some(unistd::Uid::from_raw(54321)),
+            None,
+        ) {
+            // Giving files away needs privileges
+            Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => return Ok(()),
+            result => result?,
+        }
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_owner_by_name(true);
+        if let Comparison::Unequal {
+            diff: Diff::Uids(..), ..
+        } = fscmp.dirs()?
+        {
+        } else {
+            panic!("Unresolved owner should be compared by ID");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_mode_display() {
+        let display = |first: u32, second: u32| {
+            let comp = Comparison::Unequal {
+                diff: Diff::Modes(first, second),
+                first: "a".into(),
+                second: "b".into(),
+                path: None,
+            };
+            comp.to_string()
+        };
+        assert_eq!(
+            display(0o100644, 0o40755),
+            "Mismatch: File mode\nFrom \"a\": 0o100644 (-rw-r--r--)\nFrom \"b\": 0o40755 (drwxr-xr-x)"
+        );
+        // Special bits show in place of the execute bits, capitalized if those aren't set
+        assert_eq!(
+            display(0o104755, 0o102644),
+            "Mismatch: File mode\nFrom \"a\": 0o104755 (-rwsr-xr-x)\nFrom \"b\": 0o102644 (-rw-r-Sr--)"
+        );
+        assert_eq!(
+            display(0o41777, 0o41776),
+            "Mismatch: File mode\nFrom \"a\": 0o41777 (drwxrwxrwt)\nFrom \"b\": 0o41776 (drwxrwxrwT)"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_color_display() {
+        let comp = Comparison::Unequal {
+            diff: Diff::Uids(0, 1000),
+            first: "first".into(),
+            second: "second/tree".into(),
+            path: Some("file".into()),
+        };
+        assert_eq!(
+            comp.display(DisplayOptions::default().with_color(true)).to_string(),
+            "Mismatch in \"file\": UID\nFrom \"first\":       \x1b[1;31m0\x1b[0m\nFrom \"second/tree\": \x1b[1;32m1000\x1b[0m"
+        );
+        assert_eq!(
+            comp.to_string(),
+            "Mismatch in \"file\": UID\nFrom \"first\": 0\nFrom \"second/tree\": 1000"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dir_contents_display() {
+        let comp = Comparison::Unequal {
+            diff: Diff::DirContents(
+                vec!["a".into(), "b".into(), "c".into()].into_iter().collect(),
+                vec!["a".into(), "d".into()].into_iter().collect(),
+            ),
+            first: "first".into(),
+            second: "second".into(),
+            path: None,
+        };
+        let text = comp.to_string();
+        assert!(text.contains("\"first\" has 3 entries, \"second\" has 2\n"));
+        assert!(text.contains("Only in \"first\": {\n    \"b\",\n    \"c\",\n}"));
+        assert!(text.contains("Only in \"second\": {\n    \"d\",\n}"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_oneline() {
+        let oneline = |diff, path: Option<&str>| {
+            Comparison::Unequal {
+                diff,
+                first: "first".into(),
+                second: "second".into(),
+                path: path.map(PathBuf::from),
+            }
+            .oneline()
+            .to_string()
+        };
+        assert_eq!(
+            oneline(Diff::Modes(0o644, 0o600), Some("path/to/file")),
+            "MODE path/to/file 0o644 0o600"
+        );
+        assert_eq!(
+            oneline(Diff::Types(libc::S_IFREG, libc::S_IFLNK), Some("file")),
+            "TYPE file regular-file symbolic-link"
+        );
+        assert_eq!(
+            oneline(
+                Diff::Contents(7, 7 * BLOCK_SIZE as u64, vec![0; BLOCK_SIZE], vec![1; BLOCK_SIZE]),
+                None
+            ),
+            "CONTENT - 7 7 3584"
+        );
+        assert_eq!(
+            oneline(Diff::IoBlockSize(4096, 65536), Some("file")),
+            "BLKSIZE file 4096 65536"
+        );
+        assert_eq!(
+            oneline(
+                Diff::DirContents(
+                    vec!["a".into(), "b".into(), "c".into()].into_iter().collect(),
+                    vec!["a".into()].into_iter().collect(),
+                ),
+                Some(".")
+            ),
+            "ENTRIES . b,c -"
+        );
+        assert_eq!(Comparison::Equal.oneline().to_string(), "");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_tree_digest() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        let digest = fscmp.tree_digest(dir1.path())?;
+        assert_eq!(digest, fscmp.tree_digest(dir1.path())?);
+        assert_eq!(digest, fscmp.tree_digest(dir2.path())?);
+
+        fs::write(dir2.path().join("directory/regular_file"), b"a")?;
+        assert_ne!(digest, fscmp.tree_digest(dir2.path())?);
+        fs::write(dir2.path().join("directory/regular_file"), b"")?;
+        assert_eq!(digest, fscmp.tree_digest(dir2.path())?);
+
+        fs::set_permissions(dir2.path().join("fifo"), fs::Permissions::from_mode(0o600))?;
+        assert_ne!(digest, fscmp.tree_digest(dir2.path())?);
+        let fscmp = fscmp.with_content_only(true);
+        assert_eq!(fscmp.tree_digest(dir1.path())?, fscmp.tree_digest(dir2.path())?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_special_file_metadata() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::set_permissions(dir2.path().join("fifo"), fs::Permissions::from_mode(0o600))?;
+        match unistd::chown(
+            &dir2.path().join("directory").join("socket"),
+            Some(unistd::Uid::from_raw(54321)),
+            None,
+        ) {
+            // Giving files away needs privileges
+            Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => return Ok(()),
+            result => result?,
+        }
+        let unequal = |diff, path: &str| Comparison::Unequal {
+            diff,
+            first: dir1.path().into(),
+            second: dir2.path().into(),
+            path: Some(path.into()),
+        };
+        let fifo_mode = |dir: &tempfile::TempDir| -> Fallible<u32> {
+            Ok(fs::symlink_metadata(dir.path().join("fifo"))?.mode())
+        };
+        let fifo_modes = Diff::Modes(fifo_mode(&dir1)?, fifo_mode(&dir2)?);
+
+        let diffs = Arc::new(Mutex::new(Vec::new()));
+        let handler_diffs = diffs.clone();
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_report_all(true)
+            .with_diff_handler(move |comp| handler_diffs.lock().unwrap().push(comp.clone()));
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let mut diffs = diffs.lock().unwrap().clone();
+        diffs.sort_by_key(|comp| comp.to_string());
+        assert_eq!(
+            diffs,
+            [
+                unequal(Diff::Uids(unistd::getuid().as_raw(), 54321), "directory/socket"),
+                unequal(fifo_modes.clone(), "fifo")
+            ]
+        );
+        // As roots of the comparison, special files still have their metadata compared
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("fifo".into()));
+        assert_eq!(fscmp.dirs()?, unequal(fifo_modes, "fifo"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unsupported_type() -> Fallible<()> {
+        let dir = generate_tree()?;
+        let fscmp = FSCmp::new(dir.path().into(), dir.path().into(), None, HashSet::new());
+        let root = EntryInfo::dir(dir.path())?;
+        let entry = root.child_entry(Path::new("regular_file"))?;
+        // Solaris doors are one of the types which can't be compared
+        let err = fscmp.type_eq(0o150000, &entry, &entry).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FsCmpError::UnsupportedType("regular_file".into(), 0o150000))
+        );
+
+        let fscmp = fscmp.with_unknown_type_handler(|first, second| {
+            assert_eq!(first, second);
+            assert_eq!(first.file_name(), Some("regular_file".as_ref()));
+            Ok(Comparison::Equal)
+        });
+        assert_eq!(fscmp.type_eq(0o150000, &entry, &entry)?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hard_link_topology() -> Fallible<()> {
+        // Hard link counts match, but not which paths are linked together
+        let dir1 = tempfile::tempdir()?;
+        let dir2 = tempfile::tempdir()?;
+        for (dir, links) in &[(&dir1, [("a", "b"), ("c", "d")]), (&dir2, [("a", "c"), ("b", "d")])] {
+            for (name, link) in links {
+                File::create(dir.path().join(name))?;
+                fs::hard_link(dir.path().join(name), dir.path().join(link))?;
+            }
+        }
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::Inodes(..), ..
+        } = fscmp.dirs()?
+        {
+        } else {
+            panic!("Different hard links not detected");
+        }
+
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_inode_tracking(false);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ignore_nlink() -> Fallible<()> {
+        let dir1 = tempfile::tempdir()?;
+        let dir2 = tempfile::tempdir()?;
+        File::create(dir1.path().join("a"))?;
+        fs::hard_link(dir1.path().join("a"), dir1.path().join("b"))?;
+        File::create(dir2.path().join("a"))?;
+        File::create(dir2.path().join("b"))?;
+
+        let new_fscmp = || FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert!(matches!(
+            new_fscmp().dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Nlinks(2, 1),
+                ..
+            }
+        ));
+        assert!(matches!(
+            new_fscmp().with_ignore_nlink(true).dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Inodes(..),
+                ..
+            }
+        ));
+        let fscmp = new_fscmp().with_ignore_nlink(true).with_inode_tracking(false);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_reflinked_inodes() -> Fallible<()> {
+        nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+        // Reflinked copies share contents but not inode numbers, only hard links being reflected in the inode maps
+        let dir1 = tempfile::tempdir()?;
+        let dir2 = tempfile::tempdir()?;
+        for name in &["a", "c"] {
+            File::create(dir1.path().join(name))?.write_all(&[1; 4096])?;
+            let source = File::open(dir1.path().join(name))?;
+            let copy = File::create(dir2.path().join(name))?;
+            if unsafe { ficlone(copy.as_raw_fd(), source.as_raw_fd() as _) }.is_err() {
+                // Filesystem doesn't support reflinks, a plain copy has different inode numbers all the same
+                fs::copy(dir1.path().join(name), dir2.path().join(name))?;
+            }
+        }
+        for dir in &[&dir1, &dir2] {
+            fs::hard_link(dir.path().join("a"), dir.path().join("b"))?;
+        }
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        // Filesystems mounted in the first tree reuse inode numbers, which doesn't make their files hard links
+        for name in &["m1", "m2"] {
+            for dir in &[&dir1, &dir2] {
+                fs::create_dir(dir.path().join(name))?;
+                fs::set_permissions(dir.path().join(name), fs::Permissions::from_mode(0o755))?;
+            }
+            match mount(
+                Some("tmpfs"),
+                &dir1.path().join(name),
+                Some("tmpfs"),
+                MsFlags::empty(),
+                Some("mode=755"),
+            ) {
+                // Mounting needs privileges, so without them the first mount fails and nothing is left mounted
+                Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => return Ok(()),
+                result => result?,
+            }
+        }
+        for name in &["m1/f", "m2/f"] {
+            for dir in &[&dir1, &dir2] {
+                File::create(dir.path().join(name))?;
+            }
+        }
+        let result = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).dirs();
+        for name in &["m1", "m2"] {
+            umount(&dir1.path().join(name))?;
+        }
+        assert_eq!(result?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_bind_mount_self() -> Fallible<()> {
+        // Both roots are the same inode, which mustn't stop the comparison from descending into the children
+        let dir = generate_tree()?;
+        let bind = tempfile::tempdir()?;
+        match mount(
+            Some(dir.path()),
+            bind.path(),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        ) {
+            // Mounting needs privileges
+            Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => return Ok(()),
+            result => result?,
+        }
+        let result = FSCmp::new(dir.path().into(), bind.path().into(), None, HashSet::new())
+            .with_allow_same(true)
+            .dirs();
+        umount(bind.path())?;
+        assert_eq!(result?, Comparison::Equal);
+
+        // A bind mount doesn't carry the mounts below it, so a tmpfs mounted over a subdirectory beforehand is only
+        // seen in the first tree
+        let sub = dir.path().join("directory");
+        mount(Some("tmpfs"), &sub, Some("tmpfs"), MsFlags::empty(), None::<&str>)?;
+        mount(
+            Some(dir.path()),
+            bind.path(),
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )?;
+        let result = FSCmp::new(dir.path().into(), bind.path().into(), None, HashSet::new())
+            .with_allow_same(true)
+            .dirs();
+        umount(bind.path())?;
+        umount(&sub)?;
+        match result? {
+            Comparison::Unequal { path, .. } => assert_eq!(path, Some("directory".into())),
+            Comparison::Equal => panic!("Children of a root compared with itself weren't compared"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_same_file_contents() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (file, link, copy) = (
+            dir.path().join("file"),
+            dir.path().join("link"),
+            dir.path().join("copy"),
+        );
+        fs::write(&file, [1; 512])?;
+        fs::hard_link(&file, &link)?;
+        fs::copy(&file, &copy)?;
+
+        let fscmp = FSCmp::new(file.clone(), link, None, HashSet::new()).with_allow_same(true);
+        let (comp, stats) = fscmp.contents_with_stats(512)?;
+        assert_eq!((comp, stats.bytes_read), (Comparison::Equal, 0));
+        let fscmp = FSCmp::new(file, copy, None, HashSet::new());
+        let (comp, stats) = fscmp.contents_with_stats(512)?;
+        assert_eq!((comp, stats.bytes_read), (Comparison::Equal, 1024));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "tar"))]
+    fn test_dir_with_tar() -> Fallible<()> {
+        let dir = generate_tree()?;
+        fs::write(dir.path().join("directory").join("regular_file"), vec![1; BUF_SIZE + 1])?;
+        let long_name = "long_name_".repeat(20);
+        fs::write(dir.path().join(&long_name), b"long")?;
+        fs::hard_link(dir.path().join(&long_name), dir.path().join("hard_link"))?;
+        let archive_dir = tempfile::tempdir()?;
+        let archive = archive_dir.path().join("archive.tar");
+        for format in &["gnu", "pax"] {
+            let status = std::process::Command::new("tar")
+                .arg("--format")
+                .arg(format)
+                .arg("-C")
+                .arg(dir.path())
+                .arg("-cf")
+                .arg(&archive)
+                .arg(".")
+                .status()?;
+            assert!(status.success());
+            let fscmp = FSCmp::new(dir.path().into(), archive.clone(), None, HashSet::new());
+            assert_eq!(fscmp.dir_with_tar()?, Comparison::Equal, "{} archive", format);
+        }
+
+        let mut data = vec![1; BUF_SIZE + 1];
+        data[BUF_SIZE] = 2;
+        fs::write(dir.path().join("directory").join("regular_file"), data)?;
+        let fscmp = FSCmp::new(dir.path().into(), archive.clone(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            path,
+            ..
+        } = fscmp.dir_with_tar()?
+        {
+            assert_eq!(lba, (BUF_SIZE / BLOCK_SIZE) as u64);
+            assert_eq!(path, Some(Path::new("directory").join("regular_file")));
+        } else {
+            panic!("Content diff not detected");
+        }
+
+        fs::remove_file(dir.path().join("directory").join("regular_file"))?;
+        let fscmp = FSCmp::new(dir.path().into(), archive, None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::DirContents(..),
+            ..
+        } = fscmp.dir_with_tar()?
+        {
+        } else {
+            panic!("Missing file not detected");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_compare_mtimes() -> Fallible<()> {
+        let (dir1, dir2) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let epoch = std::time::SystemTime::UNIX_EPOCH;
+        for dir in &[&dir1, &dir2] {
+            fs::create_dir(dir.path().join("directory"))?;
+            for name in &["regular_file", "directory/regular_file"] {
+                File::create(dir.path().join(name))?.set_modified(epoch)?;
+            }
+        }
+        File::open(dir1.path().join("directory"))?.set_modified(epoch)?;
+        File::open(dir2.path().join("directory"))?.set_modified(epoch + Duration::from_secs(1))?;
+
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_mtimes(true);
+        if let Comparison::Unequal {
+            diff: Diff::Mtimes(..),
+            path,
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(path, Some(PathBuf::from("directory")));
+        } else {
+            panic!("Modification time diff not detected");
+        }
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_compare_mtimes(true)
+            .with_timestamps_files_only(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ignore_changed() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let path = dir2.path().join("directory").join("regular_file");
+        fs::write(dir1.path().join("directory").join("regular_file"), b"b")?;
+        // As if modified after the comparison started
+        fs::write(&path, b"a")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_changed(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        // Files last modified before the comparison started are reported
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_changed(true);
+        if let Comparison::Unequal {
+            diff: Diff::Contents(..),
+            ..
+        } = fscmp.dirs()?
+        {
+        } else {
+            panic!("Content diff not detected");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_blocks() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        let context_payload = |size: usize, diff_offset: usize| -> Fallible<(u64, usize)> {
+            let mut data = vec![0; size];
+            fs::write(&first, &data)?;
+            data[diff_offset] = 1;
+            fs::write(&second, &data)?;
+            #[cfg(unix)]
+            let fscmp = FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+            #[cfg(windows)]
+            let fscmp = FSCmp::new(first.clone(), second.clone());
+            let fscmp = fscmp.with_context_blocks(2);
+            match fscmp.contents(size as u64)? {
+                Comparison::Unequal {
+                    diff: Diff::Contents(lba, offset, block1, block2),
+                    ..
+                } => {
+                    assert_eq!(offset, diff_offset as u64);
+                    assert_eq!(block1.len(), block2.len());
+                    Ok((lba, block1.len()))
+                }
+                comp => panic!("Unexpected {:?}", comp),
+            }
+        };
+
+        assert_eq!(context_payload(16 * BLOCK_SIZE, 4 * BLOCK_SIZE)?, (4, 5 * BLOCK_SIZE));
+        // Context stops at the start of the chunk holding the differing block, and at the end of the data
+        let block = (BUF_SIZE / BLOCK_SIZE) as u64;
+        assert_eq!(
+            context_payload(BUF_SIZE + 3 * BLOCK_SIZE, BUF_SIZE + BLOCK_SIZE)?,
+            (block + 1, 3 * BLOCK_SIZE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary_only() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&first, [0; BLOCK_SIZE])?;
+        fs::write(&second, [1; BLOCK_SIZE])?;
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(first, second, None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(first, second);
+        let fscmp = fscmp.with_summary_only(true);
+        if let Comparison::Unequal {
+            diff: Diff::Contents(0, _, block1, block2),
+            ..
+        } = fscmp.contents(BLOCK_SIZE as u64)?
+        {
+            assert!(block1.is_empty() && block2.is_empty());
+        } else {
+            panic!("Content diff not detected");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_size_past_end() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&first, vec![1; 2 * BLOCK_SIZE])?;
+        fs::write(&second, vec![1; 3 * BLOCK_SIZE])?;
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(first.clone(), second.clone());
+        let e = fscmp.contents(3 * BLOCK_SIZE as u64).unwrap_err();
+        assert!(e.to_string().contains("only 1024 bytes in size"), "{}", e);
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(first, second, None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(first, second);
+        let fscmp = fscmp.with_clamp_size(true);
+        assert_eq!(fscmp.contents(3 * BLOCK_SIZE as u64)?, Comparison::Equal);
+        let (_, stats) = fscmp
+            .with_offsets(BLOCK_SIZE as u64, 0)
+            .contents_with_stats(3 * BLOCK_SIZE as u64)?;
+        assert_eq!(stats.bytes_read, 2 * BLOCK_SIZE as u64);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_whole_devices_not_block_devices() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&first, [1; BLOCK_SIZE])?;
+        fs::write(&second, [1; BLOCK_SIZE])?;
+
+        let fscmp = FSCmp::new(first, second, None, HashSet::new());
+        let e = fscmp.whole_devices().unwrap_err();
+        assert!(e.to_string().contains("Not a block device"), "{}", e);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_zero_size_block_device() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        File::create(&first)?;
+        File::create(&second)?;
+        let fscmp = FSCmp::new(first.clone(), second.clone(), None, HashSet::new());
+        assert_eq!(fscmp.contents(0)?, Comparison::Equal);
+
+        fs::remove_file(&second)?;
+        match mknod(second.clone(), libc::S_IFBLK, 0) {
+            // Creating block devices needs privileges
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(()),
+            result => result?,
+        }
+        let fscmp = FSCmp::new(first, second.clone(), None, HashSet::new());
+        let e = fscmp.contents(0).unwrap_err();
+        assert!(e.to_string().contains("0 bytes of a block device"), "{}", e);
+        let fscmp = FSCmp::new(second, PathBuf::new(), None, HashSet::new());
+        assert!(fscmp.contents_pattern(0, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_trailing_separators() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("directory").join("regular_file"), b"a")?;
+
+        let comp = |first: PathBuf, second: PathBuf| {
+            FSCmp::new(first, second, None, HashSet::new())
+                .dirs()
+                .map(|comp| comp.to_string())
+        };
+        let expected = comp(dir1.path().into(), dir2.path().into())?;
+        let with_separators = |dir: &tempfile::TempDir| PathBuf::from(format!("{}//", dir.path().display()));
+        assert_eq!(comp(with_separators(&dir1), with_separators(&dir2))?, expected);
+        assert_eq!(
+            comp(dir1.path().join("."), dir2.path().join("directory").join(".."))?,
+            comp(dir1.path().into(), dir2.path().join("directory").join(".."))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_size_range() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir1.path().join("regular_file"), [1; 100])?;
+        fs::write(dir2.path().join("regular_file"), [2; 100])?;
+
+        let unequal_path = |min_size, max_size| -> Fallible<Option<PathBuf>> {
+            let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+                .with_min_size(min_size)
+                .with_max_size(max_size);
+            Ok(match fscmp.dirs()? {
+                Comparison::Equal => None,
+                Comparison::Unequal { path, .. } => path,
+            })
+        };
+        assert_eq!(unequal_path(None, None)?, Some("regular_file".into()));
+        assert_eq!(unequal_path(Some(101), None)?, None);
+        assert_eq!(unequal_path(None, Some(99))?, None);
+        assert_eq!(unequal_path(Some(100), Some(100))?, Some("regular_file".into()));
+
+        // Files out of the range aren't decided equal
+        let decisions = Arc::new(Mutex::new(HashMap::new()));
+        let handler_decisions = decisions.clone();
+        FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_min_size(Some(101))
+            .with_entry_handler(move |path, entry_decision| {
+                handler_decisions
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_path_buf(), entry_decision.decision);
+            })
+            .dirs()?;
+        assert_eq!(
+            decisions.lock().unwrap()[Path::new("regular_file")],
+            Decision::OutOfSizeRange
+        );
+
+        // Sizes are still compared
+        fs::write(dir2.path().join("regular_file"), [2; 200])?;
+        assert_eq!(unequal_path(Some(1000), None)?, Some("regular_file".into()));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_compare_blksize() -> Fallible<()> {
+        // Both trees being on the same filesystem, their entries prefer the same block size
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_blksize(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_dirs_with_stats() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for dir in &[&dir1, &dir2] {
+            fs::write(dir.path().join("regular_file"), [1; 512])?;
+        }
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        let (comp, stats) = fscmp.dirs_with_stats()?;
+        assert_eq!(comp, Comparison::Equal);
+        assert_eq!(
+            (stats.entries_compared, stats.bytes_read),
+            (tree_entries(dir1.path()), 1024)
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_common_orders() {
+        let names = |names: &[&str]| -> Vec<PathBuf> { names.iter().map(PathBuf::from).collect() };
+        let contents = |names: &[&str]| -> HashSet<PathBuf> { names.iter().map(PathBuf::from).collect() };
+        let (first, second) = (names(&["a", "b", "c"]), names(&["d", "b", "a"]));
+        // Entries on one side only are left out
+        assert_eq!(
+            common_orders(
+                (first, second),
+                (&contents(&["a", "b", "c"]), &contents(&["a", "b", "d"])),
+                &HashMap::new()
+            ),
+            (names(&["a", "b"]), names(&["b", "a"]))
+        );
+        // Entries of the second directory matched up to case go by the first's names
+        let second_names = vec![("b".into(), "B".into())].into_iter().collect();
+        assert_eq!(
+            common_orders(
+                (names(&["a", "b"]), names(&["B", "a"])),
+                (&contents(&["a", "b"]), &contents(&["a", "b"])),
+                &second_names
+            ),
+            (names(&["a", "b"]), names(&["b", "a"]))
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_compare_dir_order() -> Fallible<()> {
+        // tmpfs lists entries in the reverse order of their creation
+        let dir = tempfile::tempdir()?;
+        match mount(Some("tmpfs"), dir.path(), Some("tmpfs"), MsFlags::empty(), None::<&str>) {
+            // Mounting needs privileges, the order of common entries is still checked by test_common_orders
+            Err(nix::Error::Sys(nix::errno::Errno::EPERM)) => return Ok(()),
+            result => result?,
+        }
+        let (dir1, dir2) = (dir.path().join("first"), dir.path().join("second"));
+        // Nothing in here panics, so the tmpfs is always unmounted
+        let result = (|| -> Fallible<_> {
+            for (dir, names) in &[(&dir1, ["a", "b"]), (&dir2, ["b", "a"])] {
+                fs::create_dir(dir)?;
+                for name in names {
+                    File::create(dir.join(name))?;
+                }
+            }
+            fs::write(dir2.join("c"), b"")?;
+
+            let fscmp = FSCmp::new(dir1.clone(), dir2.clone(), None, HashSet::new()).with_missing_ok(true);
+            let unordered = fscmp.dirs()?;
+            let fscmp = FSCmp::new(dir1.clone(), dir2.clone(), None, HashSet::new())
+                .with_missing_ok(true)
+                .with_compare_dir_order(true);
+            Ok((unordered, fscmp.dirs()?))
+        })();
+        umount(dir.path())?;
+        let (unordered, ordered) = result?;
+        assert_eq!(unordered, Comparison::Equal);
+        assert_eq!(
+            ordered,
+            Comparison::Unequal {
+                diff: Diff::DirOrder(vec!["b".into(), "a".into()], vec!["a".into(), "b".into()]),
+                first: dir1,
+                second: dir2,
+                path: Some(".".into()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_hash_on_mismatch() -> Fallible<()> {
+        use sha2::{Digest, Sha512};
+        let dir = tempfile::tempdir()?;
+        let (file1, file2) = (dir.path().join("file1"), dir.path().join("file2"));
+        fs::write(&file1, [1; 1024])?;
+        fs::write(&file2, [2; 1024])?;
+        let fscmp =
+            FSCmp::new(file1, file2, None, HashSet::new()).with_hash_on_mismatch(Some(HashAlgorithm::Sha512));
+        match fscmp.contents(1024)? {
+            Comparison::Unequal {
+                diff: Diff::ContentHash(HashAlgorithm::Sha512, first, second),
+                ..
+            } => {
+                assert_eq!(first, Sha512::digest(&[1; 1024]).to_vec());
+                assert_eq!(second, Sha512::digest(&[2; 1024]).to_vec());
+            }
+            comp => panic!("Unexpected {:?}", comp),
+        }
+
+        #[cfg(feature = "blake3")]
+        {
+            match fscmp
+                .with_hash_on_mismatch(Some(HashAlgorithm::Blake3))
+                .contents(1024)?
+            {
+                Comparison::Unequal {
+                    diff: Diff::ContentHash(HashAlgorithm::Blake3, first, second),
+                    ..
+                } => {
+                    assert_eq!(first, blake3::hash(&[1; 1024]).as_bytes().to_vec());
+                    assert_eq!(second, blake3::hash(&[2; 1024]).as_bytes().to_vec());
+                }
+                comp => panic!("Unexpected {:?}", comp),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_changed_during_comparison() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        for name in &["first", "second"] {
+            fs::write(dir.path().join(name), [1; 1024])?;
+        }
+        let root = EntryInfo::dir(dir.path())?;
+        let (first, second) = (
+            root.child_entry("first".as_ref())?,
+            root.child_entry("second".as_ref())?,
+        );
+        // Shrunk after being queried, so it's shorter than its size when read
+        File::create(dir.path().join("second"))?.set_len(512)?;
+
+        let fscmp = FSCmp::new(dir.path().into(), dir.path().into(), None, HashSet::new());
+        match fscmp.file_eq(&first, &second)? {
+            Comparison::Unequal {
+                diff: Diff::Sizes(1024, 512),
+                ..
+            } => (),
+            comp => panic!("Unexpected {:?}", comp),
+        }
+        let fscmp = fscmp.with_strict_stable(true);
+        let err = fscmp.file_eq(&first, &second).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FsCmpError::ChangedDuringComparison("second".into()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_read_fifos() -> Fallible<()> {
+        let compare = |first_data: &'static [u8], second_data: &'static [u8]| -> Fallible<Comparison> {
+            let (dir1, dir2) = (tempfile::tempdir()?, tempfile::tempdir()?);
+            let mut writers = Vec::new();
+            for (dir, data) in &[(&dir1, first_data), (&dir2, second_data)] {
+                let path = dir.path().join("fifo");
+                mknod(path.clone(), libc::S_IFIFO, 0)?;
+                // Opening for writing waits for the comparison to open the FIFO for reading
+                let data = *data;
+                writers.push(thread::spawn(move || fs::write(path, data)));
+            }
+            let comp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+                .with_read_fifos(Some(1024))
+                .dirs();
+            for writer in writers {
+                writer.join().unwrap()?;
+            }
+            comp
+        };
+        assert_eq!(compare(b"data", b"data")?, Comparison::Equal);
+        match compare(b"data", b"date")? {
+            Comparison::Unequal {
+                diff: Diff::Contents(0, ..),
+                path,
+                ..
+            } => assert_eq!(path, Some("fifo".into())),
+            comp => panic!("Unexpected {:?}", comp),
+        }
+
+        // Nothing ever writes to these
+        let (dir1, dir2) = (generate_tree()?, generate_tree()?);
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_read_fifos(Some(1024))
+            .with_fifo_timeout(Duration::from_millis(10));
+        assert!(fscmp.dirs().is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_iter_diffs() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        fs::write(dir2.path().join("directory/regular_file"), b"a")?;
+
+        let mut paths = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .iter_diffs()
+            .map(|comp| match comp? {
+                Comparison::Unequal { path: Some(path), .. } => Ok(path),
+                comp => panic!("Unexpected comparison {:?}", comp),
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("directory/regular_file"), PathBuf::from("regular_file")]
+        );
+
+        let mut diffs = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("missing".into()))
+            .iter_diffs();
+        assert!(diffs.next().unwrap().is_err());
+        assert!(diffs.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_trailing_zeros() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir1.path().join("regular_file"), b"abc")?;
+        fs::write(dir2.path().join("regular_file"), b"abc\0\0\0")?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignore_trailing_zeros(true);
+        if let Comparison::Unequal { diff, .. } = fscmp.dirs()? {
+            assert_eq!(diff, Diff::TrailingPadding(3, 6));
+        } else {
+            panic!("Sizes should be unequal");
+        }
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignore_trailing_zeros(true)
+            .with_trailing_zeros_equal(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        fs::write(dir2.path().join("regular_file"), b"abc\0\0d")?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_ignore_trailing_zeros(true)
+            .with_trailing_zeros_equal(true);
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(lba, 0);
+        } else {
+            panic!("Contents should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ignore_case() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        File::create(dir1.path().join("directory/straße"))?;
+        File::create(dir2.path().join("directory/STRASSE"))?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_case(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        fs::write(dir2.path().join("directory/STRASSE"), b"a")?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_ignore_case(true);
+        if let Comparison::Unequal { path: Some(path), .. } = fscmp.dirs()? {
+            assert_eq!(path, Path::new("directory/straße"));
+        } else {
+            panic!("Content should be unequal");
+        }
+
+        // The dotless i only folds to the dotted one in Turkic languages, where "I" is its capital
+        let fold = |name: &str, turkic| case_fold::fold(name.as_ref(), turkic);
+        assert_eq!(fold("I", false), fold("i", false));
+        assert_ne!(fold("ı", false), fold("I", false));
+        assert_eq!(fold("ı", true), fold("I", true));
+        assert_eq!(fold("i", true), fold("İ", true));
+        assert_ne!(fold("i", true), fold("I", true));
+        assert_eq!(fold("ß", true), fold("SS", true));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reference() -> Fallible<()> {
+        use nix::sys::stat::utimes;
+        use nix::sys::time::{TimeVal, TimeValLike};
+
+        let reference = tempfile::tempdir()?;
+        let dir1 = tempfile::tempdir()?;
+        let dir2 = tempfile::tempdir()?;
+        let create = |dir: &tempfile::TempDir, name: &str, contents: u8, mtime: i64| -> Fallible<()> {
+            let path = dir.path().join(name);
+            File::create(&path)?.write_all(&[contents; 16])?;
+            utimes(&path, &TimeVal::seconds(mtime), &TimeVal::seconds(mtime))?;
+            Ok(())
+        };
+        // The first copy's contents changed without its metadata showing it, which only a full comparison finds
+        create(&reference, "unchanged", 0, 1000)?;
+        create(&dir1, "unchanged", 1, 1000)?;
+        create(&dir2, "unchanged", 0, 1000)?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_reference(Some(reference.path().into()));
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        create(&reference, "changed", 0, 1000)?;
+        create(&dir1, "changed", 1, 2000)?;
+        create(&dir2, "changed", 0, 1000)?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_reference(Some(reference.path().into()));
+        if let Comparison::Unequal { path, .. } = fscmp.dirs()? {
+            assert_eq!(path, Some("changed".into()));
+        } else {
+            panic!("Entry changed relative to the reference wasn't compared");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_subpath() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        fs::write(dir2.path().join("directory/regular_file"), b"a")?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("./directory/symlink".into()));
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("directory".into()));
+        if let Comparison::Unequal {
+            diff: Diff::Sizes(..),
+            path: Some(path),
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(path, Path::new("directory/regular_file"));
+        } else {
+            panic!("Content should be unequal");
+        }
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("missing".into()));
+        assert!(fscmp.dirs().is_err());
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_subpath(Some("../directory".into()));
+        assert!(fscmp.dirs().is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_permissions() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for entry in walkdir::WalkDir::new(dir1.path())
+            .min_depth(1)
+            .into_iter()
+            .filter(|e| !e.as_ref().unwrap().path_is_symlink())
+        {
+            let entry = entry?;
+            let original_perms = fs::symlink_metadata(entry.path())?.permissions();
+            let mut new_perms = original_perms.clone();
+            new_perms.set_readonly(true);
+            fs::set_permissions(entry.path(), new_perms)?;
+
+            let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+            if let Comparison::Unequal {
+                diff: Diff::Modes(..),
+                path: Some(path),
+                ..
+            } = fscmp.dirs()?
+            {
+                assert!(entry.path().ends_with(path));
+            } else {
+                panic!("Comparison should be unequal");
+            }
+
+            fs::set_permissions(entry.path(), original_perms)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_contents() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+
+        let file1_path = dir1.path().join("regular_file");
+        let file2_path = dir2.path().join("regular_file");
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone());
+        assert_eq!(fscmp.contents(0)?, Comparison::Equal);
+
+        let mut file1 = fs::OpenOptions::new().write(true).open(&file1_path)?;
+        let file2 = fs::OpenOptions::new().write(true).open(&file2_path)?;
+
+        file1.set_len(1024 * 1024)?;
+        file2.set_len(1024 * 1024)?;
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone());
+        assert_eq!(fscmp.contents(1024 * 1024)?, Comparison::Equal);
+
+        let offset = file1.seek(io::SeekFrom::Start(532 * 1024 + 13))?;
+        file1.write_all(b"a")?;
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(file1_path, file2_path, None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(file1_path, file2_path);
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.contents(1024 * 1024)?
+        {
+            assert_eq!(offset / 512, lba);
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_diff_summary() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for dir in &[dir1.path(), &dir1.path().join("directory")] {
+            fs::write(dir.join("regular_file"), b"a")?;
+        }
+        for dir in &[dir2.path(), &dir2.path().join("directory")] {
+            fs::write(dir.join("regular_file"), b"b")?;
+        }
+        fs::set_permissions(dir2.path().join("fifo"), fs::Permissions::from_mode(0o600))?;
+        File::create(dir1.path().join("directory/first_only"))?;
+        File::create(dir2.path().join("directory/second_only"))?;
+
+        let summary = Arc::new(Mutex::new(DiffSummary::default()));
+        let handler_summary = summary.clone();
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_report_all(true)
+            .with_diff_handler(move |comp| handler_summary.lock().unwrap().add(comp));
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        assert_eq!(
+            summary.lock().unwrap().to_string(),
+            "2 content diffs, 2 missing entries, 1 mode diff"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_report_all() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        fs::set_permissions(
+            dir2.path().join("directory/directory"),
+            fs::Permissions::from_mode(0o700),
+        )?;
+        File::create(dir2.path().join("directory/new_regular_file"))?;
+
+        let diffs = Arc::new(Mutex::new(Vec::new()));
+        let handler_diffs = diffs.clone();
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new())
+            .with_report_all(true)
+            .with_diff_handler(move |comp| handler_diffs.lock().unwrap().push(comp.clone()));
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+
+        let mut diffs: Vec<_> = diffs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|comp| match comp {
+                Comparison::Unequal {
+                    diff, path: Some(path), ..
+                } => (path.clone(), mem::discriminant(diff)),
+                _ => panic!("Unexpected comparison {:?}", comp),
+            })
+            .collect();
+        diffs.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            diffs,
+            vec![
+                (
+                    PathBuf::from("directory"),
+                    mem::discriminant(&Diff::DirContents(Default::default(), Default::default()))
+                ),
+                (
+                    PathBuf::from("directory/directory"),
+                    mem::discriminant(&Diff::Modes(0, 0))
+                ),
+                (PathBuf::from("regular_file"), mem::discriminant(&Diff::Sizes(0, 0))),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_relative_path() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        for dir in &[&dir1, &dir2] {
+            fs::create_dir(dir.path().join("directory/directory/directory"))?;
+        }
+        fs::write(dir1.path().join("directory/directory/regular_file"), b"a")?;
+        fs::write(dir2.path().join("directory/directory/regular_file"), b"b")?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::Contents(..),
+            path: Some(path),
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(path, Path::new("directory/directory/regular_file"));
+        } else {
+            panic!("Content should be unequal");
+        }
+
+        File::create(dir2.path().join("new_regular_file"))?;
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal { path: Some(path), .. } = fscmp.dirs()? {
+            assert_eq!(path, Path::new("."));
+        } else {
+            panic!("Root should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_offset() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (path1, path2) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&path1, vec![0; 4096])?;
+        let mut data = vec![0; 4096];
+        data[100] = 1;
+        data[2660] = 1;
+        fs::write(&path2, data)?;
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone());
+        assert_eq!(fscmp.with_offset(512).contents(2048)?, Comparison::Equal);
+        // Contents can only be read from whole blocks
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone());
+        assert!(fscmp.with_offset(13).contents(2048).is_err());
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone());
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.with_offset(2048).contents(1024)?
+        {
+            assert_eq!(lba, 5);
+        } else {
+            panic!("Contents should be unequal");
+        }
+
+        // The second file's only set bytes are 2560 bytes, five blocks, apart
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path2.clone(), path2.clone(), None, HashSet::new()).with_allow_same(true);
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path2.clone(), path2.clone());
+        assert_eq!(fscmp.with_offsets(0, 2560).contents(1024)?, Comparison::Equal);
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path2.clone(), path2.clone(), None, HashSet::new()).with_allow_same(true);
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path2.clone(), path2.clone());
+        assert!(fscmp.with_offsets(0, 2900).contents(1024).is_err());
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path1, path2, None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path1, path2);
+        if let Comparison::Unequal {
+            diff: Diff::RelocatedContents(first_lba, second_lba, ..),
+            ..
+        } = fscmp.with_offsets(1024, 2048).contents(1024)?
+        {
+            assert_eq!((first_lba, second_lba), (3, 5));
+        } else {
+            panic!("Contents should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_file() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (path1, path2) = (dir.path().join("first"), dir.path().join("second"));
+        let resume_file = dir.path().join("resume");
+        let size = 4 * BUF_SIZE;
+        fs::write(&path1, vec![0; size])?;
+        fs::write(&path2, vec![0; size])?;
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path1.clone(), path2.clone());
+        let fscmp = fscmp.with_resume_file(Some(resume_file.clone()));
+        assert_eq!(fscmp.contents(size as u64)?, Comparison::Equal);
+        assert_eq!(fs::read_to_string(&resume_file)?.lines().count(), 5);
+
+        // Verified regions are only read from the first path, so a change in the second one goes unnoticed
+        let mut data = vec![0; size];
+        data[BUF_SIZE + 1] = 1;
+        fs::write(&path2, &data)?;
+        assert_eq!(fscmp.contents(size as u64)?, Comparison::Equal);
+        fs::remove_file(&resume_file)?;
+        assert_ne!(fscmp.contents(size as u64)?, Comparison::Equal);
+
+        // Regions changed in the first path since they were verified are compared again
+        fs::write(&path2, vec![0; size])?;
+        assert_eq!(fscmp.contents(size as u64)?, Comparison::Equal);
+        fs::write(&path1, data)?;
+        assert_ne!(fscmp.contents(size as u64)?, Comparison::Equal);
+
+        // A sidecar of another comparison isn't reused
+        assert!(fscmp.contents(BUF_SIZE as u64).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_contents_pattern() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("regular_file");
+        fs::write(&path, vec![0; 4096])?;
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(path.clone(), "zeroes".into(), None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(path.clone(), "zeroes".into());
+        assert_eq!(fscmp.contents_pattern(4096, 0)?, Comparison::Equal);
+
+        let mut file = fs::OpenOptions::new().write(true).open(&path)?;
+        file.seek(io::SeekFrom::Start(1024 + 3))?;
+        file.write_all(b"a")?;
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, offset, first, second),
+            ..
+        } = fscmp.contents_pattern(4096, 0)?
+        {
+            assert_eq!((lba, offset), (2, 1027));
+            assert_eq!(first[3], b'a');
+            assert_eq!(second, vec![0; BLOCK_SIZE]);
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_diff_only() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        let mut contents = vec![0; BUF_SIZE * 8];
+        fs::write(&first, &contents)?;
+        contents[BUF_SIZE * 3 + 5] = 1;
+        contents[BUF_SIZE * 6] = 1;
+        fs::write(&second, &contents)?;
+
+        #[cfg(unix)]
+        let fscmp = FSCmp::new(first, second, None, HashSet::new());
+        #[cfg(windows)]
+        let fscmp = FSCmp::new(first, second);
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.with_first_diff_only(true).contents(contents.len() as u64)?
+        {
+            assert_eq!(lba as usize, BUF_SIZE * 3 / BLOCK_SIZE);
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
 
-fn calc_chunk_count(limit: u64, chunk_size: u64) -> u64 {
-    max(limit / chunk_size, 1)
-}
+    #[test]
+    #[cfg(unix)]
+    fn test_bench_read_only() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let (first, second) = (dir.path().join("first"), dir.path().join("second"));
+        fs::write(&first, [0; 512])?;
+        fs::write(&second, [1; 512])?;
 
-fn calc_leap(size: u64, limit: u64, chunk_size: u64) -> u64 {
-    if limit < chunk_size {
-        limit
-    } else {
-        max::<u64>(chunk_size, size / ((limit + chunk_size - 1) / chunk_size))
+        let fscmp = FSCmp::new(first, second, None, HashSet::new()).with_bench_read_only(true);
+        let (comp, stats) = fscmp.contents_with_stats(512)?;
+        assert_eq!((comp, stats.bytes_read), (Comparison::Equal, 1024));
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::fs;
-    use std::io;
-    use std::io::prelude::*;
-    #[cfg(unix)]
-    use std::os::unix;
-    #[cfg(unix)]
-    use std::os::unix::fs::PermissionsExt;
-    use tempfile;
+    #[test]
     #[cfg(unix)]
-    use walkdir;
+    fn test_contents_last_partial_chunk() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let size = BUF_SIZE + 1;
+        fs::write(dir.path().join("first"), vec![0; size])?;
+        let mut data = vec![0; size];
+        data[BUF_SIZE] = 1;
+        fs::write(dir.path().join("second"), data)?;
+
+        let fscmp = FSCmp::new(
+            dir.path().join("first"),
+            dir.path().join("second"),
+            None,
+            HashSet::new(),
+        );
+        if let Comparison::Unequal {
+            diff: Diff::Contents(lba, ..),
+            ..
+        } = fscmp.contents(size as u64)?
+        {
+            assert_eq!(lba, BUF_SIZE_U64 / BLOCK_SIZE as u64);
+        } else {
+            panic!("Last byte should be compared");
+        }
+        Ok(())
+    }
 
     #[test]
-    fn test_calc_leap() {
-        assert_eq!(calc_leap(100, 50, 2), 4);
-        assert_eq!(calc_leap(50, 50, 2), 2);
-        assert_eq!(calc_leap(150, 30, 2), 10);
-        assert_eq!(calc_leap(25, 50, 2), 2);
-        assert_eq!(calc_leap(25, 1, 2), 1);
-        assert_eq!(calc_leap(2_000_000_000, 2_000_000_000, BUF_SIZE_U64), BUF_SIZE_U64);
+    #[cfg(unix)]
+    fn test_contents_file_types() -> Fallible<()> {
+        let dir = generate_tree()?;
+        for name in &["directory", "symlink", "char_device", "fifo", "socket"] {
+            let fscmp = FSCmp::new(
+                dir.path().join("regular_file"),
+                dir.path().join(name),
+                None,
+                HashSet::new(),
+            );
+            assert!(fscmp.contents(1).is_err(), "{} should not be compared", name);
+        }
+        Ok(())
     }
 
     #[test]
-    fn test_calc_chunk_count() {
-        assert_eq!(calc_chunk_count(1, 2), 1);
-        assert_eq!(calc_chunk_count(50, 2), 25);
-        assert_eq!(calc_chunk_count(20, 2), 10);
+    #[cfg(target_os = "linux")]
+    fn test_compare_flags() -> Fallible<()> {
+        nix::ioctl_write_ptr!(fs_ioc_setflags, b'f', 2, libc::c_long);
+
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let file = File::open(dir2.path().join("regular_file"))?;
+        let flags: libc::c_long = 0x40; // No-dump
+        if unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }.is_err() {
+            // Filesystem doesn't support inode flags
+            return Ok(());
+        }
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_flags(true);
+        assert_eq!(
+            fscmp.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::InodeFlags(0, 0x40),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("regular_file".into()),
+            }
+        );
+        Ok(())
     }
 
-    #[cfg(unix)]
-    fn mknod(path: PathBuf, mode: libc::mode_t, dev: libc::dev_t) -> Fallible<()> {
-        use std::ffi;
-        use std::os::unix::ffi::OsStringExt;
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_compare_compression() -> Fallible<()> {
+        nix::ioctl_write_ptr!(fs_ioc_setflags, b'f', 2, libc::c_long);
 
-        unsafe {
-            libc::mknod(
-                ffi::CString::new(path.into_os_string().into_vec())?.as_ptr(),
-                mode | 0o644,
-                dev,
-            )
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let new_fscmp = || {
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_compression(true)
         };
+        assert_eq!(new_fscmp().dirs()?, Comparison::Equal);
 
+        let file = File::open(dir2.path().join("regular_file"))?;
+        let flags: libc::c_long = 0x4; // Compressed
+        if unsafe { fs_ioc_setflags(file.as_raw_fd(), &flags) }.is_err() {
+            // Filesystem doesn't support compression
+            return Ok(());
+        }
+        assert_eq!(
+            new_fscmp().dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Compression(false, true),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("regular_file".into()),
+            }
+        );
         Ok(())
     }
 
-    #[cfg(unix)]
-    fn generate_tree() -> Fallible<tempfile::TempDir> {
-        let dir = tempfile::tempdir()?;
-        for dir in &[dir.path(), &dir.path().join("directory")] {
-            fs::create_dir(dir.join("directory"))?;
-            File::create(dir.join("regular_file"))?;
-            unix::fs::symlink("symlink_target", dir.join("symlink"))?;
-            mknod(dir.join("block_device"), libc::S_IFBLK, 0)?;
-            mknod(dir.join("char_device"), libc::S_IFCHR, 0)?;
-            mknod(dir.join("fifo"), libc::S_IFIFO, 0)?;
-            mknod(dir.join("socket"), libc::S_IFSOCK, 0)?;
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_compare_xattrs() -> Fallible<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        let setxattr = |path: &Path, name: &str, value: &[u8]| -> Fallible<bool> {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            let name = CString::new(name)?;
+            let res = unsafe {
+                libc::setxattr(
+                    path.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const libc::c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            Ok(res == 0)
+        };
+        let first_file = dir1.path().join("directory/regular_file");
+        let second_file = dir2.path().join("directory/regular_file");
+        if !setxattr(&first_file, "user.kept", b"a")? {
+            // Filesystem doesn't support user xattrs
+            return Ok(());
         }
-        Ok(dir)
-    }
+        setxattr(&second_file, "user.kept", b"a")?;
+        setxattr(&first_file, "user.backup-time", b"1")?;
+        setxattr(&second_file, "user.backup-time", b"2")?;
 
-    #[cfg(windows)]
-    fn generate_tree() -> Fallible<tempfile::TempDir> {
-        let dir = tempfile::tempdir()?;
-        File::create(dir.path().join("regular_file"))?;
-        Ok(dir)
+        let fscmp = || FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_eq!(fscmp().dirs()?, Comparison::Equal);
+        assert_eq!(
+            fscmp().with_compare_xattrs(true).dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Xattrs(
+                    vec![("user.backup-time".to_string(), b"1".to_vec())]
+                        .into_iter()
+                        .collect(),
+                    vec![("user.backup-time".to_string(), b"2".to_vec())]
+                        .into_iter()
+                        .collect(),
+                ),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("directory/regular_file".into()),
+            }
+        );
+        let fscmp = fscmp()
+            .with_compare_xattrs(true)
+            .with_ignored_xattrs(&["user.backup-*".into()])?;
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        Ok(())
     }
 
     #[test]
-    #[cfg(unix)]
-    fn test_simple() -> Fallible<()> {
+    #[cfg(target_os = "linux")]
+    fn test_compare_extents() -> Fallible<()> {
         let dir1 = generate_tree()?;
-        let fscmp = FSCmp::new(dir1.path().into(), dir1.path().into(), None, HashSet::new());
+        let dir2 = generate_tree()?;
+        for dir in &[&dir1, &dir2] {
+            let mut file = File::create(dir.path().join("regular_file"))?;
+            file.write_all(&[1; 4096])?;
+            file.sync_all()?;
+        }
+        if ioctl::extents(&File::open(dir1.path().join("regular_file"))?)?.is_none() {
+            // Filesystem doesn't support FIEMAP
+            return Ok(());
+        }
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
         assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_extents(true);
+        assert_eq!(
+            fscmp.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::ExtentSharing(0, 4096),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("regular_file".into()),
+            }
+        );
+        Ok(())
+    }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_compare_sparse_map() -> Fallible<()> {
+        let dir1 = generate_tree()?;
         let dir2 = generate_tree()?;
+        // Same zeroes, written out in the first and left as a hole in the second
+        File::create(dir1.path().join("regular_file"))?.write_all(&[0; 8192])?;
+        let file = File::create(dir2.path().join("regular_file"))?;
+        file.set_len(4096)?;
+        file.write_all_at(&[0; 4096], 4096)?;
+        file.sync_all()?;
+        let second_regions = ioctl::data_regions(&file, 8192)?;
+        if second_regions == [(0, 8192)] {
+            // Filesystem doesn't track holes
+            return Ok(());
+        }
+
         let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
         assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_sparse_map(true);
+        assert_eq!(
+            fscmp.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::SparseLayout(vec![(0, 8192)], second_regions),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("regular_file".into()),
+            }
+        );
+        Ok(())
+    }
 
-        File::create(dir2.path().join("new_regular_file"))?;
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_overlay_aware() -> Fallible<()> {
+        // The generated char_device is numbered 0/0, which is a whiteout
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::remove_file(dir2.path().join("char_device"))?;
         let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
         if let Comparison::Unequal {
             diff: Diff::DirContents(..),
@@ -602,84 +5930,93 @@ mod test {
         } = fscmp.dirs()?
         {
         } else {
-            panic!("New file not detected");
+            panic!("Missing entry not detected");
         }
-        fs::remove_file(dir2.path().join("new_regular_file"))?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_overlay_aware(true);
+        assert_eq!(
+            fscmp.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Whiteouts(true, false),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("char_device".into()),
+            }
+        );
+
+        File::create(dir2.path().join("char_device"))?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_overlay_aware(true);
+        assert_eq!(
+            fscmp.dirs()?,
+            Comparison::Unequal {
+                diff: Diff::Whiteouts(true, false),
+                first: dir1.path().into(),
+                second: dir2.path().into(),
+                path: Some("char_device".into()),
+            }
+        );
         Ok(())
     }
 
     #[test]
     #[cfg(unix)]
-    fn test_permissions() -> Fallible<()> {
-        let dir1 = generate_tree()?;
-        let dir2 = generate_tree()?;
-        for entry in walkdir::WalkDir::new(dir1.path())
-            .min_depth(1)
-            .into_iter()
-            .filter(|e| !e.as_ref().unwrap().path_is_symlink())
-        {
-            let entry = entry?;
-            let original_perms = fs::symlink_metadata(entry.path())?.permissions();
-            let mut new_perms = original_perms.clone();
-            new_perms.set_readonly(true);
-            fs::set_permissions(entry.path(), new_perms)?;
+    fn test_retries() {
+        let fscmp = FSCmp::new("first".into(), "second".into(), None, HashSet::new()).with_retries(2);
+        let failing = |errno: i32, failures: u32| {
+            let mut attempts = 0;
+            let res = fscmp.retry(Path::new("test"), || {
+                attempts += 1;
+                if attempts <= failures {
+                    Err(io::Error::from_raw_os_error(errno)).context("test")?;
+                }
+                Ok(())
+            });
+            (res.is_ok(), attempts)
+        };
+        assert_eq!(failing(libc::ESTALE, 2), (true, 3));
+        assert_eq!(failing(libc::EIO, 3), (false, 3));
+        // Permanent errors aren't retried
+        assert_eq!(failing(libc::ENOENT, 1), (false, 1));
+    }
 
-            let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
-            if let Comparison::Unequal {
-                diff: Diff::Modes(..),
-                path: Some(path),
-                ..
-            } = fscmp.dirs()?
-            {
-                assert!(entry.path().ends_with(path));
-            } else {
-                panic!("Comparison should be unequal");
-            }
+    #[test]
+    #[cfg(unix)]
+    fn test_timeout() -> Fallible<()> {
+        let dir = generate_tree()?;
+        let fscmp = FSCmp::new(dir.path().into(), dir.path().into(), None, HashSet::new())
+            .with_timeout(Some(Duration::from_secs(60)));
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
 
-            fs::set_permissions(entry.path(), original_perms)?;
-        }
+        // As if the watchdog expired during the comparison
+        fscmp.timed_out.store(true, Ordering::Relaxed);
+        let root = EntryInfo::dir(dir.path())?;
+        let err = fscmp.entry_eq(&root, &root).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&FsCmpError::Timeout));
         Ok(())
     }
 
     #[test]
-    fn test_contents() -> Fallible<()> {
-        let dir1 = generate_tree()?;
-        let dir2 = generate_tree()?;
-
-        let file1_path = dir1.path().join("regular_file");
-        let file2_path = dir2.path().join("regular_file");
-
-        #[cfg(unix)]
-        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone(), None, HashSet::new());
-        #[cfg(windows)]
-        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone());
-        assert_eq!(fscmp.contents(0)?, Comparison::Equal);
-
-        let mut file1 = fs::OpenOptions::new().write(true).open(&file1_path)?;
-        let file2 = fs::OpenOptions::new().write(true).open(&file2_path)?;
-
-        file1.set_len(1024 * 1024)?;
-        file2.set_len(1024 * 1024)?;
+    fn test_cancel() -> Fallible<()> {
+        let dir = generate_tree()?;
+        let path = dir.path().join("regular_file");
+        fs::write(&path, b"a")?;
+        let cancel = Arc::new(AtomicBool::new(false));
         #[cfg(unix)]
-        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone(), None, HashSet::new());
+        let fscmp = FSCmp::new(path.clone(), path, None, HashSet::new()).with_cancel(cancel.clone());
         #[cfg(windows)]
-        let fscmp = FSCmp::new(file1_path.clone(), file2_path.clone());
-        assert_eq!(fscmp.contents(1024 * 1024)?, Comparison::Equal);
+        let fscmp = FSCmp::new(path.clone(), path).with_cancel(cancel.clone());
+        assert_eq!(fscmp.contents(1)?, Comparison::Equal);
 
-        let offset = file1.seek(io::SeekFrom::Start(532 * 1024 + 13))?;
-        file1.write_all(b"a")?;
+        cancel.store(true, Ordering::Relaxed);
+        let err = fscmp.contents(1).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&FsCmpError::Cancelled));
         #[cfg(unix)]
-        let fscmp = FSCmp::new(file1_path, file2_path, None, HashSet::new());
-        #[cfg(windows)]
-        let fscmp = FSCmp::new(file1_path, file2_path);
-        if let Comparison::Unequal {
-            diff: Diff::Contents(lba, ..),
-            ..
-        } = fscmp.contents(1024 * 1024)?
         {
-            assert_eq!(offset / 512, lba);
-        } else {
-            panic!("Content should be unequal");
+            let fscmp =
+                FSCmp::new(dir.path().into(), dir.path().into(), None, HashSet::new()).with_cancel(cancel.clone());
+            let err = fscmp.dirs().unwrap_err();
+            assert_eq!(err.downcast_ref(), Some(&FsCmpError::Cancelled));
         }
         Ok(())
     }
@@ -705,6 +6042,68 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_path_max_file_contents() -> Fallible<()> {
+        // 17 components of 240 bytes make a relative path of exactly PATH_MAX bytes
+        let name = "a".repeat(240);
+        let create = |contents: &[u8]| -> Fallible<tempfile::TempDir> {
+            let dir = tempfile::tempdir()?;
+            let mut parent = openat::Dir::open(dir.path())?;
+            for _ in 0..16 {
+                parent.create_dir(name.as_str(), 0o755)?;
+                parent = parent.sub_dir(name.as_str())?;
+            }
+            parent.new_file(name.as_str(), 0o644)?.write_all(contents)?;
+            Ok(dir)
+        };
+        let dir1 = create(&[0; 4096])?;
+        let dir2 = create(&[1; 4096])?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        if let Comparison::Unequal {
+            diff: Diff::Contents(0, ..),
+            path: Some(path),
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(path.as_os_str().len(), libc::PATH_MAX as usize);
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_content_only() -> Fallible<()> {
+        let dir1 = generate_tree()?;
+        let dir2 = generate_tree()?;
+        fs::set_permissions(dir2.path().join("regular_file"), fs::Permissions::from_mode(0o600))?;
+        fs::hard_link(dir2.path().join("regular_file"), dir2.path().join("hard_link"))?;
+        fs::hard_link(dir1.path().join("regular_file"), dir1.path().join("hard_link"))?;
+        fs::remove_file(dir2.path().join("hard_link"))?;
+        fs::copy(dir2.path().join("regular_file"), dir2.path().join("hard_link"))?;
+
+        let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
+        assert_ne!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_content_only(true);
+        assert_eq!(fscmp.dirs()?, Comparison::Equal);
+
+        fs::write(dir2.path().join("regular_file"), b"a")?;
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_content_only(true);
+        if let Comparison::Unequal {
+            diff: Diff::Sizes(..), ..
+        } = fscmp.dirs()?
+        {
+        } else {
+            panic!("Content should be unequal");
+        }
+        Ok(())
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_root_mode() -> failure::Fallible<()> {
@@ -713,6 +6112,85 @@ mod test {
         fs::set_permissions(dir2.path(), fs::Permissions::from_mode(0o777))?;
         let fscmp = FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new());
         assert_eq!(fscmp.dirs()?, Comparison::Equal);
+        let fscmp =
+            FSCmp::new(dir1.path().into(), dir2.path().into(), None, HashSet::new()).with_compare_root_meta(true);
+        if let Comparison::Unequal {
+            diff: Diff::Modes(..),
+            path: Some(path),
+            ..
+        } = fscmp.dirs()?
+        {
+            assert_eq!(path, Path::new("."));
+        } else {
+            panic!("Root mode should be unequal");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_broken_symlink_root() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let link = dir.path().join("symlink");
+        unix::fs::symlink("missing", &link)?;
+        let fscmp = FSCmp::new(link.clone(), dir.path().into(), None, HashSet::new());
+        let err = fscmp.dirs().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FsCmpError::BrokenSymlink(link, "missing".into()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_binary_round_trip() -> Fallible<()> {
+        let comparisons: Vec<_> = vec![
+            Diff::Modes(0o644, 0o600),
+            Diff::Mtimes(-1, 1),
+            Diff::Inodes(Some("hardlink".into()), None),
+            Diff::Contents(3, 12345, vec![0; 16], vec![1; 16]),
+            Diff::ContentHash(HashAlgorithm::Sha512, vec![0xab; 64], vec![0xcd; 64]),
+            Diff::DirContents(vec!["a".into(), "b".into()].into_iter().collect(), HashSet::new()),
+            Diff::SparseLayout(vec![(0, 4096)], vec![(0, 4096), (8192, 12288)]),
+            Diff::Xattrs(
+                vec![("user.name".into(), b"value".to_vec())].into_iter().collect(),
+                BTreeMap::new(),
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, diff)| Comparison::Unequal {
+            diff,
+            first: "first".into(),
+            second: "second".into(),
+            path: if i % 2 == 0 {
+                Some(format!("directory/{}", i).into())
+            } else {
+                None
+            },
+        })
+        .collect();
+
+        let mut encoded = vec![];
+        for comp in &comparisons {
+            comp.write_binary(&mut encoded)?;
+        }
+        Comparison::Equal.write_binary(&mut encoded)?;
+
+        let mut reader = &encoded[..];
+        let mut decoded = vec![];
+        while let Some(comp) = decode_binary(&mut reader)? {
+            decoded.push(comp);
+        }
+        assert_eq!(decoded, comparisons);
+
+        let mut truncated = &encoded[..encoded.len() - 1];
+        for _ in 1..comparisons.len() {
+            decode_binary(&mut truncated)?;
+        }
+        assert!(decode_binary(&mut truncated).is_err());
+        assert!(decode_binary(&mut &encoded[..2]).is_err());
         Ok(())
     }
 }
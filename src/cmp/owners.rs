@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::Mutex;
+
+/// Looks up the name of an ID with a reentrant NSS function, growing the string buffer as needed
+macro_rules! lookup_name {
+    ($lookup:path, $entry_type:ty, $name_field:ident, $id:expr) => {{
+        let mut buf = vec![0 as libc::c_char; 1024];
+        loop {
+            let mut entry = MaybeUninit::<$entry_type>::uninit();
+            let mut result = ptr::null_mut();
+            let ret = unsafe { $lookup($id, entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) };
+            if ret == libc::ERANGE {
+                let len = buf.len();
+                buf.resize(len * 2, 0);
+                continue;
+            }
+            break if ret != 0 || result.is_null() {
+                None
+            } else {
+                Some(
+                    unsafe { CStr::from_ptr((*result).$name_field) }
+                        .to_string_lossy()
+                        .into_owned(),
+                )
+            };
+        }
+    }};
+}
+
+/// Cached user and group names, saving repeated NSS lookups of the same IDs
+#[derive(Default)]
+pub struct OwnerNames {
+    users: Mutex<HashMap<libc::uid_t, Option<String>>>,
+    groups: Mutex<HashMap<libc::gid_t, Option<String>>>,
+}
+
+impl OwnerNames {
+    /// Name of a user ID, `None` if it doesn't resolve
+    pub fn user(&self, uid: libc::uid_t) -> Option<String> {
+        self.users
+            .lock()
+            .unwrap()
+            .entry(uid)
+            .or_insert_with(|| lookup_name!(libc::getpwuid_r, libc::passwd, pw_name, uid))
+            .clone()
+    }
+
+    /// Name of a group ID, `None` if it doesn't resolve
+    pub fn group(&self, gid: libc::gid_t) -> Option<String> {
+        self.groups
+            .lock()
+            .unwrap()
+            .entry(gid)
+            .or_insert_with(|| lookup_name!(libc::getgrgid_r, libc::group, gr_name, gid))
+            .clone()
+    }
+}
@@ -0,0 +1,107 @@
+//! Sidecar files recording the verified regions of a content comparison, so an interrupted one can be resumed
+//!
+//! A sidecar starts with a line describing the comparison, followed by a line for each region found equal:
+//!
+//! ```text
+//! fscmp-resume 1 size=<size> offsets=<first offset>,<second offset> region=<region size>
+//! <region start> <SHA-256 of the region's contents in hex>
+//! ```
+//!
+//! Region starts are relative to the offsets. A sidecar describing another comparison is rejected rather than
+//! reused, and a last line cut short by an interruption is ignored. Recorded regions are skipped only if the first
+//! path still holds what was recorded for them, so changes made since to the second path alone go unnoticed.
+
+use super::comparison::hex;
+use failure::{bail, Fallible, ResultExt};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+pub struct ResumeLog {
+    /// Digests of the verified regions by start
+    verified: HashMap<u64, String>,
+    file: Mutex<File>,
+}
+
+impl ResumeLog {
+    /// Open the sidecar at `path` for comparing `size` bytes from `offsets` in regions of `region_size`, creating
+    /// it if it doesn't exist
+    pub fn open(path: &Path, size: u64, offsets: (u64, u64), region_size: u64) -> Fallible<Self> {
+        let header = format!(
+            "fscmp-resume 1 size={} offsets={},{} region={}",
+            size, offsets.0, offsets.1, region_size
+        );
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+
+        let mut verified = HashMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            writeln!(file, "{}", header)?;
+        } else {
+            if line.trim_end() != header {
+                bail!("\"{}\" was written for another comparison", path.display());
+            }
+            let mut complete = true;
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                complete = line.ends_with('\n');
+                if let Some((start, digest)) = parse_region(&line) {
+                    verified.insert(start, digest.to_string());
+                }
+            }
+            if !complete {
+                // Start the next region on a line of its own
+                writeln!(file)?;
+            }
+        }
+        Ok(ResumeLog {
+            verified,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn is_recorded(&self, start: u64) -> bool {
+        self.verified.contains_key(&start)
+    }
+
+    /// Whether the region at `start` was verified holding `data`
+    pub fn is_verified(&self, start: u64, data: &[u8]) -> bool {
+        self.verified.get(&start) == Some(&digest(data))
+    }
+
+    /// Record the region at `start` holding `data` as verified
+    pub fn record(&self, start: u64, data: &[u8]) -> Fallible<()> {
+        let line = format!("{} {}\n", start, digest(data));
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// The start and digest of the region of a complete line
+fn parse_region(line: &str) -> Option<(u64, &str)> {
+    let line = line.strip_suffix('\n')?;
+    let mut fields = line.split(' ');
+    let start = fields.next()?.parse().ok()?;
+    match (fields.next(), fields.next()) {
+        (Some(digest), None) if digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Some((start, digest))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,24 @@
+//! Detection of the errors network filesystems return transiently, which a retry may resolve
+
+use failure::Error;
+use std::io;
+use std::time::Duration;
+
+/// E.g. NFS file handles going stale or reads timing out while a server fails over
+const TRANSIENT_ERRNOS: &[i32] = &[libc::ESTALE, libc::EIO, libc::EAGAIN, libc::ETIMEDOUT, libc::ECONNRESET];
+/// Backoff before the first retry, doubled before each one after it
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The errno of the first transient error in the chain of `e`
+pub fn transient_errno(e: &Error) -> Option<i32> {
+    e.iter_chain().find_map(|cause| {
+        let errno = if let Some(e) = cause.downcast_ref::<io::Error>() {
+            e.raw_os_error()
+        } else if let Some(nix::Error::Sys(errno)) = cause.downcast_ref::<nix::Error>() {
+            Some(*errno as i32)
+        } else {
+            None
+        };
+        errno.filter(|errno| TRANSIENT_ERRNOS.contains(errno))
+    })
+}
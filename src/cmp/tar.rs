@@ -0,0 +1,455 @@
+//! Comparison of a directory with the tree a tar archive extracts to, read from the archive in place
+//!
+//! Archives in the ustar format are read, along with the GNU and pax extensions for long paths and sizes.
+//! Ownership and timestamps aren't compared, hard links in the archive compare as the entries they link to, and
+//! sockets, which tar can't store, are left out of the directory's side. Directories the archive only implies by
+//! the paths of their entries are compared by their entries alone.
+
+use super::{Comparison, Decision, Diff, FSCmp, BLOCK_SIZE, BUF_SIZE, BUF_SIZE_U64};
+use crate::range_chunks::ChunkableRange;
+use failure::{bail, format_err, Fallible, ResultExt};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+const HEADER_SIZE: u64 = 512;
+
+#[derive(Clone, Debug)]
+struct TarEntry {
+    /// Permissions along with the file type bits
+    mode: u32,
+    size: u64,
+    rdev: u64,
+    link_target: Option<PathBuf>,
+    /// Offset of the contents in the archive
+    data_offset: u64,
+}
+
+struct Archive {
+    file: File,
+    entries: BTreeMap<PathBuf, TarEntry>,
+    /// Names in each directory, including the directories only implied by paths
+    children: BTreeMap<PathBuf, BTreeSet<OsString>>,
+}
+
+impl Archive {
+    fn open(path: &Path) -> Fallible<Self> {
+        let file = File::open(path).with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+        let archive_size = file.metadata()?.len();
+        let mut entries = BTreeMap::new();
+        let mut hard_links = Vec::new();
+        // Set by extension headers for the entry following them
+        let (mut long_name, mut long_link, mut long_size) = (None, None, None);
+        let mut header = [0; HEADER_SIZE as usize];
+        let mut offset = 0;
+        while offset + HEADER_SIZE <= archive_size {
+            file.read_exact_at(&mut header, offset)
+                .with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if number(&header[148..156]) != Some(checksum(&header)) {
+                bail!("\"{}\": Invalid tar header at offset {}", path.display(), offset);
+            }
+            let type_flag = header[156];
+            let is_extension = [b'L', b'K', b'x', b'g'].contains(&type_flag);
+            let mut size = number(&header[124..136]).ok_or_else(|| {
+                format_err!(
+                    "\"{}\": Invalid size in tar header at offset {}",
+                    path.display(),
+                    offset
+                )
+            })?;
+            if !is_extension {
+                size = long_size.take().unwrap_or(size);
+            }
+            let data_offset = offset + HEADER_SIZE;
+            if size > archive_size - data_offset {
+                bail!(
+                    "\"{}\": Tar entry at offset {} of {} bytes ends past the archive's end",
+                    path.display(),
+                    offset,
+                    size
+                );
+            }
+            offset = data_offset + size.div_ceil(HEADER_SIZE) * HEADER_SIZE;
+            let read_data = || -> Fallible<Vec<u8>> {
+                let mut data = vec![0; size as usize];
+                file.read_exact_at(&mut data, data_offset)
+                    .with_context(|e| format!("\"{}\": {}", path.display(), e))?;
+                Ok(data)
+            };
+
+            match type_flag {
+                b'L' => long_name = Some(trim_nul(&read_data()?).to_vec()),
+                b'K' => long_link = Some(trim_nul(&read_data()?).to_vec()),
+                b'x' => {
+                    for (key, value) in pax_records(&read_data()?) {
+                        match key {
+                            b"path" => long_name = Some(value.to_vec()),
+                            b"linkpath" => long_link = Some(value.to_vec()),
+                            b"size" => long_size = std::str::from_utf8(value).ok().and_then(|s| s.parse().ok()),
+                            _ => (),
+                        }
+                    }
+                }
+                b'g' => (),
+                _ => {
+                    let name = long_name.take().unwrap_or_else(|| {
+                        let name = trim_nul(&header[..100]);
+                        let prefix = trim_nul(&header[345..500]);
+                        // Old GNU archives keep other fields where POSIX ones keep the prefix
+                        if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                            [prefix, b"/", name].concat()
+                        } else {
+                            name.to_vec()
+                        }
+                    });
+                    let link = long_link.take().unwrap_or_else(|| trim_nul(&header[157..257]).to_vec());
+                    let entry_path = normalize(&name);
+                    let file_type = match type_flag {
+                        b'0' | b'\0' | b'7' if name.ends_with(b"/") => libc::S_IFDIR,
+                        b'0' | b'\0' | b'7' => libc::S_IFREG,
+                        b'1' => {
+                            hard_links.push((entry_path, normalize(&link)));
+                            continue;
+                        }
+                        b'2' => libc::S_IFLNK,
+                        b'3' => libc::S_IFCHR,
+                        b'4' => libc::S_IFBLK,
+                        b'5' => libc::S_IFDIR,
+                        b'6' => libc::S_IFIFO,
+                        _ => bail!(
+                            "\"{}\": Unsupported type '{}' of tar entry \"{}\"",
+                            path.display(),
+                            type_flag as char,
+                            entry_path.display()
+                        ),
+                    };
+                    let device_number = |field: &[u8]| number(field).unwrap_or(0);
+                    let entry = TarEntry {
+                        mode: number(&header[100..108]).unwrap_or(0) as u32 & 0o7777 | file_type,
+                        size: if file_type == libc::S_IFREG { size } else { 0 },
+                        rdev: nix::sys::stat::makedev(
+                            device_number(&header[329..337]),
+                            device_number(&header[337..345]),
+                        ),
+                        link_target: if file_type == libc::S_IFLNK {
+                            Some(PathBuf::from(OsStr::from_bytes(&link)))
+                        } else {
+                            None
+                        },
+                        data_offset,
+                    };
+                    entries.insert(entry_path, entry);
+                }
+            }
+        }
+
+        for (link_path, target) in hard_links {
+            let entry = entries.get(&target).cloned().ok_or_else(|| {
+                format_err!(
+                    "\"{}\": Tar entry \"{}\" links to missing \"{}\"",
+                    path.display(),
+                    link_path.display(),
+                    target.display()
+                )
+            })?;
+            entries.insert(link_path, entry);
+        }
+
+        let mut children: BTreeMap<PathBuf, BTreeSet<OsString>> = BTreeMap::new();
+        children.entry(PathBuf::new()).or_default();
+        for entry_path in entries.keys() {
+            let mut child = entry_path.as_path();
+            while let (Some(parent), Some(name)) = (child.parent(), child.file_name()) {
+                children
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .insert(name.to_os_string());
+                child = parent;
+            }
+        }
+        Ok(Archive {
+            file,
+            entries,
+            children,
+        })
+    }
+}
+
+/// A field up to its first NUL
+fn trim_nul(field: &[u8]) -> &[u8] {
+    field.split(|&b| b == 0).next().unwrap()
+}
+
+/// A numeric field, in octal or in GNU's base-256 encoding
+fn number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        return Some(field[1..].iter().fold(0, |n, &b| n << 8 | u64::from(b)));
+    }
+    let digits = std::str::from_utf8(trim_nul(field)).ok()?.trim();
+    if digits.is_empty() {
+        Some(0)
+    } else {
+        u64::from_str_radix(digits, 8).ok()
+    }
+}
+
+/// Sum of the header's bytes, its checksum field counting as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum()
+}
+
+/// The key and value of each "<length> <key>=<value>\n" record of a pax extended header
+fn pax_records(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut records = Vec::new();
+    while let Some(space) = data.iter().position(|&b| b == b' ') {
+        let len = match std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+        {
+            Some(len) if len > space + 1 && len <= data.len() => len,
+            _ => break,
+        };
+        let record = &data[space + 1..len - 1];
+        if let Some(equals) = record.iter().position(|&b| b == b'=') {
+            records.push((&record[..equals], &record[equals + 1..]));
+        }
+        data = &data[len..];
+    }
+    records
+}
+
+/// An entry's path relative to the archive's root, the root itself being empty
+fn normalize(name: &[u8]) -> PathBuf {
+    Path::new(OsStr::from_bytes(name))
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+impl FSCmp {
+    /// Compare the first path, a directory, with the tree the second path, a tar archive, extracts to
+    pub fn dir_with_tar(&self) -> Fallible<Comparison> {
+        let archive = Archive::open(&self.second)?;
+        self.tar_entry_eq(&archive, Path::new(""))
+    }
+
+    fn tar_entry_eq(&self, archive: &Archive, path: &Path) -> Fallible<Comparison> {
+        self.check_interrupted()?;
+        let full_path = self.first.join(path);
+        let metadata =
+            fs::symlink_metadata(&full_path).with_context(|e| format!("\"{}\": {}", full_path.display(), e))?;
+        self.entries_compared.fetch_add(1, Ordering::Relaxed);
+        let file_type = metadata.mode() & libc::S_IFMT;
+
+        let mut comp = Comparison::Equal;
+        match archive.entries.get(path) {
+            Some(entry) => {
+                let entry_type = entry.mode & libc::S_IFMT;
+                if file_type != entry_type {
+                    return Ok(self.tar_unequal(Diff::Types(file_type, entry_type), path, file_type));
+                }
+                if !self.content_only
+                    && (!path.as_os_str().is_empty() || self.compare_root_meta)
+                    && metadata.mode() != entry.mode
+                {
+                    let check = self.tar_unequal(Diff::Modes(metadata.mode(), entry.mode), path, file_type);
+                    if self.merge_tar(&mut comp, check) {
+                        return Ok(comp);
+                    }
+                }
+                let check = match file_type {
+                    libc::S_IFREG => self.tar_contents_eq(archive, entry, &full_path, metadata.len(), path)?,
+                    libc::S_IFLNK => {
+                        let target = fs::read_link(&full_path)
+                            .with_context(|e| format!("\"{}\": {}", full_path.display(), e))?;
+                        match &entry.link_target {
+                            Some(entry_target) if *entry_target != target => {
+                                self.tar_unequal(Diff::LinkTarget(target, entry_target.clone()), path, file_type)
+                            }
+                            _ => Comparison::Equal,
+                        }
+                    }
+                    libc::S_IFBLK | libc::S_IFCHR if metadata.rdev() != entry.rdev => {
+                        self.tar_unequal(Diff::DeviceTypes(metadata.rdev(), entry.rdev), path, file_type)
+                    }
+                    _ => Comparison::Equal,
+                };
+                if self.merge_tar(&mut comp, check) {
+                    return Ok(comp);
+                }
+            }
+            None if file_type != libc::S_IFDIR => {
+                return Ok(self.tar_unequal(Diff::Types(file_type, libc::S_IFDIR), path, file_type));
+            }
+            None => (),
+        }
+
+        if file_type == libc::S_IFDIR {
+            let check = self.tar_dir_eq(archive, &full_path, path)?;
+            self.merge_tar(&mut comp, check);
+        }
+        match &comp {
+            Comparison::Equal => self.decided(Some(tar_path(path)), Some(file_type), Decision::Equal, None),
+            Comparison::Unequal { path: diff_path, .. } => {
+                // Directories differing only under them are recorded as well, as no decision is made about them
+                if let Some(audit_log) = &self.audit_log {
+                    if file_type == libc::S_IFDIR && diff_path.as_deref() != Some(tar_path(path)) {
+                        audit_log.record(tar_path(path), Some(file_type), Decision::Unequal, None);
+                    }
+                }
+            }
+        }
+        Ok(comp)
+    }
+
+    fn tar_dir_eq(&self, archive: &Archive, full_path: &Path, path: &Path) -> Fallible<Comparison> {
+        let is_ignored = |name: &OsStr| {
+            self.ignored_dirs.contains(Path::new(name))
+                || (!self.ignored_paths.is_empty() && self.ignored_paths.contains(&path.join(name)))
+        };
+        let mut names = HashSet::new();
+        for dir_entry in fs::read_dir(full_path).with_context(|e| format!("\"{}\": {}", full_path.display(), e))? {
+            let dir_entry = dir_entry?;
+            let name = dir_entry.file_name();
+            if is_ignored(&name) {
+                let file_type = dir_entry.metadata()?.mode() & libc::S_IFMT;
+                self.decided(Some(&path.join(&name)), Some(file_type), Decision::Ignored, None);
+            } else if !dir_entry.file_type()?.is_socket() {
+                names.insert(PathBuf::from(name));
+            }
+        }
+        let archive_names: HashSet<PathBuf> = archive
+            .children
+            .get(path)
+            .into_iter()
+            .flatten()
+            .filter(|name| !is_ignored(name))
+            .map(PathBuf::from)
+            .collect();
+
+        let mut comp = Comparison::Equal;
+        if names != archive_names {
+            let diff = Diff::DirContents(names.clone(), archive_names.clone());
+            let check = self.tar_unequal(diff, path, libc::S_IFDIR);
+            if self.merge_tar(&mut comp, check) {
+                return Ok(comp);
+            }
+        }
+        let mut common: Vec<_> = names.intersection(&archive_names).collect();
+        common.sort();
+        for name in common {
+            let check = self.tar_entry_eq(archive, &path.join(name))?;
+            if self.merge_tar(&mut comp, check) {
+                return Ok(comp);
+            }
+        }
+        Ok(comp)
+    }
+
+    fn tar_contents_eq(
+        &self,
+        archive: &Archive,
+        entry: &TarEntry,
+        full_path: &Path,
+        size: u64,
+        path: &Path,
+    ) -> Fallible<Comparison> {
+        if size != entry.size {
+            return Ok(self.tar_unequal(Diff::Sizes(size as i64, entry.size as i64), path, libc::S_IFREG));
+        }
+        let file = File::open(full_path).with_context(|e| format!("\"{}\": {}", full_path.display(), e))?;
+        let (mut first, mut second) = (vec![0; BUF_SIZE], vec![0; BUF_SIZE]);
+        for chunk in (0..size).chunks(BUF_SIZE_U64) {
+            self.check_interrupted()?;
+            let len = (chunk.end - chunk.start) as usize;
+            let (first, second) = (&mut first[..len], &mut second[..len]);
+            file.read_exact_at(first, chunk.start)
+                .with_context(|e| format!("\"{}\": {}", full_path.display(), e))?;
+            archive
+                .file
+                .read_exact_at(second, entry.data_offset + chunk.start)
+                .with_context(|e| format!("\"{}\": {}", self.second.display(), e))?;
+            self.bytes_read.fetch_add(2 * len as u64, Ordering::Relaxed);
+            if let Some(diff_index) = first.iter().zip(second.iter()).position(|(a, b)| a != b) {
+                let (block1, block2) = self.diff_blocks(first, second, diff_index / BLOCK_SIZE * BLOCK_SIZE);
+                let offset = chunk.start + diff_index as u64;
+                let lba = offset / BLOCK_SIZE as u64;
+                let diff = Diff::Contents(lba, offset, block1, block2);
+                return Ok(self.tar_unequal(diff, path, libc::S_IFREG));
+            }
+        }
+        Ok(Comparison::Equal)
+    }
+
+    fn tar_unequal(&self, diff: Diff, path: &Path, file_type: u32) -> Comparison {
+        self.report(diff, Some(tar_path(path).to_path_buf()), Some(file_type))
+    }
+
+    /// Keep the first difference in `comp`, returning whether the comparison stops at `check`, as it does unless
+    /// all differences are reported
+    fn merge_tar(&self, comp: &mut Comparison, check: Comparison) -> bool {
+        if check == Comparison::Equal {
+            return false;
+        }
+        if *comp == Comparison::Equal {
+            *comp = check;
+        }
+        !self.report_all
+    }
+}
+
+/// The path an entry is reported at, "." for the root which is empty in the archive
+fn tar_path(path: &Path) -> &Path {
+    if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pax_records() {
+        assert_eq!(
+            pax_records(b"13 path=name\n11 size=10\n"),
+            vec![(&b"path"[..], &b"name"[..]), (&b"size"[..], &b"10"[..])]
+        );
+        assert!(pax_records(b"2 ").is_empty());
+        assert!(pax_records(b"99 path=name\n").is_empty());
+    }
+
+    #[test]
+    fn test_truncated_archive() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("archive.tar");
+        let mut header = [0; HEADER_SIZE as usize];
+        header[..4].copy_from_slice(b"file");
+        header[124..136].copy_from_slice(b"77777777777\0");
+        header[156] = b'0';
+        let sum = format!("{:06o}\0 ", checksum(&header));
+        header[148..156].copy_from_slice(sum.as_bytes());
+        fs::write(&path, &header[..])?;
+        assert!(Archive::open(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use nix::errno::Errno;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// The value of an extended attribute of an open file, `None` if it isn't set or xattrs aren't supported
+pub fn get(file: &File, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let name = CString::new(name)?;
+    loop {
+        let size = unsafe { libc::fgetxattr(file.as_raw_fd(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return none_if_missing(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            match io::Error::last_os_error() {
+                // Grew since its size was queried
+                e if e.raw_os_error() == Some(Errno::ERANGE as i32) => continue,
+                e => return none_if_missing(e),
+            }
+        }
+        value.truncate(size as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Whether an extended attribute is set on `path` relative to the directory `dir_fd`, without opening it or following
+/// it if it's a symbolic link
+pub fn is_set_at(dir_fd: RawFd, path: &Path, name: &str) -> io::Result<bool> {
+    let path = CString::new(proc_path(dir_fd, path).as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return none_if_missing(io::Error::last_os_error()).map(|value| value.is_some());
+    }
+    Ok(true)
+}
+
+/// The names of the extended attributes set on `path` relative to the directory `dir_fd`, without following it if it's
+/// a symbolic link, none if xattrs aren't supported
+pub fn list_at(dir_fd: RawFd, path: &Path) -> io::Result<Vec<String>> {
+    let path = CString::new(proc_path(dir_fd, path).as_os_str().as_bytes())?;
+    loop {
+        let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return none_if_missing(io::Error::last_os_error()).map(|_| vec![]);
+        }
+        let mut names = vec![0u8; size as usize];
+        let size =
+            unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+        if size < 0 {
+            match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(Errno::ERANGE as i32) => continue,
+                e => return none_if_missing(e).map(|_| vec![]),
+            }
+        }
+        names.truncate(size as usize);
+        return Ok(names
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect());
+    }
+}
+
+/// The value of an extended attribute of `path` relative to the directory `dir_fd`, without following it if it's a
+/// symbolic link, `None` if it isn't set
+pub fn get_at(dir_fd: RawFd, path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = CString::new(proc_path(dir_fd, path).as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    loop {
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return none_if_missing(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(Errno::ERANGE as i32) => continue,
+                e => return none_if_missing(e),
+            }
+        }
+        value.truncate(size as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// There's no l*xattr() relative to a directory, so the directory is reached through its file descriptor
+fn proc_path(dir_fd: RawFd, path: &Path) -> PathBuf {
+    Path::new(&format!("/proc/self/fd/{}", dir_fd)).join(path)
+}
+
+fn none_if_missing(e: io::Error) -> io::Result<Option<Vec<u8>>> {
+    match e.raw_os_error() {
+        Some(errno) if errno == libc::ENODATA || errno == libc::ENOTSUP => Ok(None),
+        _ => Err(e),
+    }
+}
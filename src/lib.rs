@@ -0,0 +1,14 @@
+//! Comparison of files, block devices and directory trees
+
+mod cmp;
+pub mod range_chunks;
+
+#[cfg(feature = "structured-log")]
+pub use crate::cmp::DECISION_LOG_TARGET;
+pub use crate::cmp::{
+    decode_binary, hex, Comparison, ComparisonDisplay, ComparisonStats, Decision, Diff, DiffSummary,
+    DisplayOptions, EntryDecision, FSCmp, FsCmpError, OnelineDisplay, Progress, BINARY_FORMAT_VERSION,
+    DIFF_QUEUE_SIZE, EQUAL_LOG_TARGET, WARNING_LOG_TARGET,
+};
+#[cfg(unix)]
+pub use crate::cmp::{HashAlgorithm, DEFAULT_FIFO_TIMEOUT};
@@ -1,21 +1,38 @@
-mod cmp;
-
-use crate::cmp::{Comparison, FSCmp};
+#[cfg(unix)]
+use fscmp::DiffSummary;
+#[cfg(all(unix, feature = "simplelog"))]
+use fscmp::EQUAL_LOG_TARGET;
+#[cfg(unix)]
+use fscmp::{hex, HashAlgorithm};
+use fscmp::{Comparison, DisplayOptions, FSCmp, WARNING_LOG_TARGET};
 use log::error;
 #[cfg(unix)]
+use log::info;
+use log::warn;
+#[cfg(unix)]
 use std::collections::HashSet;
 #[cfg(feature = "simplelog")]
 use std::ffi::{OsStr, OsString};
 #[cfg(feature = "simplelog")]
 use std::fs::File;
+use std::io::{self, IsTerminal, Write};
 #[cfg(unix)]
 use std::iter::FromIterator;
-#[cfg(feature = "simplelog")]
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(any(unix, feature = "simplelog"))]
 use std::path::Path;
 use std::path::PathBuf;
 use std::process;
+use std::str::FromStr;
+#[cfg(unix)]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use structopt::StructOpt;
 
+/// Interval of --progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
 #[cfg(feature = "simplelog")]
 fn parse_log_dir(src: &OsStr) -> Result<PathBuf, OsString> {
     let path = Path::new(src);
@@ -26,13 +43,117 @@ fn parse_log_dir(src: &OsStr) -> Result<PathBuf, OsString> {
     }
 }
 
+fn parse_hex_width(src: &str) -> Result<usize, String> {
+    match src.parse() {
+        Ok(0) => Err("Hex width must be positive".into()),
+        Ok(width) => Ok(width),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+#[cfg(unix)]
+fn parse_percent(src: &str) -> Result<f64, String> {
+    match src.parse() {
+        Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(percent),
+        Ok(_) => Err("Percentage must be more than 0 and at most 100".into()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn parse_hex_byte(src: &str) -> Result<u8, std::num::ParseIntError> {
+    u8::from_str_radix(src.trim_start_matches("0x"), 16)
+}
+
+/// A label of one of the arguments, as `first=<label>` or `second=<label>`
+#[derive(Debug, Clone)]
+enum Label {
+    First(PathBuf),
+    Second(PathBuf),
+}
+
+impl FromStr for Label {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("first", label)) => Ok(Label::First(label.into())),
+            Some(("second", label)) => Ok(Label::Second(label.into())),
+            _ => Err(format!("Label \"{}\" must be first=<label> or second=<label>", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Text,
+    Ndjson,
+    Oneline,
+    Binary,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "ndjson" => Ok(Format::Ndjson),
+            "oneline" => Ok(Format::Oneline),
+            "binary" => Ok(Format::Binary),
+            _ => Err(format!("Unknown format \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(format!("Unknown color mode \"{}\"", s)),
+        }
+    }
+}
+
+impl Format {
+    /// Output a difference, text goes to stderr while machine readable formats go to stdout
+    fn print(self, comp: &Comparison, options: DisplayOptions) {
+        match self {
+            Format::Text => eprintln!("{}", comp.display(options)),
+            Format::Ndjson => match serde_json::to_string(comp) {
+                Ok(line) => println!("{}", line),
+                Err(e) => error!("Failed to serialize {:?}: {}", comp, e),
+            },
+            Format::Oneline => println!("{}", comp.oneline()),
+            Format::Binary => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                if let Err(e) = comp.write_binary(&mut stdout).and_then(|_| stdout.flush()) {
+                    error!("Failed to write {:?}: {}", comp, e);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(about)]
 /// Directory/file comparison utility
 struct Opt {
     #[cfg(feature = "simplelog")]
     #[structopt(long, parse(try_from_os_str = parse_log_dir))]
-    /// Directory to store log(s) in
+    /// Directory to store log(s) in. If it's in a compared tree, it's left out of the comparison, though it still
+    /// counts towards the link count of the directory holding it, which --ignore-nlink ignores
     log_dir: Option<PathBuf>,
 
     #[structopt(long)]
@@ -45,26 +166,531 @@ struct Opt {
     /// Compare arguments using specified size (used for block devices) - mandatory on Windows, as only file-to-file comparison is currently supported
     content_size: u64,
 
+    #[structopt(long, requires = "content-size", conflicts_with_all = &["first-offset", "second-offset"])]
+    /// Byte offset in both arguments to compare --content-size bytes from, a multiple of the 512 bytes block size,
+    /// differing blocks still being numbered from the start
+    offset: Option<u64>,
+
+    #[structopt(long, requires = "content-size", conflicts_with_all = &["expect-zero", "expect-pattern"])]
+    /// Byte offset in the first argument to compare --content-size bytes from, a multiple of the 512 bytes block
+    /// size
+    first_offset: Option<u64>,
+
+    #[structopt(long, requires = "content-size", conflicts_with_all = &["expect-zero", "expect-pattern"])]
+    /// Byte offset in the second argument to compare --content-size bytes from, a multiple of the 512 bytes block
+    /// size
+    second_offset: Option<u64>,
+
+    #[structopt(long, parse(from_os_str), requires = "content-size", conflicts_with_all = &["expect-zero", "expect-pattern"])]
+    /// File recording the regions found equal so far, for resuming an interrupted comparison of --content-size bytes
+    resume_file: Option<PathBuf>,
+
     #[structopt(long)]
     #[cfg(unix)]
     /// Size in bytes to limit full compare (larger files will be sampled)
     full_compare_limit: Option<u64>,
 
+    #[structopt(long, parse(try_from_str = parse_percent))]
+    #[cfg(unix)]
+    /// Percentage of each file's size to limit full compare to, the lesser limit applying along with
+    /// --full-compare-limit
+    sample_percent: Option<f64>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Number of chunks to sample evenly from each file, overriding --full-compare-limit
+    sample_chunks: Option<u64>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Size in bytes of the smallest files to compare the contents of, smaller ones only having their metadata
+    /// compared
+    min_size: Option<u64>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Size in bytes of the largest files to compare the contents of, larger ones only having their metadata
+    /// compared
+    max_size: Option<u64>,
+
     #[structopt(long = "ignore-dir", number_of_values = 1)]
     #[cfg(unix)]
     /// Directories to ignore when comparing
     ignored_dirs: Vec<PathBuf>,
 
+    #[structopt(long = "ignore-path", number_of_values = 1)]
+    #[cfg(unix)]
+    /// Paths relative to the compared roots to ignore
+    ignored_paths: Vec<PathBuf>,
+
+    #[structopt(long = "allow-diff", number_of_values = 1)]
+    #[cfg(unix)]
+    /// Paths relative to the compared roots whose differences are only warned about, not failing the comparison
+    /// (an entry missing on one side differs at its directory's path, and ignored paths aren't compared at all)
+    allowed_diffs: Vec<PathBuf>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Ignore entries matching gitignore-style rules of .fscmpignore files in the compared trees
+    ignore_files: bool,
+
+    #[structopt(long = "include-glob", number_of_values = 1)]
+    #[cfg(unix)]
+    /// Compare only entries matching these gitignore-style globs, still looking into all directories, ignored
+    /// entries staying ignored
+    include_globs: Vec<String>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare owners by user and group names instead of numeric IDs
+    owner_by_name: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    #[cfg(unix)]
+    /// Compare only this path relative to both arguments
+    subpath: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    #[cfg(unix)]
+    /// Skip entries other than directories which are unchanged in both arguments relative to this reference
+    /// directory, going by their mode, owners, size, modification time and device number
+    reference: Option<PathBuf>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Match directory entries by case folded names, the Turkic way when the locale is Turkish or Azerbaijani
+    ignore_case: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare the mode and ownership of the arguments themselves, not just of what's in them
+    compare_root_meta: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare modification times too
+    compare_mtimes: bool,
+
+    #[structopt(long, requires = "compare-mtimes")]
+    #[cfg(unix)]
+    /// Compare modification times only of regular files and symbolic links, skipping directories
+    timestamps_files_only: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare preferred I/O block sizes too, which reflect the filesystems rather than the files' contents
+    compare_blksize: bool,
+
+    #[structopt(long, default_value = "0")]
+    #[cfg(unix)]
+    /// Retry operations failing with errors which network filesystems return transiently, e.g. ESTALE, up to this
+    /// many times with exponential backoff
+    retries: u32,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Don't track inodes, saving memory on huge trees but comparing hard linked files at each of their paths and
+    /// missing hard link differences
+    no_inode_tracking: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Don't compare link counts, e.g. against copies made without preserving hard links. Hard links are still
+    /// compared unless --no-inode-tracking is given too
+    ignore_nlink: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Report files which only differ by trailing zeroes in the longer one as such, rather than by their sizes
+    ignore_trailing_zeros: bool,
+
+    #[structopt(long, requires = "ignore-trailing-zeros")]
+    #[cfg(unix)]
+    /// Consider files which only differ by trailing zeroes equal
+    trailing_zeros_equal: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare only file contents and directory entries, ignoring mode, ownership and hard link count
+    content_only: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Warn about FIFOs and sockets, whose contents aren't compared (FIFOs unless --read-fifos is given)
+    strict_special: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare FIFOs by reading up to this many bytes from both, consuming the data written to them (e.g. by test
+    /// harnesses)
+    read_fifos: Option<u64>,
+
+    #[structopt(long, default_value = "10")]
+    #[cfg(unix)]
+    /// Seconds to wait for data when reading FIFOs with --read-fifos before failing
+    fifo_timeout: u64,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Compare inode flags (as set by chattr) of regular files and directories
+    compare_flags: bool,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Compare whether regular files and directories are marked for transparent compression (e.g. on Btrfs),
+    /// ignored where the filesystem doesn't support it
+    compare_compression: bool,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Compare extended attributes of entries
+    compare_xattrs: bool,
+
+    #[structopt(long = "ignore-xattr", number_of_values = 1, requires = "compare-xattrs")]
+    #[cfg(target_os = "linux")]
+    /// Extended attributes to leave out of their comparison, by name or glob (e.g. "security.selinux", "user.*")
+    ignored_xattrs: Vec<String>,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Check that identical regular files also share their physical extents (reflinks or deduplication)
+    compare_extents: bool,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Check that identical regular files also have their holes at the same offsets (SEEK_HOLE/SEEK_DATA)
+    compare_sparse_map: bool,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Compare OverlayFS upper layers, reporting whiteouts and opaque directories
+    overlay_aware: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Skip entries present on only one side, so trees are only known to agree on their common entries
+    missing_ok: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Report the first difference in path order, for reproducible output
+    deterministic: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Fail on files which shrink while being compared, instead of comparing their sizes again
+    strict_stable: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Log content differences of files modified since the comparison started instead of reporting them, e.g. when
+    /// comparing a live source with its snapshot
+    ignore_changed: bool,
+
+    #[cfg_attr(not(feature = "blake3"), structopt(long, possible_values = &["sha256", "sha512"]))]
+    #[cfg_attr(feature = "blake3", structopt(long, possible_values = &["sha256", "sha512", "blake3"]))]
+    #[cfg(unix)]
+    /// Report hashes of the whole contents of differing files with this algorithm, instead of the first differing
+    /// block
+    hash_on_mismatch: Option<HashAlgorithm>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Compare the order in which directories list their entries, e.g. to check tar/cpio extraction is reproducible
+    compare_dir_order: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Don't warn when comparing a path to itself
+    allow_same: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Warn about directories which are mount points in only one of the compared trees
+    warn_mounts: bool,
+
+    #[structopt(long)]
+    #[cfg(target_os = "linux")]
+    /// Warn if an entry has an ACL, as then comparing modes doesn't compare all permissions
+    warn_acls: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Report every difference instead of stopping at the first one
+    report_all: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Log the path of every entry compared equal to stderr (and to the log file, if any)
+    log_equal: bool,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Log how much of each file compared equal by sampling was read and at which offsets, to stderr (and to the
+    /// log file, if any)
+    log_coverage: bool,
+
+    #[structopt(long, parse(from_os_str), conflicts_with = "count-entries")]
+    #[cfg(unix)]
+    /// Record every entry examined in this file as JSON objects, one per line, with its type and what was decided
+    /// about it, including the entries which weren't compared and why. Counting entries examines none of them
+    audit_log: Option<PathBuf>,
+
+    #[structopt(long)]
+    #[cfg(unix)]
+    /// Print only differences and errors, leaving warnings and the summary to the log
+    quiet: bool,
+
+    #[cfg_attr(unix, structopt(long, conflicts_with_all = &["report-all", "hash-on-mismatch", "digest"]))]
+    #[cfg_attr(windows, structopt(long))]
+    /// Print only "equal" or "unequal" instead of the difference, skipping the work of describing it
+    summary_only: bool,
+
+    #[structopt(long)]
+    /// Compare contents in order and stop at the first difference, reading less but without parallelism
+    first_diff_only: bool,
+
+    #[structopt(long)]
+    /// Print progress and the time left every few seconds, counted in entries for trees of mostly small files and
+    /// in bytes otherwise
+    progress: bool,
+
+    #[structopt(long, hidden = true)]
+    /// Read contents without comparing them, always reporting them equal, to measure read throughput only
+    bench_read_only: bool,
+
+    #[structopt(long)]
+    /// Open empty files too, failing on ones which can't be read instead of considering them equal
+    verify_openable: bool,
+
+    #[structopt(long, default_value = "0")]
+    /// Include this many blocks before and after a differing block, as far as the chunk read along with it goes
+    context_blocks: usize,
+
+    #[structopt(long, requires = "content-size")]
+    /// Compare only as much as both arguments have when --content-size is larger than either, warning instead of
+    /// failing
+    clamp_size: bool,
+
+    #[structopt(long)]
+    /// Fail if the comparison takes longer than this many seconds
+    timeout: Option<u64>,
+
+    #[structopt(long, default_value = "text", possible_values = &["text", "ndjson", "oneline", "binary"])]
+    /// Output format of differences, ndjson writes one JSON object per line to stdout, oneline a line of text per
+    /// difference and binary a length-prefixed record per difference
+    format: Format,
+
+    #[structopt(long, conflicts_with_all = &["expect-zero", "expect-pattern"])]
+    #[cfg(unix)]
+    /// Read both arguments sequentially (e.g. pipes), implied when either isn't seekable
+    stream: bool,
+
+    #[structopt(long, conflicts_with_all = &["content-size", "expect-zero", "expect-pattern", "stream"])]
+    #[cfg(all(target_os = "linux", feature = "tar"))]
+    /// Compare the first argument, a directory, with the tree the second argument, a tar archive, extracts to
+    tar: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["expect-zero", "expect-pattern", "stream", "digest", "count-entries", "report-all"]
+    )]
+    #[cfg(unix)]
+    /// Print how many bytes comparing would read from both sides, as limited or sampled, without comparing
+    estimate: bool,
+
+    #[structopt(long, conflicts_with_all = &["content-size", "expect-zero", "expect-pattern", "stream", "digest"])]
+    #[cfg(unix)]
+    /// Compare only how many entries are under each directory, reporting the first directory found to diverge
+    count_entries: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["content-size", "expect-zero", "expect-pattern", "stream", "count-entries", "report-all"]
+    )]
+    #[cfg(unix)]
+    /// Print the top-level entries of the trees by how many differences are under each, the most differing first
+    top_divergence: bool,
+
+    #[structopt(long, conflicts_with_all = &["content-size", "expect-zero", "expect-pattern", "stream"])]
+    #[cfg(target_os = "linux")]
+    /// Compare block devices up to their ends, reading their sizes from the devices instead of --content-size
+    whole_device: bool,
+
+    #[structopt(long, conflicts_with_all = &["second", "expect-zero", "expect-pattern", "stream"])]
+    #[cfg(unix)]
+    /// Print a digest of the first argument's tree instead of comparing, equal for identical trees
+    digest: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["second", "content-size", "expect-zero", "expect-pattern", "stream", "digest"]
+    )]
+    #[cfg(unix)]
+    /// Write a manifest of the first argument's tree and the hashes of its files to this file instead of comparing
+    write_manifest: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["content-size", "expect-zero", "expect-pattern", "stream", "digest", "count-entries"]
+    )]
+    #[cfg(unix)]
+    /// Verify the first argument's tree against the manifest given as the second argument, reading the tree once
+    verify_manifest: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &["second", "content-size", "expect-zero", "expect-pattern", "stream", "digest", "verify-manifest"]
+    )]
+    #[cfg(unix)]
+    /// Verify the first argument's tree against a checksum list as written by sha256sum or sha512sum
+    check_sums: Option<PathBuf>,
+
+    #[structopt(long, requires = "check-sums")]
+    #[cfg(unix)]
+    /// Also report the files of the tree missing from the checksum list
+    strict: bool,
+
+    #[structopt(long, requires = "content-size", conflicts_with_all = &["second", "expect-pattern"])]
+    /// Compare the first argument against zeroes instead of a second argument
+    expect_zero: bool,
+
+    #[structopt(long, requires = "content-size", conflicts_with = "second", parse(try_from_str = parse_hex_byte))]
+    /// Compare the first argument against a constant byte (in hex) instead of a second argument
+    expect_pattern: Option<u8>,
+
+    #[structopt(long, default_value = "32", parse(try_from_str = parse_hex_width))]
+    /// Bytes per line when printing differing blocks
+    hex_width: usize,
+
+    #[structopt(long = "label", number_of_values = 1)]
+    /// Show differences with a label instead of an argument's path, as first=<label> or second=<label>
+    labels: Vec<Label>,
+
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "always", "never"])]
+    /// Highlight metadata differences in text output, by default when it goes to a terminal
+    color: Color,
+
     #[structopt(parse(from_os_str), required = true)]
+    /// First path to compare. Remote trees aren't read directly, but can be compared once mounted, e.g. with sshfs
     first: PathBuf,
 
-    #[structopt(parse(from_os_str), required = true)]
-    second: PathBuf,
+    #[cfg_attr(
+        unix,
+        structopt(parse(from_os_str), required_unless_one = &["expect-zero", "expect-pattern", "digest", "write-manifest", "check-sums"])
+    )]
+    #[cfg_attr(
+        windows,
+        structopt(parse(from_os_str), required_unless_one = &["expect-zero", "expect-pattern"])
+    )]
+    second: Option<PathBuf>,
 }
 
-fn run() -> failure::Fallible<Comparison> {
-    let opt = Opt::from_args();
+/// Writes the bare messages of equal entries to stderr, the rest of the log is too noisy for a terminal
+#[cfg(all(unix, feature = "simplelog"))]
+struct EqualLogger;
+
+#[cfg(all(unix, feature = "simplelog"))]
+impl log::Log for EqualLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == EQUAL_LOG_TARGET && metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(all(unix, feature = "simplelog"))]
+impl simplelog::SharedLogger for EqualLogger {
+    fn level(&self) -> log::LevelFilter {
+        log::LevelFilter::Info
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        self
+    }
+}
 
+/// Writes the warnings meant for the user to stderr, unless --quiet leaves them to the log file
+#[cfg(all(unix, feature = "simplelog"))]
+struct WarningLogger;
+
+#[cfg(all(unix, feature = "simplelog"))]
+impl log::Log for WarningLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.target() == WARNING_LOG_TARGET && metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("Warning: {}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(all(unix, feature = "simplelog"))]
+impl simplelog::SharedLogger for WarningLogger {
+    fn level(&self) -> log::LevelFilter {
+        log::LevelFilter::Warn
+    }
+
+    fn config(&self) -> Option<&simplelog::Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        self
+    }
+}
+
+/// The log directory's path relative to each compared root it's in, or the log file's if it's the root itself
+#[cfg(all(unix, feature = "simplelog"))]
+fn log_paths_within(log_dir: &Path, log_file: &Path, roots: &[&Path]) -> Vec<PathBuf> {
+    let log_dir = match log_dir.canonicalize() {
+        Ok(log_dir) => log_dir,
+        Err(_) => return Vec::new(),
+    };
+    roots
+        .iter()
+        .filter_map(|root| {
+            let root = root.canonicalize().ok()?;
+            let path = log_dir.strip_prefix(root).ok()?;
+            Some(if path.as_os_str().is_empty() {
+                PathBuf::from(log_file.file_name()?)
+            } else {
+                path.to_path_buf()
+            })
+        })
+        .collect()
+}
+
+/// Pipes and character devices can only be read sequentially
+#[cfg(unix)]
+fn is_stream(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_fifo() || metadata.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+#[cfg_attr(windows, allow(unused_variables))]
+fn run(
+    opt: Opt,
+    display_options: DisplayOptions,
+    #[cfg(unix)] summary: Arc<Mutex<DiffSummary>>,
+) -> failure::Fallible<Comparison> {
     #[cfg(feature = "loggest")]
     let mut _flush_log = loggest::init(
         log::LevelFilter::max(),
@@ -72,47 +698,288 @@ fn run() -> failure::Fallible<Comparison> {
     )
     .unwrap();
 
+    #[cfg(unix)]
+    #[cfg_attr(not(feature = "simplelog"), allow(unused_mut))]
+    let mut ignored_paths = HashSet::from_iter(opt.ignored_paths);
+
     #[cfg(feature = "simplelog")]
     {
-        if let Some(log_dir) = opt.log_dir {
+        let mut loggers: Vec<Box<dyn simplelog::SharedLogger>> = Vec::new();
+        // Warned about once the loggers are set up
+        #[cfg(unix)]
+        let mut log_paths = Vec::new();
+        if let Some(log_dir) = &opt.log_dir {
             let log_file = log_dir.join(format!("{}.{}.log", env!("CARGO_PKG_NAME"), process::id()));
-            simplelog::WriteLogger::init(
+            #[cfg(unix)]
+            {
+                let roots: Vec<&Path> = std::iter::once(&opt.first)
+                    .chain(&opt.second)
+                    .map(|p| p.as_path())
+                    .collect();
+                log_paths = log_paths_within(log_dir, &log_file, &roots);
+            }
+            loggers.push(simplelog::WriteLogger::new(
                 simplelog::LevelFilter::max(),
                 simplelog::ConfigBuilder::new()
                     .set_target_level(simplelog::LevelFilter::Off)
                     .set_time_format_str("%F %T%.3f")
                     .build(),
                 File::create(log_file)?,
-            )
-            .unwrap();
+            ));
+        }
+        #[cfg(unix)]
+        {
+            if opt.log_equal || opt.log_coverage {
+                loggers.push(Box::new(EqualLogger));
+            }
+            if !opt.quiet {
+                loggers.push(Box::new(WarningLogger));
+            }
+        }
+        if !loggers.is_empty() {
+            simplelog::CombinedLogger::init(loggers).unwrap();
+        }
+        #[cfg(unix)]
+        for path in log_paths {
+            warn!(
+                target: WARNING_LOG_TARGET,
+                "Ignoring \"{}\", as the log is written there",
+                path.display()
+            );
+            ignored_paths.insert(path);
         }
     }
 
+    if opt.bench_read_only {
+        warn!(
+            target: WARNING_LOG_TARGET,
+            "Contents are read but not compared, differences won't be reported"
+        );
+    }
+
+    let pattern = if opt.expect_zero { Some(0) } else { opt.expect_pattern };
+    #[cfg(unix)]
+    let second = opt.second.or(opt.check_sums.clone());
+    #[cfg(windows)]
+    let second = opt.second;
+    let second = match (second, pattern) {
+        (Some(second), _) => second,
+        (None, Some(pattern)) => format!("<pattern {:#04x}>", pattern).into(),
+        (None, None) => PathBuf::new(),
+    };
+
+    #[cfg(unix)]
+    let stream = pattern.is_none() && (opt.stream || is_stream(&opt.first) || is_stream(&second));
+
     let fscmp = FSCmp::new(
-        opt.first,
-        opt.second,
+        opt.first.clone(),
+        second,
         #[cfg(unix)]
         opt.full_compare_limit,
         #[cfg(unix)]
-        HashSet::from_iter(opt.ignored_dirs.into_iter()),
-    );
+        HashSet::from_iter(opt.ignored_dirs),
+    )
+    .with_first_diff_only(opt.first_diff_only)
+    .with_bench_read_only(opt.bench_read_only)
+    .with_verify_openable(opt.verify_openable)
+    .with_context_blocks(opt.context_blocks)
+    .with_clamp_size(opt.clamp_size)
+    .with_summary_only(opt.summary_only)
+    .with_resume_file(opt.resume_file)
+    .with_offsets(
+        opt.offset.or(opt.first_offset).unwrap_or(0),
+        opt.offset.or(opt.second_offset).unwrap_or(0),
+    )
+    .with_timeout(opt.timeout.map(Duration::from_secs));
+    let fscmp = opt.labels.iter().fold(fscmp, |fscmp, label| match label {
+        Label::First(label) => fscmp.with_first_label(label.clone()),
+        Label::Second(label) => fscmp.with_second_label(label.clone()),
+    });
+    let fscmp = if opt.progress {
+        fscmp.with_progress_handler(PROGRESS_INTERVAL, |progress| eprintln!("Progress: {}", progress))
+    } else {
+        fscmp
+    };
+    #[cfg(unix)]
+    let fscmp = fscmp
+        .with_ignored_paths(ignored_paths)
+        .with_allowed_diffs(HashSet::from_iter(opt.allowed_diffs))
+        .with_ignore_files(opt.ignore_files)
+        .with_include_globs(&opt.include_globs)?
+        .with_subpath(opt.subpath)
+        .with_reference(opt.reference)
+        .with_ignore_case(opt.ignore_case)
+        .with_owner_by_name(opt.owner_by_name)
+        .with_compare_root_meta(opt.compare_root_meta)
+        .with_compare_mtimes(opt.compare_mtimes)
+        .with_timestamps_files_only(opt.timestamps_files_only)
+        .with_compare_blksize(opt.compare_blksize)
+        .with_inode_tracking(!opt.no_inode_tracking)
+        .with_ignore_nlink(opt.ignore_nlink)
+        .with_retries(opt.retries)
+        .with_ignore_trailing_zeros(opt.ignore_trailing_zeros)
+        .with_trailing_zeros_equal(opt.trailing_zeros_equal)
+        .with_content_only(opt.content_only)
+        .with_sample_chunks(opt.sample_chunks)
+        .with_sample_percent(opt.sample_percent)
+        .with_min_size(opt.min_size)
+        .with_max_size(opt.max_size)
+        .with_strict_special(opt.strict_special)
+        .with_read_fifos(opt.read_fifos)
+        .with_fifo_timeout(Duration::from_secs(opt.fifo_timeout))
+        .with_missing_ok(opt.missing_ok)
+        .with_deterministic(opt.deterministic)
+        .with_compare_dir_order(opt.compare_dir_order)
+        .with_hash_on_mismatch(opt.hash_on_mismatch)
+        .with_strict_stable(opt.strict_stable)
+        .with_ignore_changed(opt.ignore_changed)
+        .with_allow_same(opt.allow_same)
+        .with_warn_mounts(opt.warn_mounts)
+        .with_log_equal(opt.log_equal)
+        .with_log_coverage(opt.log_coverage)
+        .with_audit_log(opt.audit_log.as_deref())?;
+    #[cfg(target_os = "linux")]
+    let fscmp = fscmp
+        .with_compare_flags(opt.compare_flags)
+        .with_compare_compression(opt.compare_compression)
+        .with_compare_xattrs(opt.compare_xattrs)
+        .with_ignored_xattrs(&opt.ignored_xattrs)?
+        .with_compare_extents(opt.compare_extents)
+        .with_compare_sparse_map(opt.compare_sparse_map)
+        .with_overlay_aware(opt.overlay_aware)
+        .with_warn_acls(opt.warn_acls);
+    #[cfg(unix)]
+    let fscmp = if opt.report_all {
+        let format = opt.format;
+        fscmp.with_report_all(true).with_diff_handler(move |comp| {
+            summary.lock().unwrap().add(comp);
+            format.print(comp, display_options)
+        })
+    } else {
+        fscmp
+    };
 
     #[cfg(windows)]
-    return Ok(fscmp.contents(opt.content_size)?);
+    return Ok(match pattern {
+        Some(pattern) => fscmp.contents_pattern(opt.content_size, pattern)?,
+        None => fscmp.contents(opt.content_size)?,
+    });
 
     #[cfg(unix)]
-    Ok(if let Some(content_size) = opt.content_size {
-        fscmp.contents(content_size)?
-    } else {
-        fscmp.dirs()?
+    {
+        if opt.digest {
+            let digest = fscmp.tree_digest(&opt.first)?;
+            println!("{}", hex(&digest));
+            return Ok(Comparison::Equal);
+        }
+        if let Some(manifest) = &opt.write_manifest {
+            fscmp.write_manifest(manifest)?;
+            return Ok(Comparison::Equal);
+        }
+        if opt.verify_manifest {
+            return fscmp.verify_manifest();
+        }
+        if opt.check_sums.is_some() {
+            return fscmp.check_sums(opt.strict);
+        }
+        if opt.estimate {
+            let bytes = match opt.content_size {
+                Some(content_size) => fscmp.estimate_contents(content_size)?,
+                None => fscmp.estimate_dirs(),
+            };
+            println!("{}", bytes);
+            return Ok(Comparison::Equal);
+        }
+        if stream {
+            return fscmp.streams(opt.content_size);
+        }
+        if opt.count_entries {
+            return fscmp.count_entries();
+        }
+        if opt.top_divergence {
+            let (comp, counts) = fscmp.top_divergence()?;
+            for (path, count) in counts {
+                println!("{}\t{}", count, path.display());
+            }
+            return Ok(comp);
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "tar"))]
+    {
+        if opt.tar {
+            return fscmp.dir_with_tar();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if opt.whole_device {
+            return fscmp.whole_devices();
+        }
+    }
+
+    #[cfg(unix)]
+    Ok(match (opt.content_size, pattern) {
+        (Some(content_size), Some(pattern)) => fscmp.contents_pattern(content_size, pattern)?,
+        (Some(content_size), None) => fscmp.contents(content_size)?,
+        (None, _) => fscmp.dirs()?,
     })
 }
 
+fn display_options(opt: &Opt) -> DisplayOptions {
+    // Text output goes to stderr, machine readable formats are never colored
+    let color = match opt.color {
+        Color::Auto => std::io::stderr().is_terminal(),
+        Color::Always => true,
+        Color::Never => false,
+    };
+    DisplayOptions::default()
+        .with_hex_width(opt.hex_width)
+        .with_color(color)
+}
+
 fn main() {
-    match run() {
-        Ok(Comparison::Equal) => (),
+    let opt = Opt::from_args();
+    let (format, display_options) = (opt.format, display_options(&opt));
+    // With --report-all each difference is printed as soon as it's found
+    #[cfg(unix)]
+    let (reported, quiet, summary_only) = (opt.report_all, opt.quiet, opt.summary_only);
+    #[cfg(windows)]
+    let (reported, summary_only) = (false, opt.summary_only);
+
+    #[cfg(unix)]
+    let summary = Arc::new(Mutex::new(DiffSummary::default()));
+
+    match run(
+        opt,
+        display_options,
+        #[cfg(unix)]
+        summary.clone(),
+    ) {
+        Ok(Comparison::Equal) => {
+            if summary_only {
+                println!("equal");
+            }
+        }
         Ok(comp) => {
-            eprintln!("{}", comp);
+            if summary_only {
+                println!("unequal");
+            } else if !reported {
+                format.print(&comp, display_options);
+            }
+            #[cfg(unix)]
+            {
+                // Goes to stderr along with text output, keeping machine readable stdout clean
+                let summary = summary.lock().unwrap();
+                if !summary.is_empty() {
+                    if quiet {
+                        info!("Summary: {}", summary);
+                    } else {
+                        eprintln!("Summary: {}", summary);
+                    }
+                }
+            }
             std::process::exit(1);
         }
         Err(e) => {
@@ -0,0 +1,171 @@
+//! Splitting of ranges into evenly spaced chunks, used for sampled IO
+//!
+//! ```
+//! use fscmp::range_chunks::ChunkableRange;
+//!
+//! // Read 2 bytes out of every 4
+//! let chunks: Vec<_> = (0..10).leap_chunks(2, 4).collect();
+//! assert_eq!(chunks, vec![0..2, 4..6, 8..10]);
+//! ```
+
+use std::cmp::min;
+use std::convert::TryFrom;
+use std::ops::Range;
+
+/// Iterator over chunks of a range, each chunk starting `leap` after the previous one
+///
+/// The last chunk is truncated to the end of the range. If `leap` is smaller than the chunk size the chunks overlap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeChunks {
+    range: Range<u64>,
+    chunk_size: u64,
+    leap: u64,
+    next: u64,
+    count: u64,
+}
+
+pub trait ChunkableRange {
+    /// Split into consecutive chunks of `chunk_size`
+    ///
+    /// ```
+    /// use fscmp::range_chunks::ChunkableRange;
+    ///
+    /// let chunks: Vec<_> = (10..15).chunks(2).collect();
+    /// assert_eq!(chunks, vec![10..12, 12..14, 14..15]);
+    /// ```
+    fn chunks(self, chunk_size: u64) -> RangeChunks;
+
+    /// Split into chunks of `chunk_size`, starting every `leap`
+    ///
+    /// ```
+    /// use fscmp::range_chunks::ChunkableRange;
+    ///
+    /// let chunks: Vec<_> = (0..100).leap_chunks(10, 40).collect();
+    /// assert_eq!(chunks, vec![0..10, 40..50, 80..90]);
+    /// ```
+    fn leap_chunks(self, chunk_size: u64, leap: u64) -> RangeChunks;
+}
+
+impl ChunkableRange for Range<u64> {
+    fn chunks(self, chunk_size: u64) -> RangeChunks {
+        self.leap_chunks(chunk_size, chunk_size)
+    }
+
+    fn leap_chunks(self, chunk_size: u64, leap: u64) -> RangeChunks {
+        assert!(chunk_size > 0, "Chunk size must be positive");
+        assert!(leap > 0, "Leap must be positive");
+        let count = if self.start < self.end {
+            (self.end - self.start).div_ceil(leap)
+        } else {
+            0
+        };
+        RangeChunks {
+            range: self,
+            chunk_size,
+            leap,
+            next: 0,
+            count,
+        }
+    }
+}
+
+impl RangeChunks {
+    /// Number of chunks left
+    pub fn len(&self) -> u64 {
+        self.count - self.next
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The chunk `index` chunks ahead, without advancing the iterator
+    ///
+    /// ```
+    /// use fscmp::range_chunks::ChunkableRange;
+    ///
+    /// let chunks = (0..10).leap_chunks(2, 4);
+    /// assert_eq!(chunks.get(2), Some(8..10));
+    /// assert_eq!(chunks.get(3), None);
+    /// ```
+    pub fn get(&self, index: u64) -> Option<Range<u64>> {
+        if index >= self.len() {
+            return None;
+        }
+        let start = self.range.start + (self.next + index) * self.leap;
+        Some(start..min(self.range.end, start + self.chunk_size))
+    }
+}
+
+impl Iterator for RangeChunks {
+    type Item = Range<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.get(0)?;
+        self.next += 1;
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match usize::try_from(self.len()) {
+            Ok(len) => (len, Some(len)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_empty_range() {
+        assert_eq!((0..0).chunks(4).count(), 0);
+        assert_eq!((5..5).leap_chunks(4, 8).count(), 0);
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 5..3;
+        assert!(reversed.chunks(1).is_empty());
+    }
+
+    #[test]
+    fn test_leap_larger_than_range() {
+        assert_eq!((0..10).leap_chunks(4, 100).collect::<Vec<_>>(), vec![0..4]);
+        assert_eq!((0..3).leap_chunks(4, 100).collect::<Vec<_>>(), vec![0..3]);
+    }
+
+    #[test]
+    fn test_overlapping_chunks() {
+        assert_eq!((0..4).leap_chunks(3, 2).collect::<Vec<_>>(), vec![0..3, 2..4]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Consecutive chunks put back together give back the original range
+        for start in 0..10 {
+            for end in start..30 {
+                for chunk_size in 1..12 {
+                    let chunks: Vec<_> = (start..end).chunks(chunk_size).collect();
+                    assert_eq!(chunks.len() as u64, (start..end).chunks(chunk_size).len());
+                    let mut next = start;
+                    for chunk in chunks {
+                        assert_eq!(chunk.start, next);
+                        assert!(chunk.end > chunk.start && chunk.end - chunk.start <= chunk_size);
+                        next = chunk.end;
+                    }
+                    assert_eq!(next, end);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let mut chunks = (0..10).leap_chunks(2, 3);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks.get(3), Some(9..10));
+        assert_eq!(chunks.next(), Some(0..2));
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.get(0), Some(3..5));
+        assert_eq!(chunks.get(3), None);
+    }
+}